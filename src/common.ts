@@ -1,9 +1,16 @@
-import { ServiceConfiguration } from "./native/types";
+import { ControllerProfile, ServiceConfiguration } from "./native/types";
 
+// The first controller's profile is kept at the top level, as that's where it was stored before multiple controllers
+// were supported
 export type AppSettings = {
   doubleMovementEnabled: boolean;
   enabledToggleAccelerator: number[];
-} & ServiceConfiguration;
+  additionalControllers?: ControllerProfile[];
+  useAnalogInput: boolean;
+  // Whether to use key states while no analog keyboard is available, defaults to true
+  analogFallback?: boolean;
+} & ControllerProfile &
+  Omit<ServiceConfiguration, "version" | "controllers" | "inputPolicy">;
 
 type Size = [width: number, height: number];
 
@@ -23,20 +23,28 @@ import {
   setServiceConfig,
   startService,
   stopService,
+  subscribeServiceEvents,
 } from "./native";
 import {
   defaultKeyMapping,
   defaultLeftJoystickStrafingAngles,
+  defaultMouseStick,
+  defaultPollConfiguration,
+  defaultRightJoystickTurn,
   defaultSettings,
   defaultToggleAccelerator,
   JoystickAngleConfiguration,
+  KeyMapping,
+  maxControllers,
   SDKState,
   ServiceConfiguration,
+  serviceConfigurationVersion,
 } from "./native/types";
 import { functions } from "electron-log";
 import { autoUpdater } from "electron-updater";
 import { PrettyAcceleratorName } from "./accelerator";
 import _ from "lodash";
+import { hidFromVk } from "./native/keycode";
 
 Object.assign(console, functions);
 app.commandLine.appendSwitch("disable-pinch");
@@ -348,6 +356,42 @@ function showNotification(state: boolean) {
   notification.show();
 }
 
+// Converts every bind in a key mapping from a Windows virtual-key code to a HID usage code. Keys given by name are
+// already layout-independent and kept, keys without a HID usage code are dropped
+function keyMappingToHid(mapping: any): KeyMapping {
+  const convert = (key: any) =>
+    typeof key === "number" ? hidFromVk(key) : key;
+  const convertBinds = (direction: any) => {
+    if (Array.isArray(direction?.binds)) {
+      direction.binds = direction.binds
+        .map((bind: any) => ({ ...bind, key: convert(bind.key) }))
+        .filter((bind: any) => bind.key !== undefined);
+    }
+  };
+  const convertJoystick = (joystick: any) => {
+    if (!joystick) return;
+    for (const field of Object.keys(joystick)) {
+      if (field === "sources") continue;
+      if (typeof joystick[field] === "number") {
+        joystick[field] = convert(joystick[field]);
+      } else {
+        convertBinds(joystick[field]);
+      }
+    }
+  };
+
+  mapping = _.cloneDeep(mapping);
+  convertJoystick(mapping?.leftJoystick);
+  convertJoystick(mapping?.rightJoystick);
+  Object.values(mapping?.buttons ?? {}).forEach(convertBinds);
+  if (Array.isArray(mapping?.taps)) {
+    mapping.taps = mapping.taps
+      .map((tap: any) => ({ ...tap, key: convert(tap.key) }))
+      .filter((tap: any) => tap.key !== undefined);
+  }
+  return mapping;
+}
+
 class ServiceManager {
   running: boolean = false;
   store = new ElectronStore<AppSettings>({
@@ -384,6 +428,25 @@ class ServiceManager {
         console.log("Migrated angles: ", newAngles);
         store.set("leftJoystickStrafingAngles", newAngles);
       },
+      ">=1.6.0": (store) => {
+        console.log("Migrating settings to 1.6.0");
+        // Binds used to be stored as virtual-key codes, the native side now reads HID usage codes. Migrations also run
+        // on a new store, which already has the HID defaults, and no virtual-key mapping can look like those
+        const keyMapping = store.get("keyMapping");
+        if (keyMapping && !_.isEqual(keyMapping, defaultKeyMapping)) {
+          store.set("keyMapping", keyMappingToHid(keyMapping));
+        }
+        const additional = store.get("additionalControllers");
+        if (additional) {
+          store.set(
+            "additionalControllers",
+            additional.map((controller) => ({
+              ...controller,
+              keyMapping: keyMappingToHid(controller.keyMapping),
+            }))
+          );
+        }
+      },
     },
   });
 
@@ -409,7 +472,15 @@ class ServiceManager {
       if (
         name == "leftJoystickStrafingAngles" ||
         name === "keyMapping" ||
-        name === "useAnalogInput"
+        name === "output" ||
+        name === "mouseStick" ||
+        name === "rightJoystickTurn" ||
+        name === "additionalControllers" ||
+        name === "useAnalogInput" ||
+        name === "analogFallback" ||
+        name === "poll" ||
+        name === "analogDeviceId" ||
+        name === "rejectBindingConflicts"
       ) {
         this.update_config();
       }
@@ -491,20 +562,36 @@ class ServiceManager {
       shell.openExternal(url);
     });
 
-    setInterval(() => {
-      this.check_sdk_state();
-    }, 1000);
+    subscribeServiceEvents((event) => {
+      switch (event.type) {
+        case "SDKStateChanged":
+          this.set_sdk_state(event.value);
+          break;
+        case "PollError":
+          console.error("Service poll error", event.value);
+          break;
+        case "ServiceStateChanged":
+          if (event.value.type === "Faulted") {
+            console.error("Service faulted", event.value.reason);
+          } else {
+            console.log("Service state changed", event.value);
+          }
+          break;
+        default:
+          console.debug("Service event", event);
+      }
+    });
   }
 
   check_sdk_state() {
-    let newState: SDKState;
-    // TODO: More optimal getter
-    if (this.running && this.serviceConfiguration().useAnalogInput) {
-      newState = getSDKState();
+    if (this.running && this.serviceConfiguration().inputPolicy !== "Digital") {
+      this.set_sdk_state(getSDKState());
     } else {
-      newState = { type: "Uninitialized" };
+      this.set_sdk_state({ type: "Uninitialized" });
     }
+  }
 
+  set_sdk_state(newState: SDKState) {
     if (!_.isEqual(newState, this.sdk_state)) {
       this.sdk_state = newState;
       mainWindow?.webContents.send("sdk_state_changed", newState);
@@ -528,15 +615,40 @@ class ServiceManager {
 
   serviceConfiguration(): ServiceConfiguration {
     return {
-      leftJoystickStrafingAngles: {
-        ...defaultLeftJoystickStrafingAngles,
-        ...this.store.get("leftJoystickStrafingAngles"),
-      },
-      keyMapping: {
-        ...defaultKeyMapping,
-        ...this.store.get("keyMapping"),
+      version: serviceConfigurationVersion,
+      controllers: [
+        {
+          leftJoystickStrafingAngles: {
+            ...defaultLeftJoystickStrafingAngles,
+            ...this.store.get("leftJoystickStrafingAngles"),
+          },
+          keyMapping: {
+            ...defaultKeyMapping,
+            ...this.store.get("keyMapping"),
+          },
+          output: this.store.get("output"),
+          mouseStick: {
+            ...defaultMouseStick,
+            ...this.store.get("mouseStick"),
+          },
+          rightJoystickTurn: {
+            ...defaultRightJoystickTurn,
+            ...this.store.get("rightJoystickTurn"),
+          },
+        },
+        ...(this.store.get("additionalControllers") ?? []),
+      ].slice(0, maxControllers),
+      inputPolicy: !this.store.get("useAnalogInput")
+        ? "Digital"
+        : this.store.get("analogFallback") ?? true
+        ? "AnalogWithFallback"
+        : "Analog",
+      poll: {
+        ...defaultPollConfiguration,
+        ...this.store.get("poll"),
       },
-      useAnalogInput: this.store.get("useAnalogInput") ?? false,
+      analogDeviceId: this.store.get("analogDeviceId"),
+      rejectBindingConflicts: this.store.get("rejectBindingConflicts"),
     };
   }
 
@@ -555,7 +667,11 @@ class ServiceManager {
 
   update_config() {
     if (this.running) {
-      setServiceConfig(this.serviceConfiguration());
+      try {
+        setServiceConfig(this.serviceConfiguration());
+      } catch (e) {
+        console.error("Failed to update service config", e, e.errors);
+      }
     }
   }
 
@@ -1,5 +1,35 @@
-import { get_sdk_state, set_config, start_service, stop_service } from "./native";
-import { ServiceConfiguration, SDKState } from "./types";
+import {
+  analyze_bindings,
+  end_bind_capture,
+  get_controller_feedback,
+  get_input_source,
+  get_poll_stats,
+  get_sdk_info,
+  get_sdk_state,
+  get_service_state,
+  get_telemetry,
+  get_xinput_slots,
+  reset_poll_stats,
+  set_config,
+  set_telemetry_interval,
+  start_bind_capture,
+  start_service,
+  stop_service,
+  subscribe,
+  unsubscribe,
+} from "./native";
+import {
+  BindingIssue,
+  ControllerFeedback,
+  InputSource,
+  PollStats,
+  ServiceConfiguration,
+  SDKInfo,
+  SDKState,
+  ServiceEvent,
+  ServiceState,
+  Telemetry,
+} from "./types";
 
 export function startService(
   config: ServiceConfiguration,
@@ -15,6 +45,11 @@ export function setServiceConfig(config: ServiceConfiguration) {
   set_config(JSON.stringify(config));
 }
 
+/// Throws if the config can't be parsed
+export function analyzeBindings(config: ServiceConfiguration): BindingIssue[] {
+  return JSON.parse(analyze_bindings(JSON.stringify(config)));
+}
+
 export function getSDKState(): SDKState {
   const raw_state = get_sdk_state()
   if (raw_state) {
@@ -24,3 +59,73 @@ export function getSDKState(): SDKState {
     return { type: 'Uninitialized' }
   }
 }
+
+export function getSDKInfo(): SDKInfo {
+  const raw_info = get_sdk_info();
+  return raw_info
+    ? JSON.parse(raw_info)
+    : { version: null, keycode_mode: null };
+}
+
+export function getInputSource(): InputSource {
+  const raw_source = get_input_source();
+  return raw_source ? JSON.parse(raw_source) : "Digital";
+}
+
+export function getServiceState(): ServiceState {
+  const raw_state = get_service_state();
+  return raw_state ? JSON.parse(raw_state) : { type: "Stopped" };
+}
+
+/// Returns function to unsubscribe from events
+export function subscribeServiceEvents(
+  callback: (event: ServiceEvent) => void
+): () => void {
+  const id = subscribe((raw_event) => callback(JSON.parse(raw_event)));
+  return () => {
+    if (id != null) {
+      unsubscribe(id);
+    }
+  };
+}
+
+export function getTelemetry(): Telemetry | null {
+  const raw_telemetry = get_telemetry();
+  return raw_telemetry ? JSON.parse(raw_telemetry) : null;
+}
+
+/// Returns the rumble, LED and lightbar state of each controller, in the same order as the config
+export function getControllerFeedback(): ControllerFeedback[] {
+  const raw_feedback = get_controller_feedback();
+  return raw_feedback ? JSON.parse(raw_feedback) : [];
+}
+
+/// Returns the xinput slot of each controller, null for controllers that don't have one
+export function getXInputSlots(): (number | null)[] {
+  const raw_slots = get_xinput_slots();
+  return raw_slots ? JSON.parse(raw_slots) : [];
+}
+
+/// Pass null to stop receiving Telemetry events
+export function setTelemetryInterval(intervalMs: number | null) {
+  set_telemetry_interval(intervalMs);
+}
+
+export function getPollStats(): PollStats | null {
+  const raw_stats = get_poll_stats();
+  return raw_stats ? JSON.parse(raw_stats) : null;
+}
+
+/// The next key pressed past the threshold (0-1) is reported with a BindCaptured event, the capture ends after that
+/// Throws if the service isn't running or the threshold is out of range
+export function startBindCapture(threshold?: number) {
+  start_bind_capture(threshold);
+}
+
+export function endBindCapture() {
+  end_bind_capture();
+}
+
+export function resetPollStats() {
+  reset_poll_stats();
+}
@@ -0,0 +1,151 @@
+// Keys are stored and sent to the native side as HID usage codes, which don't depend on the platform or the keyboard
+// layout. Must match KEYS in native/src/keycode.rs
+const keys: [hid: number, code: string, vk: number | null][] = [
+  [0x04, "KeyA", 0x41],
+  [0x05, "KeyB", 0x42],
+  [0x06, "KeyC", 0x43],
+  [0x07, "KeyD", 0x44],
+  [0x08, "KeyE", 0x45],
+  [0x09, "KeyF", 0x46],
+  [0x0a, "KeyG", 0x47],
+  [0x0b, "KeyH", 0x48],
+  [0x0c, "KeyI", 0x49],
+  [0x0d, "KeyJ", 0x4a],
+  [0x0e, "KeyK", 0x4b],
+  [0x0f, "KeyL", 0x4c],
+  [0x10, "KeyM", 0x4d],
+  [0x11, "KeyN", 0x4e],
+  [0x12, "KeyO", 0x4f],
+  [0x13, "KeyP", 0x50],
+  [0x14, "KeyQ", 0x51],
+  [0x15, "KeyR", 0x52],
+  [0x16, "KeyS", 0x53],
+  [0x17, "KeyT", 0x54],
+  [0x18, "KeyU", 0x55],
+  [0x19, "KeyV", 0x56],
+  [0x1a, "KeyW", 0x57],
+  [0x1b, "KeyX", 0x58],
+  [0x1c, "KeyY", 0x59],
+  [0x1d, "KeyZ", 0x5a],
+  [0x1e, "Digit1", 0x31],
+  [0x1f, "Digit2", 0x32],
+  [0x20, "Digit3", 0x33],
+  [0x21, "Digit4", 0x34],
+  [0x22, "Digit5", 0x35],
+  [0x23, "Digit6", 0x36],
+  [0x24, "Digit7", 0x37],
+  [0x25, "Digit8", 0x38],
+  [0x26, "Digit9", 0x39],
+  [0x27, "Digit0", 0x30],
+  [0x28, "Enter", 0x0d],
+  [0x29, "Escape", 0x1b],
+  [0x2a, "Backspace", 0x08],
+  [0x2b, "Tab", 0x09],
+  [0x2c, "Space", 0x20],
+  [0x2d, "Minus", 0xbd],
+  [0x2e, "Equal", 0xbb],
+  [0x2f, "BracketLeft", 0xdb],
+  [0x30, "BracketRight", 0xdd],
+  [0x31, "Backslash", 0xdc],
+  [0x33, "Semicolon", 0xba],
+  [0x34, "Quote", 0xde],
+  [0x35, "Backquote", 0xc0],
+  [0x36, "Comma", 0xbc],
+  [0x37, "Period", 0xbe],
+  [0x38, "Slash", 0xbf],
+  [0x39, "CapsLock", 0x14],
+  [0x3a, "F1", 0x70],
+  [0x3b, "F2", 0x71],
+  [0x3c, "F3", 0x72],
+  [0x3d, "F4", 0x73],
+  [0x3e, "F5", 0x74],
+  [0x3f, "F6", 0x75],
+  [0x40, "F7", 0x76],
+  [0x41, "F8", 0x77],
+  [0x42, "F9", 0x78],
+  [0x43, "F10", 0x79],
+  [0x44, "F11", 0x7a],
+  [0x45, "F12", 0x7b],
+  [0x46, "PrintScreen", 0x2c],
+  [0x47, "ScrollLock", 0x91],
+  [0x48, "Pause", 0x13],
+  [0x49, "Insert", 0x2d],
+  [0x4a, "Home", 0x24],
+  [0x4b, "PageUp", 0x21],
+  [0x4c, "Delete", 0x2e],
+  [0x4d, "End", 0x23],
+  [0x4e, "PageDown", 0x22],
+  [0x4f, "ArrowRight", 0x27],
+  [0x50, "ArrowLeft", 0x25],
+  [0x51, "ArrowDown", 0x28],
+  [0x52, "ArrowUp", 0x26],
+  [0x53, "NumLock", 0x90],
+  [0x54, "NumpadDivide", 0x6f],
+  [0x55, "NumpadMultiply", 0x6a],
+  [0x56, "NumpadSubtract", 0x6d],
+  [0x57, "NumpadAdd", 0x6b],
+  [0x58, "NumpadEnter", null],
+  [0x59, "Numpad1", 0x61],
+  [0x5a, "Numpad2", 0x62],
+  [0x5b, "Numpad3", 0x63],
+  [0x5c, "Numpad4", 0x64],
+  [0x5d, "Numpad5", 0x65],
+  [0x5e, "Numpad6", 0x66],
+  [0x5f, "Numpad7", 0x67],
+  [0x60, "Numpad8", 0x68],
+  [0x61, "Numpad9", 0x69],
+  [0x62, "Numpad0", 0x60],
+  [0x63, "NumpadDecimal", 0x6e],
+  [0x64, "IntlBackslash", 0xe2],
+  [0x65, "ContextMenu", 0x5d],
+  [0x68, "F13", 0x7c],
+  [0x69, "F14", 0x7d],
+  [0x6a, "F15", 0x7e],
+  [0x6b, "F16", 0x7f],
+  [0x6c, "F17", 0x80],
+  [0x6d, "F18", 0x81],
+  [0x6e, "F19", 0x82],
+  [0x6f, "F20", 0x83],
+  [0x70, "F21", 0x84],
+  [0x71, "F22", 0x85],
+  [0x72, "F23", 0x86],
+  [0x73, "F24", 0x87],
+  [0xe0, "ControlLeft", 0xa2],
+  [0xe1, "ShiftLeft", 0xa0],
+  [0xe2, "AltLeft", 0xa4],
+  [0xe3, "MetaLeft", 0x5b],
+  [0xe4, "ControlRight", 0xa3],
+  [0xe5, "ShiftRight", 0xa1],
+  [0xe6, "AltRight", 0xa5],
+  [0xe7, "MetaRight", 0x5c],
+  [0xf0, "MouseLeft", 0x01],
+  [0xf1, "MouseRight", 0x02],
+  [0xf2, "MouseMiddle", 0x04],
+  [0xf3, "MouseBack", 0x05],
+  [0xf4, "MouseForward", 0x06],
+];
+
+// The generic modifier virtual-key codes don't say which side was pressed, so they're treated as the left one
+const vkAliases: [vk: number, hid: number][] = [
+  [0x10, 0xe1],
+  [0x11, 0xe0],
+  [0x12, 0xe2],
+];
+
+// The HID usage code for a KeyboardEvent.code
+export function hidFromCode(code: string): number | undefined {
+  return keys.find(([, keyCode]) => keyCode === code)?.[0];
+}
+
+// The HID usage code for a Windows virtual-key code, which is what keys were stored as before
+export function hidFromVk(vk: number): number | undefined {
+  return (
+    keys.find(([, , keyVk]) => keyVk === vk)?.[0] ??
+    vkAliases.find(([alias]) => alias === vk)?.[1]
+  );
+}
+
+// The KeyboardEvent.code style name of a HID usage code, for display
+export function hidName(hid: number): string | undefined {
+  return keys.find(([keyHid]) => keyHid === hid)?.[1];
+}
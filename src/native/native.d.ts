@@ -4,7 +4,21 @@ export function start_service(
 ): boolean;
 export function stop_service();
 export function get_xinput_slot(): number | null;
+export function get_xinput_slots(): string | null;
 export function get_sdk_state(): string | null;
+export function get_sdk_info(): string | null;
+export function get_input_source(): string | null;
+export function get_service_state(): string | null;
 export function set_config(config: string);
+export function analyze_bindings(config: string): string;
 export function start_gamepad_detection();
 export function end_gamepad_detection();
+export function start_bind_capture(threshold?: number);
+export function end_bind_capture();
+export function get_telemetry(): string | null;
+export function get_controller_feedback(): string | null;
+export function set_telemetry_interval(intervalMs: number | null);
+export function get_poll_stats(): string | null;
+export function reset_poll_stats();
+export function subscribe(callback: (event: string) => void): number | null;
+export function unsubscribe(id: number): boolean;
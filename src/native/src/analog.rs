@@ -0,0 +1,652 @@
+// Only the service uses this, which is Windows only, but it's built everywhere for its tests
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use log::*;
+use sdk::{
+    DeviceEventType, DeviceID, DeviceInfo, DeviceInfo_FFI, DeviceType, KeycodeType,
+    WootingAnalogResult,
+};
+use serde::{Deserialize, Serialize};
+use wooting_analog_wrapper as sdk;
+
+/// A connected analog device with everything the SDK tells us about it
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct AnalogDevice {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer_name: String,
+    pub device_name: String,
+    /// Sent as a string as it doesn't fit in a JS number
+    pub device_id: String,
+    pub device_type: DeviceType,
+}
+
+impl From<&DeviceInfo> for AnalogDevice {
+    fn from(device: &DeviceInfo) -> Self {
+        AnalogDevice {
+            vendor_id: device.vendor_id,
+            product_id: device.product_id,
+            manufacturer_name: device.manufacturer_name.clone(),
+            device_name: device.device_name.clone(),
+            device_id: device.device_id.to_string(),
+            device_type: device.device_type.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(tag = "type", content = "value")]
+pub enum AnalogSDKState {
+    Uninitialized,
+    Error(sdk::WootingAnalogResult),
+    DevicesConnected(Vec<AnalogDevice>),
+    NoDevices,
+}
+
+impl AnalogSDKState {
+    pub fn devices(&self) -> &[AnalogDevice] {
+        match self {
+            AnalogSDKState::DevicesConnected(devices) => devices,
+            _ => &[],
+        }
+    }
+}
+
+/// Details about the loaded SDK that are useful for diagnosing issues, the devices are part of `AnalogSDKState`
+#[derive(Clone, Serialize, Default, Debug)]
+pub struct AnalogSDKInfo {
+    /// The major version of the SDK, if it's been initialised
+    pub version: Option<u32>,
+    /// The keycode mode the SDK has been set to, if it's been initialised
+    pub keycode_mode: Option<KeycodeType>,
+}
+
+/// The parts of the Wooting Analog SDK the service uses. This lets the service be driven by a fake SDK, so device
+/// changes and read errors can be simulated without real hardware
+pub trait AnalogSdk {
+    /// Returns the number of connected devices
+    fn initialise(&mut self) -> Result<u32, WootingAnalogResult>;
+    fn uninitialise(&mut self) -> Result<(), WootingAnalogResult>;
+    /// Returns the major version of the SDK that's been loaded
+    fn version(&mut self) -> Result<u32, WootingAnalogResult>;
+    fn set_keycode_mode(&mut self, mode: KeycodeType) -> Result<(), WootingAnalogResult>;
+    fn connected_devices(&mut self) -> Result<Vec<DeviceInfo>, WootingAnalogResult>;
+    fn read_full_buffer(&mut self) -> Result<HashMap<u16, f32>, WootingAnalogResult>;
+    fn read_full_buffer_device(
+        &mut self,
+        device_id: DeviceID,
+    ) -> Result<HashMap<u16, f32>, WootingAnalogResult>;
+    /// Returns true if a device has been connected or disconnected since the last call
+    fn take_device_change(&mut self) -> bool;
+}
+
+/// The maximum number of devices we ask the SDK about
+const MAX_DEVICES: usize = 10;
+/// The maximum number of pressed keys read in one go
+const MAX_KEYS: usize = 20;
+
+// The SDK's device event callback doesn't take any user data, so it can only get to us through a global
+static DEVICES_CHANGED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_device_event(event_type: DeviceEventType, _device: *mut DeviceInfo_FFI) {
+    // This is called from the SDK's own thread, so just flag it and let the service pick up the new device list on
+    // its next poll rather than calling back into the SDK from here
+    debug!("Received device event {:?}", event_type);
+    DEVICES_CHANGED.store(true, Ordering::SeqCst);
+}
+
+/// The real SDK, loaded through `wooting-analog-wrapper`
+pub struct WootingAnalogSdk;
+
+impl AnalogSdk for WootingAnalogSdk {
+    fn initialise(&mut self) -> Result<u32, WootingAnalogResult> {
+        let device_num = sdk::initialise().0?;
+
+        DEVICES_CHANGED.store(false, Ordering::SeqCst);
+        if let Err(e) = sdk::set_device_event_cb(on_device_event).0 {
+            // Not fatal, we'll still notice devices going away when reads start failing
+            warn!("Failed to register for device events: {}", e);
+        }
+
+        Ok(device_num)
+    }
+
+    fn uninitialise(&mut self) -> Result<(), WootingAnalogResult> {
+        if let Err(e) = sdk::clear_device_event_cb().0 {
+            warn!("Failed to clear the device event callback: {}", e);
+        }
+        sdk::uninitialise().0
+    }
+
+    fn version(&mut self) -> Result<u32, WootingAnalogResult> {
+        sdk::version().0
+    }
+
+    fn set_keycode_mode(&mut self, mode: KeycodeType) -> Result<(), WootingAnalogResult> {
+        sdk::set_keycode_mode(mode).0
+    }
+
+    fn connected_devices(&mut self) -> Result<Vec<DeviceInfo>, WootingAnalogResult> {
+        sdk::get_connected_devices_info(MAX_DEVICES).0
+    }
+
+    fn read_full_buffer(&mut self) -> Result<HashMap<u16, f32>, WootingAnalogResult> {
+        sdk::read_full_buffer(MAX_KEYS).0
+    }
+
+    fn read_full_buffer_device(
+        &mut self,
+        device_id: DeviceID,
+    ) -> Result<HashMap<u16, f32>, WootingAnalogResult> {
+        sdk::read_full_buffer_device(MAX_KEYS, device_id).0
+    }
+
+    fn take_device_change(&mut self) -> bool {
+        DEVICES_CHANGED.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// How long to wait before retrying analog input after the SDK failed or lost its devices, this doubles with each
+/// attempt
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+/// The longest the retry backoff can get
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Keeps track of the SDK and the devices connected to it, reading analog values and retrying when it's unavailable.
+/// This knows nothing about the controllers, the service decides what the values are used for and tells JS about
+/// changes to the state
+pub struct AnalogInput {
+    sdk: Box<dyn AnalogSdk>,
+    initialised: bool,
+    devices: Vec<DeviceInfo>,
+    state: AnalogSDKState,
+    info: AnalogSDKInfo,
+    retry_backoff: Duration,
+    next_retry: Option<Instant>,
+}
+
+impl AnalogInput {
+    pub fn new(sdk: Box<dyn AnalogSdk>) -> Self {
+        AnalogInput {
+            sdk,
+            initialised: false,
+            devices: vec![],
+            state: AnalogSDKState::Uninitialized,
+            info: AnalogSDKInfo::default(),
+            retry_backoff: RETRY_BACKOFF,
+            next_retry: None,
+        }
+    }
+
+    pub fn state(&self) -> &AnalogSDKState {
+        &self.state
+    }
+
+    pub fn info(&self) -> &AnalogSDKInfo {
+        &self.info
+    }
+
+    pub fn is_initialised(&self) -> bool {
+        self.initialised
+    }
+
+    /// Whether `device_id` is connected, or any device is if it's `None`
+    pub fn has_device(&self, device_id: Option<u64>) -> bool {
+        match device_id {
+            Some(device_id) => self
+                .devices
+                .iter()
+                .any(|device| device.device_id == device_id),
+            None => !self.devices.is_empty(),
+        }
+    }
+
+    /// Whether analog input can be read, meaning the SDK is loaded and the keyboard we read from is connected
+    pub fn is_available(&self, device_id: Option<u64>) -> bool {
+        self.initialised && self.has_device(device_id)
+    }
+
+    /// Replaces the list of connected devices, which also moves the state between `NoDevices` and
+    /// `DevicesConnected`
+    fn set_devices(&mut self, devices: Vec<DeviceInfo>) {
+        self.state = if devices.is_empty() {
+            AnalogSDKState::NoDevices
+        } else {
+            AnalogSDKState::DevicesConnected(devices.iter().map(AnalogDevice::from).collect())
+        };
+        self.devices = devices;
+    }
+
+    fn refresh_devices(&mut self) {
+        match self.sdk.connected_devices() {
+            Ok(devices) => {
+                debug!("Connected devices are now {:?}", devices);
+                self.set_devices(devices);
+            }
+            Err(WootingAnalogResult::NoDevices) => self.set_devices(vec![]),
+            Err(e) => error!("Failed to get the connected devices: {}", e),
+        }
+    }
+
+    pub fn init(&mut self) -> Result<()> {
+        if self.initialised {
+            return Ok(());
+        }
+
+        match self.sdk.initialise() {
+            Ok(device_num) => {
+                info!(
+                    "Analog SDK Successfully initialised with {} devices",
+                    device_num
+                );
+
+                match self.sdk.version() {
+                    Ok(version) => {
+                        info!("Analog SDK major version is {}", version);
+                        self.info.version = Some(version);
+                    }
+                    Err(e) => warn!("Failed to get the Analog SDK version: {}", e),
+                }
+
+                match self.sdk.connected_devices() {
+                    Ok(devices) => {
+                        if devices.len() as u32 != device_num {
+                            error!(
+                                "Analog SDK initialised with {} devices but reported {} connected",
+                                device_num,
+                                devices.len()
+                            );
+                        }
+                        for (i, device) in devices.iter().enumerate() {
+                            info!("Device {} is {:?}", i, device);
+                        }
+                        self.set_devices(devices);
+                    }
+                    Err(WootingAnalogResult::NoDevices) => self.set_devices(vec![]),
+                    Err(e) => error!("Failed to get the connected devices: {}", e),
+                }
+
+                // Binds are HID usage codes, which the SDK can give us directly regardless of the keyboard layout
+                let keycode_mode = KeycodeType::HID;
+                if let Err(e) = self.sdk.set_keycode_mode(keycode_mode.clone()) {
+                    // Reading in any other mode would give the wrong keys, so unload it again and leave it to retry
+                    if let Err(e) = self.sdk.uninitialise() {
+                        warn!("Failed to uninitialise the Analog SDK: {}", e);
+                    }
+                    self.devices.clear();
+                    self.info = AnalogSDKInfo::default();
+                    self.state = AnalogSDKState::Error(e);
+                    return Err(e).context("Failed to set keyboard mode");
+                }
+                self.info.keycode_mode = Some(keycode_mode);
+
+                self.initialised = true;
+            }
+            Err(e) => {
+                error!("Wooting Analog SDK Failed to initialise: {}", e);
+                self.state = AnalogSDKState::Error(e);
+            }
+        };
+
+        Ok(())
+    }
+
+    /// Unloads the SDK. It counts as uninitialised afterwards even if the SDK reported an error, as there's nothing
+    /// more that could be done with it
+    pub fn uninit(&mut self) -> Result<()> {
+        if !self.initialised {
+            return Ok(());
+        }
+
+        self.initialised = false;
+        self.devices.clear();
+        self.info = AnalogSDKInfo::default();
+        self.state = AnalogSDKState::Uninitialized;
+        self.sdk
+            .uninitialise()
+            .context("Failed to uninitialise analog sdk")
+    }
+
+    pub fn reset_retry(&mut self) {
+        self.retry_backoff = RETRY_BACKOFF;
+        self.next_retry = None;
+    }
+
+    /// Keeps trying to load the SDK or find the device while analog input isn't available, backing off between
+    /// attempts. Also picks up device changes the SDK told us about
+    pub fn retry(&mut self, device_id: Option<u64>, now: Instant) {
+        if self.initialised && self.sdk.take_device_change() {
+            self.refresh_devices();
+        }
+
+        if self.is_available(device_id) {
+            self.reset_retry();
+            return;
+        }
+
+        match self.next_retry {
+            None => self.next_retry = Some(now + self.retry_backoff),
+            Some(next) if now >= next => {
+                debug!("Retrying analog input");
+                if self.initialised {
+                    // Covers the device event callback not being available
+                    self.refresh_devices();
+                } else if let Err(e) = self.init() {
+                    error!("Failed to initialise analog {:#}", e);
+                }
+
+                if self.is_available(device_id) {
+                    info!("Analog input is available again");
+                    self.reset_retry();
+                } else {
+                    self.retry_backoff = (self.retry_backoff * 2).min(MAX_RETRY_BACKOFF);
+                    self.next_retry = Some(now + self.retry_backoff);
+                    debug!(
+                        "Analog input still isn't available, retrying in {:?}",
+                        self.retry_backoff
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Reads how far every pressed key is pressed, from `device_id` or every device if it's `None`. Returns `None` if
+    /// there's nothing to read, in which case the state has been updated with why
+    pub fn read(&mut self, device_id: Option<u64>) -> Option<HashMap<u16, f32>> {
+        if !self.initialised {
+            return None;
+        }
+
+        let read_result = match device_id {
+            Some(device_id) => {
+                if !self.has_device(Some(device_id)) {
+                    // The chosen keyboard isn't plugged in, so it shouldn't be driving anything
+                    return None;
+                }
+                self.sdk.read_full_buffer_device(device_id)
+            }
+            None => self.sdk.read_full_buffer(),
+        };
+
+        match read_result {
+            Ok(analog) => {
+                // Covers the device event callback not being available, as a successful read means there's a device
+                if self.state == AnalogSDKState::NoDevices {
+                    self.refresh_devices();
+                }
+                Some(analog)
+            }
+            Err(e) => {
+                match e {
+                    WootingAnalogResult::NoDevices => self.set_devices(vec![]),
+                    WootingAnalogResult::DeviceDisconnected => self.refresh_devices(),
+                    WootingAnalogResult::UnInitialized => {
+                        // The SDK has gone away underneath us, so it'll have to be initialised again
+                        warn!("Analog SDK is no longer initialised");
+                        self.initialised = false;
+                        self.devices.clear();
+                        self.info = AnalogSDKInfo::default();
+                        self.state = AnalogSDKState::Error(e);
+                    }
+                    _ => {}
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// What the fake SDK reports, shared with the test so it can be changed while the `AnalogInput` owns the SDK
+    struct FakeState {
+        init_error: Option<WootingAnalogResult>,
+        keycode_mode_error: Option<WootingAnalogResult>,
+        devices: Vec<DeviceInfo>,
+        read: Result<HashMap<u16, f32>, WootingAnalogResult>,
+        device_changed: bool,
+        initialise_calls: u32,
+        uninitialise_calls: u32,
+        read_device: Option<DeviceID>,
+    }
+
+    struct FakeSdk(Rc<RefCell<FakeState>>);
+
+    impl AnalogSdk for FakeSdk {
+        fn initialise(&mut self) -> Result<u32, WootingAnalogResult> {
+            let mut state = self.0.borrow_mut();
+            state.initialise_calls += 1;
+            match state.init_error {
+                Some(e) => Err(e),
+                None => Ok(state.devices.len() as u32),
+            }
+        }
+
+        fn uninitialise(&mut self) -> Result<(), WootingAnalogResult> {
+            self.0.borrow_mut().uninitialise_calls += 1;
+            Ok(())
+        }
+
+        fn version(&mut self) -> Result<u32, WootingAnalogResult> {
+            Ok(0)
+        }
+
+        fn set_keycode_mode(&mut self, _mode: KeycodeType) -> Result<(), WootingAnalogResult> {
+            match self.0.borrow().keycode_mode_error {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+
+        fn connected_devices(&mut self) -> Result<Vec<DeviceInfo>, WootingAnalogResult> {
+            Ok(self.0.borrow().devices.clone())
+        }
+
+        fn read_full_buffer(&mut self) -> Result<HashMap<u16, f32>, WootingAnalogResult> {
+            self.0.borrow().read.clone()
+        }
+
+        fn read_full_buffer_device(
+            &mut self,
+            device_id: DeviceID,
+        ) -> Result<HashMap<u16, f32>, WootingAnalogResult> {
+            let mut state = self.0.borrow_mut();
+            state.read_device = Some(device_id);
+            state.read.clone()
+        }
+
+        fn take_device_change(&mut self) -> bool {
+            std::mem::replace(&mut self.0.borrow_mut().device_changed, false)
+        }
+    }
+
+    fn device(device_id: DeviceID) -> DeviceInfo {
+        DeviceInfo {
+            vendor_id: 0x31e3,
+            product_id: 0x1100,
+            manufacturer_name: "Wooting".to_string(),
+            device_name: "Wooting Two".to_string(),
+            device_id,
+            device_type: DeviceType::Keyboard,
+        }
+    }
+
+    fn input(devices: Vec<DeviceInfo>) -> (AnalogInput, Rc<RefCell<FakeState>>) {
+        let state = Rc::new(RefCell::new(FakeState {
+            init_error: None,
+            keycode_mode_error: None,
+            devices,
+            read: Ok(HashMap::new()),
+            device_changed: false,
+            initialise_calls: 0,
+            uninitialise_calls: 0,
+            read_device: None,
+        }));
+        (AnalogInput::new(Box::new(FakeSdk(state.clone()))), state)
+    }
+
+    #[test]
+    fn init_finds_the_devices() {
+        let (mut analog, _) = input(vec![device(1)]);
+        analog.init().unwrap();
+
+        assert!(analog.is_initialised());
+        assert_eq!(
+            analog.state(),
+            &AnalogSDKState::DevicesConnected(vec![AnalogDevice::from(&device(1))])
+        );
+        assert_eq!(analog.info().version, Some(0));
+        assert!(analog.is_available(None));
+        assert!(analog.is_available(Some(1)));
+        assert!(!analog.is_available(Some(2)));
+    }
+
+    #[test]
+    fn uninit_forgets_the_devices() {
+        let (mut analog, _) = input(vec![device(1)]);
+        analog.init().unwrap();
+        analog.uninit().unwrap();
+
+        assert!(!analog.is_initialised());
+        assert_eq!(analog.state(), &AnalogSDKState::Uninitialized);
+        assert!(analog.info().version.is_none());
+        assert!(!analog.is_available(None));
+    }
+
+    #[test]
+    fn failing_to_set_the_keycode_mode_unloads_the_sdk() {
+        let (mut analog, fake) = input(vec![device(1)]);
+        fake.borrow_mut().keycode_mode_error = Some(WootingAnalogResult::NotAvailable);
+
+        assert!(analog.init().is_err());
+        assert!(!analog.is_initialised());
+        assert_eq!(fake.borrow().uninitialise_calls, 1);
+        assert_eq!(
+            analog.state(),
+            &AnalogSDKState::Error(WootingAnalogResult::NotAvailable)
+        );
+        assert!(analog.info().version.is_none());
+        assert!(!analog.is_available(None));
+
+        // It's loaded again on the next attempt
+        fake.borrow_mut().keycode_mode_error = None;
+        analog.init().unwrap();
+        assert!(analog.is_available(Some(1)));
+        assert_eq!(fake.borrow().initialise_calls, 2);
+    }
+
+    #[test]
+    fn failed_init_is_retried_with_backoff() {
+        let (mut analog, fake) = input(vec![device(1)]);
+        fake.borrow_mut().init_error = Some(WootingAnalogResult::DLLNotFound);
+        analog.init().unwrap();
+        assert_eq!(
+            analog.state(),
+            &AnalogSDKState::Error(WootingAnalogResult::DLLNotFound)
+        );
+
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        // The first call only schedules the retry
+        analog.retry(None, at(0));
+        analog.retry(None, at(999));
+        assert_eq!(fake.borrow().initialise_calls, 1);
+        analog.retry(None, at(1000));
+        assert_eq!(fake.borrow().initialise_calls, 2);
+
+        // Still failing, so the next attempt is twice as far away
+        analog.retry(None, at(2999));
+        assert_eq!(fake.borrow().initialise_calls, 2);
+        fake.borrow_mut().init_error = None;
+        analog.retry(None, at(3000));
+        assert_eq!(fake.borrow().initialise_calls, 3);
+        assert!(analog.is_available(None));
+
+        // Being available resets the backoff
+        fake.borrow_mut().devices.clear();
+        fake.borrow_mut().device_changed = true;
+        analog.retry(None, at(4000));
+        assert_eq!(analog.state(), &AnalogSDKState::NoDevices);
+        analog.retry(None, at(4999));
+        assert_eq!(analog.state(), &AnalogSDKState::NoDevices);
+        fake.borrow_mut().devices.push(device(1));
+        analog.retry(None, at(5000));
+        assert!(analog.is_available(None));
+    }
+
+    #[test]
+    fn device_changes_are_picked_up() {
+        let (mut analog, fake) = input(vec![device(1)]);
+        analog.init().unwrap();
+
+        fake.borrow_mut().devices.push(device(2));
+        analog.retry(Some(2), Instant::now());
+        assert!(!analog.is_available(Some(2)));
+
+        fake.borrow_mut().device_changed = true;
+        analog.retry(Some(2), Instant::now());
+        assert!(analog.is_available(Some(2)));
+        assert_eq!(analog.state().devices().len(), 2);
+    }
+
+    #[test]
+    fn read_returns_the_values() {
+        let (mut analog, fake) = input(vec![device(1)]);
+        let values: HashMap<u16, f32> = [(0x1a, 0.5)].iter().copied().collect();
+        fake.borrow_mut().read = Ok(values.clone());
+
+        assert_eq!(analog.read(None), None, "nothing to read before init");
+        analog.init().unwrap();
+        assert_eq!(analog.read(None), Some(values.clone()));
+        assert_eq!(analog.read(Some(1)), Some(values));
+        assert_eq!(fake.borrow().read_device, Some(1));
+    }
+
+    #[test]
+    fn read_skips_a_device_that_is_not_connected() {
+        let (mut analog, fake) = input(vec![device(1)]);
+        analog.init().unwrap();
+
+        assert_eq!(analog.read(Some(2)), None);
+        assert_eq!(fake.borrow().read_device, None);
+    }
+
+    #[test]
+    fn read_errors_update_the_state() {
+        let (mut analog, fake) = input(vec![device(1)]);
+        analog.init().unwrap();
+
+        fake.borrow_mut().read = Err(WootingAnalogResult::NoDevices);
+        assert_eq!(analog.read(None), None);
+        assert_eq!(analog.state(), &AnalogSDKState::NoDevices);
+
+        // A successful read means there's a device after all
+        fake.borrow_mut().read = Ok(HashMap::new());
+        assert!(analog.read(None).is_some());
+        assert_eq!(analog.state().devices().len(), 1);
+
+        fake.borrow_mut().devices.clear();
+        fake.borrow_mut().read = Err(WootingAnalogResult::DeviceDisconnected);
+        assert_eq!(analog.read(None), None);
+        assert_eq!(analog.state(), &AnalogSDKState::NoDevices);
+
+        fake.borrow_mut().read = Err(WootingAnalogResult::UnInitialized);
+        assert_eq!(analog.read(None), None);
+        assert!(!analog.is_initialised());
+        assert_eq!(
+            analog.state(),
+            &AnalogSDKState::Error(WootingAnalogResult::UnInitialized)
+        );
+    }
+}
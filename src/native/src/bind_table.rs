@@ -0,0 +1,371 @@
+// Only raw input uses this, but it's built for tests without it too
+#![cfg_attr(not(feature = "rawinput"), allow(dead_code))]
+
+use std::collections::{HashMap, HashSet};
+
+use crate::bindings::BindTarget;
+use crate::config::{ControllerProfile, DirectionBinds, KeyMapping};
+use crate::controller::ControllerState;
+use crate::keycode::KeyCode;
+use crate::tap::Taps;
+
+/// A target on one of the controllers, `pad` being its index in the config's `controllers`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundTarget {
+    pub pad: usize,
+    pub target: BindTarget,
+}
+
+/// Every target each key is bound to, so a key event can go straight to what it affects. This has to be rebuilt
+/// whenever the config changes
+#[derive(Default)]
+pub struct BindTable {
+    targets: HashMap<KeyCode, Vec<BoundTarget>>,
+}
+
+impl BindTable {
+    pub fn new(controllers: &[ControllerProfile]) -> Self {
+        let mut targets: HashMap<KeyCode, Vec<BoundTarget>> = HashMap::new();
+        for (pad, profile) in controllers.iter().enumerate() {
+            for target in BindTarget::all() {
+                for bind in &target.binds(&profile.key_mapping).binds {
+                    let bound = BoundTarget { pad, target };
+                    let entry = targets.entry(bind.key).or_default();
+                    // A key can be listed more than once for a target, but it only needs updating once
+                    if !entry.contains(&bound) {
+                        entry.push(bound);
+                    }
+                }
+            }
+        }
+        BindTable { targets }
+    }
+
+    /// The targets the key is bound to, empty if it isn't bound to anything
+    pub fn get(&self, key: KeyCode) -> &[BoundTarget] {
+        self.targets.get(&key).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// The keys currently held down according to raw input, for one controller
+pub struct KeyBindState {
+    pressed: HashSet<KeyCode>,
+}
+
+impl KeyBindState {
+    pub fn new() -> Self {
+        KeyBindState {
+            pressed: HashSet::new(),
+        }
+    }
+
+    pub fn is_pressed(&self, key: KeyCode) -> bool {
+        self.pressed.contains(&key)
+    }
+
+    pub fn set(&mut self, key: KeyCode, pressed: bool) {
+        if pressed {
+            self.pressed.insert(key);
+        } else {
+            self.pressed.remove(&key);
+        }
+    }
+
+    /// Updates the key's state and the target it's bound to, which comes from the `BindTable`. Returns whether the
+    /// controller changed
+    pub fn process_key_event(
+        &mut self,
+        state: &mut ControllerState,
+        taps: &Taps,
+        mapping: &KeyMapping,
+        key: KeyCode,
+        pressed: bool,
+        target: BindTarget,
+    ) -> bool {
+        self.set(key, pressed);
+        self.update_target(state, taps, mapping, target)
+    }
+
+    /// Sets the target from whether the keys bound to it are held
+    pub fn update_target(
+        &self,
+        state: &mut ControllerState,
+        taps: &Taps,
+        mapping: &KeyMapping,
+        target: BindTarget,
+    ) -> bool {
+        let held = |binds: &DirectionBinds| {
+            binds.combine(|bind| {
+                if self.is_held(taps, mapping, bind.key) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }) > 0.0
+        };
+        let binds = target.binds(mapping);
+        let changed = match target {
+            BindTarget::LeftJoystick(direction) => state
+                .left_joystick
+                .set_direction_state_digital(direction, held(binds)),
+            // The right stick is worked out every poll by the service, which reads the key state
+            BindTarget::RightJoystick(_) => false,
+            BindTarget::Button(_) => return self.update_buttons(state, taps, mapping),
+        };
+        // Stick binds can fully press a button as well
+        if binds.binds.iter().any(|bind| bind.full_press.is_some()) {
+            changed | self.update_buttons(state, taps, mapping)
+        } else {
+            changed
+        }
+    }
+
+    /// Sets every button from whether the keys bound to it, or that fully press it, are held
+    pub fn update_buttons(
+        &self,
+        state: &mut ControllerState,
+        taps: &Taps,
+        mapping: &KeyMapping,
+    ) -> bool {
+        state.update_button_key_states(mapping, &|key| self.is_held(taps, mapping, key))
+    }
+
+    /// Whether the key counts as held by its binds, which for a key that can be tapped is only once it's a hold
+    fn is_held(&self, taps: &Taps, mapping: &KeyMapping, key: KeyCode) -> bool {
+        let value = if self.is_pressed(key) { 1.0 } else { 0.0 };
+        taps.gate(&mapping.taps, key, value) > 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Actuation, Bind, FullPress, OutputType};
+    use crate::controller::{ControllerButton, JoystickDirection};
+
+    fn key(name: &str) -> KeyCode {
+        KeyCode::from_name(name).unwrap()
+    }
+
+    fn bound(pad: usize, target: BindTarget) -> BoundTarget {
+        BoundTarget { pad, target }
+    }
+
+    #[test]
+    fn looks_up_the_target_a_key_is_bound_to() {
+        let table = BindTable::new(&[ControllerProfile::default()]);
+
+        assert_eq!(
+            table.get(key("KeyW")),
+            [bound(0, BindTarget::LeftJoystick(JoystickDirection::Up))]
+        );
+        assert_eq!(
+            table.get(key("KeyD")),
+            [bound(0, BindTarget::LeftJoystick(JoystickDirection::Right))]
+        );
+        assert!(table.get(key("KeyQ")).is_empty());
+    }
+
+    #[test]
+    fn empty_without_controllers() {
+        let table = BindTable::new(&[]);
+
+        assert!(table.get(key("KeyW")).is_empty());
+        assert!(BindTable::default().get(key("KeyW")).is_empty());
+    }
+
+    #[test]
+    fn a_key_can_be_bound_to_several_targets() {
+        let mut profile = ControllerProfile::default();
+        let mapping = &mut profile.key_mapping;
+        mapping.right_joystick.up.binds.push(Bind::new(key("KeyW")));
+        mapping.buttons.a.binds.push(Bind::new(key("KeyW")));
+        mapping.buttons.a.binds.push(Bind::new(key("Space")));
+
+        let table = BindTable::new(&[profile]);
+
+        assert_eq!(
+            table.get(key("KeyW")),
+            [
+                bound(0, BindTarget::LeftJoystick(JoystickDirection::Up)),
+                bound(0, BindTarget::RightJoystick(JoystickDirection::Up)),
+                bound(0, BindTarget::Button(ControllerButton::A)),
+            ]
+        );
+        assert_eq!(
+            table.get(key("Space")),
+            [bound(0, BindTarget::Button(ControllerButton::A))]
+        );
+    }
+
+    #[test]
+    fn a_key_listed_twice_for_a_target_is_only_looked_up_once() {
+        let mut profile = ControllerProfile::default();
+        profile
+            .key_mapping
+            .left_joystick
+            .up
+            .binds
+            .push(Bind::new(key("KeyW")));
+
+        let table = BindTable::new(&[profile]);
+
+        assert_eq!(
+            table.get(key("KeyW")),
+            [bound(0, BindTarget::LeftJoystick(JoystickDirection::Up))]
+        );
+    }
+
+    #[test]
+    fn keys_are_looked_up_on_every_controller() {
+        let mut second = ControllerProfile::default();
+        second.key_mapping.left_joystick.up.binds = vec![Bind::new(key("ArrowUp"))];
+
+        let table = BindTable::new(&[ControllerProfile::default(), second]);
+
+        assert_eq!(
+            table.get(key("KeyS")),
+            [
+                bound(0, BindTarget::LeftJoystick(JoystickDirection::Down)),
+                bound(1, BindTarget::LeftJoystick(JoystickDirection::Down)),
+            ]
+        );
+        assert_eq!(
+            table.get(key("KeyW")),
+            [bound(0, BindTarget::LeftJoystick(JoystickDirection::Up))]
+        );
+        assert_eq!(
+            table.get(key("ArrowUp")),
+            [bound(1, BindTarget::LeftJoystick(JoystickDirection::Up))]
+        );
+    }
+
+    #[test]
+    fn rebuilding_follows_config_changes() {
+        let mut profile = ControllerProfile::default();
+        let table = BindTable::new(std::slice::from_ref(&profile));
+        assert!(table.get(key("KeyE")).is_empty());
+
+        profile.key_mapping.left_joystick.up.binds = vec![Bind::new(key("KeyE"))];
+        profile.key_mapping.buttons.b.binds = vec![Bind::new(key("KeyW"))];
+        let table = BindTable::new(std::slice::from_ref(&profile));
+
+        assert_eq!(
+            table.get(key("KeyE")),
+            [bound(0, BindTarget::LeftJoystick(JoystickDirection::Up))]
+        );
+        assert_eq!(
+            table.get(key("KeyW")),
+            [bound(0, BindTarget::Button(ControllerButton::B))]
+        );
+    }
+
+    fn up(state: &ControllerState) -> f32 {
+        state
+            .get_telemetry(None, OutputType::Xbox360)
+            .left_joystick
+            .raw
+            .up
+    }
+
+    /// Sends the key event to every target it's bound to, as the service does
+    fn press(
+        table: &BindTable,
+        profile: &ControllerProfile,
+        keys: &mut KeyBindState,
+        state: &mut ControllerState,
+        key: KeyCode,
+        pressed: bool,
+    ) -> bool {
+        let mut changed = false;
+        for bound in table.get(key) {
+            changed |= keys.process_key_event(
+                state,
+                &Taps::new(),
+                &profile.key_mapping,
+                key,
+                pressed,
+                bound.target,
+            );
+        }
+        changed
+    }
+
+    #[test]
+    fn key_events_update_the_targets() {
+        let mut profile = ControllerProfile::default();
+        profile.key_mapping.buttons.a.binds = vec![Bind::new(key("Space"))];
+        let mut full_press = Bind::new(key("KeyW"));
+        full_press.full_press = Some(FullPress {
+            button: ControllerButton::LeftThumb,
+            actuation: Actuation::default(),
+        });
+        profile.key_mapping.left_joystick.up.binds = vec![full_press];
+        let table = BindTable::new(std::slice::from_ref(&profile));
+        let mut keys = KeyBindState::new();
+        let mut state = ControllerState::new();
+
+        assert!(press(
+            &table,
+            &profile,
+            &mut keys,
+            &mut state,
+            key("KeyW"),
+            true
+        ));
+        assert!(keys.is_pressed(key("KeyW")));
+        assert_eq!(up(&state), 1.0);
+        assert!(state.buttons.is_pressed(ControllerButton::LeftThumb));
+
+        assert!(press(
+            &table,
+            &profile,
+            &mut keys,
+            &mut state,
+            key("Space"),
+            true
+        ));
+        assert!(state.buttons.is_pressed(ControllerButton::A));
+        // Pressing it again changes nothing
+        assert!(!press(
+            &table,
+            &profile,
+            &mut keys,
+            &mut state,
+            key("Space"),
+            true
+        ));
+
+        assert!(press(
+            &table,
+            &profile,
+            &mut keys,
+            &mut state,
+            key("KeyW"),
+            false
+        ));
+        assert!(!keys.is_pressed(key("KeyW")));
+        assert_eq!(up(&state), 0.0);
+        assert!(!state.buttons.is_pressed(ControllerButton::LeftThumb));
+        assert!(state.buttons.is_pressed(ControllerButton::A));
+
+        assert!(press(
+            &table,
+            &profile,
+            &mut keys,
+            &mut state,
+            key("Space"),
+            false
+        ));
+        assert!(!state.buttons.is_pressed(ControllerButton::A));
+        // Unbound keys are still tracked, but don't touch the controller
+        assert!(!press(
+            &table,
+            &profile,
+            &mut keys,
+            &mut state,
+            key("KeyQ"),
+            true
+        ));
+    }
+}
@@ -0,0 +1,474 @@
+use serde::Serialize;
+
+use std::fmt;
+
+use crate::config::{ButtonKeyMapping, ConfigError, DirectionBinds, KeyMapping};
+use crate::controller::{ControllerButton, JoystickDirection};
+use crate::keycode::KeyCode;
+
+/// Something on a controller that keys can be bound to
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(tag = "type", content = "value")]
+pub enum BindTarget {
+    LeftJoystick(JoystickDirection),
+    RightJoystick(JoystickDirection),
+    Button(ControllerButton),
+}
+
+impl BindTarget {
+    pub fn all() -> impl Iterator<Item = BindTarget> {
+        JoystickDirection::ALL
+            .iter()
+            .copied()
+            .map(BindTarget::LeftJoystick)
+            .chain(
+                JoystickDirection::ALL
+                    .iter()
+                    .copied()
+                    .map(BindTarget::RightJoystick),
+            )
+            .chain(
+                ControllerButton::ALL
+                    .iter()
+                    .copied()
+                    .map(BindTarget::Button),
+            )
+    }
+
+    /// The binds for this target in the key mapping
+    pub fn binds(self, mapping: &KeyMapping) -> &DirectionBinds {
+        let (joystick, direction) = match self {
+            BindTarget::LeftJoystick(direction) => (&mapping.left_joystick, direction),
+            BindTarget::RightJoystick(direction) => (&mapping.right_joystick, direction),
+            BindTarget::Button(button) => return mapping.buttons.binds(button),
+        };
+        match direction {
+            JoystickDirection::Up => &joystick.up,
+            JoystickDirection::Down => &joystick.down,
+            JoystickDirection::Left => &joystick.left,
+            JoystickDirection::Right => &joystick.right,
+        }
+    }
+
+    /// The path of the target's binds within the key mapping, the same form as `ConfigError::path`
+    fn path(self) -> String {
+        match self {
+            BindTarget::LeftJoystick(direction) => format!("leftJoystick.{}", direction.name()),
+            BindTarget::RightJoystick(direction) => format!("rightJoystick.{}", direction.name()),
+            BindTarget::Button(button) => {
+                format!("buttons.{}", ButtonKeyMapping::field_name(button))
+            }
+        }
+    }
+
+    /// The stick and direction, if the target is one
+    fn joystick(self) -> Option<(bool, JoystickDirection)> {
+        match self {
+            BindTarget::LeftJoystick(direction) => Some((false, direction)),
+            BindTarget::RightJoystick(direction) => Some((true, direction)),
+            BindTarget::Button(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for BindTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindTarget::LeftJoystick(direction) => write!(f, "left stick {}", direction.name()),
+            BindTarget::RightJoystick(direction) => write!(f, "right stick {}", direction.name()),
+            BindTarget::Button(button) => write!(f, "{:?}", button),
+        }
+    }
+}
+
+/// What a key bound to both `direction` and its opposite does when pressed on its own. The left stick goes up or right
+/// on a tie, as `get_basic_direction` favours them, where the right stick takes one direction from the other, see
+/// `Pad::update_right_joystick`
+fn opposite_directions_effect(right_joystick: bool, direction: JoystickDirection) -> String {
+    if right_joystick {
+        return "they cancel each other out and it never moves the stick".to_string();
+    }
+    let winner = match direction {
+        JoystickDirection::Up | JoystickDirection::Down => JoystickDirection::Up,
+        JoystickDirection::Left | JoystickDirection::Right => JoystickDirection::Right,
+    };
+    format!("it only ever pushes {}, which wins ties", winner.name())
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    /// Probably not what was meant, but the mapping still works
+    Warning,
+    /// The binds can't do what they say, e.g. a key that pushes both up and down and so never moves the stick
+    Error,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum BindingIssueKind {
+    /// The same key is bound to opposite directions of a stick. They always tie, so on the left stick the key only
+    /// ever pushes the direction that wins ties, and on the right stick it never moves it at all
+    Conflict,
+    /// The same key is bound to two targets, e.g. two directions next to each other so it only ever moves
+    /// diagonally, or a stick and a button so it does both at once
+    SharedKey,
+    /// The key is already bound earlier in the same target, so this bind adds nothing
+    Shadowed,
+    /// Nothing is bound to the stick direction
+    Unbound,
+    /// The key has a tap, so its other binds don't do anything until the tap window has passed
+    Tapped,
+}
+
+/// Something found by `analyze`. `path` points at the offending bind or target the same way `ConfigError` does
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BindingIssue {
+    pub kind: BindingIssueKind,
+    pub severity: Severity,
+    pub path: String,
+    pub key: Option<KeyCode>,
+    pub targets: Vec<BindTarget>,
+    pub message: String,
+}
+
+/// Looks for binds that clash with each other or can never do anything. `path` is the JSON path of the key mapping,
+/// which each issue's path is relative to. Every stick direction, button and tap is checked, but only the left stick
+/// and a right stick with anything bound to it are expected to have every direction bound
+pub fn analyze(mapping: &KeyMapping, path: &str) -> Vec<BindingIssue> {
+    let targets: Vec<_> = BindTarget::all()
+        .map(|target| (target, target.binds(mapping)))
+        .collect();
+    let right_joystick_used = mapping.right_joystick.keys().next().is_some();
+    let mut issues = vec![];
+
+    for (i, (target, binds)) in targets.iter().enumerate() {
+        let target_path = format!("{}.{}", path, target.path());
+        let expects_binds = match target {
+            BindTarget::LeftJoystick(_) => true,
+            BindTarget::RightJoystick(_) => right_joystick_used,
+            BindTarget::Button(_) => false,
+        };
+        if expects_binds && binds.binds.is_empty() {
+            issues.push(BindingIssue {
+                kind: BindingIssueKind::Unbound,
+                severity: Severity::Warning,
+                path: target_path.clone(),
+                key: None,
+                targets: vec![*target],
+                message: format!("Nothing is bound to {}", target),
+            });
+        }
+
+        for (j, bind) in binds.binds.iter().enumerate() {
+            let bind_path = format!("{}.binds[{}]", target_path, j);
+            if let Some(first) = binds.binds[..j].iter().position(|b| b.key == bind.key) {
+                issues.push(BindingIssue {
+                    kind: BindingIssueKind::Shadowed,
+                    severity: Severity::Warning,
+                    path: bind_path,
+                    key: Some(bind.key),
+                    targets: vec![*target],
+                    message: format!(
+                        "{} is already bound to {} by binds[{}]",
+                        bind.key, target, first
+                    ),
+                });
+                continue;
+            }
+
+            // Only compare against the targets after this one, so each pair is reported once
+            for (other, other_binds) in &targets[i + 1..] {
+                if !other_binds.contains(bind.key) {
+                    continue;
+                }
+                let (kind, severity, effect) = match (target.joystick(), other.joystick()) {
+                    (Some((right, direction)), Some((other_right, other_direction)))
+                        if right == other_right && other_direction == direction.opposite() =>
+                    {
+                        (
+                            BindingIssueKind::Conflict,
+                            Severity::Error,
+                            opposite_directions_effect(right, direction),
+                        )
+                    }
+                    (Some((right, _)), Some((other_right, _))) if right == other_right => (
+                        BindingIssueKind::SharedKey,
+                        Severity::Warning,
+                        "it only moves diagonally".to_string(),
+                    ),
+                    _ => (
+                        BindingIssueKind::SharedKey,
+                        Severity::Warning,
+                        "it does both at once".to_string(),
+                    ),
+                };
+                issues.push(BindingIssue {
+                    kind,
+                    severity,
+                    path: bind_path.clone(),
+                    key: Some(bind.key),
+                    targets: vec![*target, *other],
+                    message: format!(
+                        "{} is bound to both {} and {}, so {}",
+                        bind.key, target, other, effect
+                    ),
+                });
+            }
+        }
+    }
+
+    for (i, tap) in mapping.taps.iter().enumerate() {
+        let bound: Vec<_> = targets
+            .iter()
+            .filter(|(_, binds)| binds.contains(tap.key))
+            .map(|(target, _)| *target)
+            .collect();
+        if bound.is_empty() {
+            continue;
+        }
+        let names: Vec<_> = bound.iter().map(ToString::to_string).collect();
+        issues.push(BindingIssue {
+            kind: BindingIssueKind::Tapped,
+            severity: Severity::Warning,
+            path: format!("{}.taps[{}].key", path, i),
+            key: Some(tap.key),
+            message: format!(
+                "{} taps {:?}, so its binds to {} do nothing until it's been held for {}ms",
+                tap.key,
+                tap.button,
+                names.join(" or "),
+                tap.window_ms
+            ),
+            targets: bound,
+        });
+    }
+
+    issues
+}
+
+/// The issues that should stop a config being applied, as `ConfigError`s
+pub fn hard_conflicts(mapping: &KeyMapping, path: &str) -> Vec<ConfigError> {
+    analyze(mapping, path)
+        .into_iter()
+        .filter(|issue| issue.severity == Severity::Error)
+        .map(|issue| ConfigError::new(issue.path, issue.message))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Bind, TapBind};
+
+    fn key(name: &str) -> KeyCode {
+        KeyCode::from_name(name).unwrap()
+    }
+
+    fn bind(mapping: &mut KeyMapping, target: BindTarget, name: &str) {
+        let binds = match target {
+            BindTarget::LeftJoystick(direction) => {
+                direction_binds(&mut mapping.left_joystick, direction)
+            }
+            BindTarget::RightJoystick(direction) => {
+                direction_binds(&mut mapping.right_joystick, direction)
+            }
+            BindTarget::Button(ControllerButton::A) => &mut mapping.buttons.a,
+            BindTarget::Button(button) => panic!("{:?} isn't used by the tests", button),
+        };
+        binds.binds.push(Bind::new(key(name)));
+    }
+
+    fn direction_binds(
+        joystick: &mut crate::config::JoystickKeyMapping,
+        direction: JoystickDirection,
+    ) -> &mut DirectionBinds {
+        match direction {
+            JoystickDirection::Up => &mut joystick.up,
+            JoystickDirection::Down => &mut joystick.down,
+            JoystickDirection::Left => &mut joystick.left,
+            JoystickDirection::Right => &mut joystick.right,
+        }
+    }
+
+    fn issues_of(mapping: &KeyMapping, kind: BindingIssueKind) -> Vec<BindingIssue> {
+        analyze(mapping, "$")
+            .into_iter()
+            .filter(|issue| issue.kind == kind)
+            .collect()
+    }
+
+    const LEFT_UP: BindTarget = BindTarget::LeftJoystick(JoystickDirection::Up);
+    const LEFT_DOWN: BindTarget = BindTarget::LeftJoystick(JoystickDirection::Down);
+    const LEFT_LEFT: BindTarget = BindTarget::LeftJoystick(JoystickDirection::Left);
+    const LEFT_RIGHT: BindTarget = BindTarget::LeftJoystick(JoystickDirection::Right);
+    const RIGHT_UP: BindTarget = BindTarget::RightJoystick(JoystickDirection::Up);
+    const RIGHT_DOWN: BindTarget = BindTarget::RightJoystick(JoystickDirection::Down);
+    const BUTTON_A: BindTarget = BindTarget::Button(ControllerButton::A);
+
+    #[test]
+    fn the_default_mapping_has_no_issues() {
+        assert_eq!(analyze(&KeyMapping::default(), "$"), vec![]);
+    }
+
+    #[test]
+    fn opposite_directions_push_the_one_that_wins_ties() {
+        let mut mapping = KeyMapping::default();
+        bind(&mut mapping, LEFT_DOWN, "KeyW");
+        bind(&mut mapping, LEFT_LEFT, "KeyD");
+
+        let issues = issues_of(&mapping, BindingIssueKind::Conflict);
+
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].severity, Severity::Error);
+        assert_eq!(issues[0].path, "$.leftJoystick.up.binds[0]");
+        assert_eq!(issues[0].targets, vec![LEFT_UP, LEFT_DOWN]);
+        assert!(issues[0]
+            .message
+            .ends_with("so it only ever pushes up, which wins ties"));
+        assert_eq!(issues[1].path, "$.leftJoystick.left.binds[1]");
+        assert_eq!(issues[1].targets, vec![LEFT_LEFT, LEFT_RIGHT]);
+        assert!(issues[1]
+            .message
+            .ends_with("so it only ever pushes right, which wins ties"));
+    }
+
+    #[test]
+    fn neighbouring_directions_only_move_diagonally() {
+        let mut mapping = KeyMapping::default();
+        bind(&mut mapping, LEFT_RIGHT, "KeyW");
+
+        let issues = analyze(&mapping, "$");
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, BindingIssueKind::SharedKey);
+        assert_eq!(issues[0].severity, Severity::Warning);
+        assert_eq!(issues[0].targets, vec![LEFT_UP, LEFT_RIGHT]);
+        assert!(issues[0].message.ends_with("so it only moves diagonally"));
+    }
+
+    #[test]
+    fn checks_the_right_stick() {
+        let mut mapping = KeyMapping::default();
+        bind(&mut mapping, RIGHT_UP, "ArrowUp");
+        bind(&mut mapping, RIGHT_DOWN, "ArrowUp");
+
+        let conflicts = issues_of(&mapping, BindingIssueKind::Conflict);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, "$.rightJoystick.up.binds[0]");
+        assert_eq!(conflicts[0].targets, vec![RIGHT_UP, RIGHT_DOWN]);
+        assert!(
+            conflicts[0]
+                .message
+                .ends_with("so they cancel each other out and it never moves the stick"),
+            "{}",
+            conflicts[0].message
+        );
+
+        let unbound: Vec<_> = issues_of(&mapping, BindingIssueKind::Unbound)
+            .into_iter()
+            .map(|issue| issue.path)
+            .collect();
+        assert_eq!(
+            unbound,
+            vec!["$.rightJoystick.left", "$.rightJoystick.right"]
+        );
+    }
+
+    #[test]
+    fn an_empty_right_stick_is_not_unbound_but_the_left_one_is() {
+        let mut mapping = KeyMapping::default();
+        mapping.left_joystick.up.binds.clear();
+
+        let issues = analyze(&mapping, "$");
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, BindingIssueKind::Unbound);
+        assert_eq!(issues[0].path, "$.leftJoystick.up");
+        assert_eq!(issues[0].message, "Nothing is bound to left stick up");
+    }
+
+    #[test]
+    fn a_key_on_a_stick_and_a_button_does_both() {
+        let mut mapping = KeyMapping::default();
+        bind(&mut mapping, BUTTON_A, "KeyW");
+        bind(&mut mapping, RIGHT_UP, "KeyS");
+        bind(&mut mapping, RIGHT_DOWN, "ArrowDown");
+        mapping
+            .right_joystick
+            .left
+            .binds
+            .push(Bind::new(key("ArrowLeft")));
+        mapping
+            .right_joystick
+            .right
+            .binds
+            .push(Bind::new(key("ArrowRight")));
+
+        let issues = issues_of(&mapping, BindingIssueKind::SharedKey);
+
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].path, "$.leftJoystick.up.binds[0]");
+        assert_eq!(issues[0].targets, vec![LEFT_UP, BUTTON_A]);
+        assert!(issues[0]
+            .message
+            .ends_with("left stick up and A, so it does both at once"));
+        assert_eq!(issues[1].path, "$.leftJoystick.down.binds[0]");
+        assert_eq!(issues[1].targets, vec![LEFT_DOWN, RIGHT_UP]);
+        assert_eq!(issues_of(&mapping, BindingIssueKind::Conflict), vec![]);
+    }
+
+    #[test]
+    fn a_key_bound_twice_to_a_button_is_shadowed() {
+        let mut mapping = KeyMapping::default();
+        bind(&mut mapping, BUTTON_A, "Space");
+        bind(&mut mapping, BUTTON_A, "Space");
+
+        let issues = analyze(&mapping, "$");
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, BindingIssueKind::Shadowed);
+        assert_eq!(issues[0].path, "$.buttons.a.binds[1]");
+    }
+
+    #[test]
+    fn a_key_shared_between_a_tap_and_a_stick_is_reported() {
+        let mut mapping = KeyMapping::default();
+        mapping.taps.push(TapBind {
+            key: key("KeyW"),
+            button: ControllerButton::Y,
+            window_ms: 200,
+            press_ms: 50,
+        });
+        mapping.taps.push(TapBind {
+            key: key("KeyE"),
+            button: ControllerButton::X,
+            window_ms: 200,
+            press_ms: 50,
+        });
+
+        let issues = analyze(&mapping, "$");
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, BindingIssueKind::Tapped);
+        assert_eq!(issues[0].severity, Severity::Warning);
+        assert_eq!(issues[0].path, "$.taps[0].key");
+        assert_eq!(issues[0].targets, vec![LEFT_UP]);
+        assert!(issues[0].message.ends_with(
+            "taps Y, so its binds to left stick up do nothing until it's been held for 200ms"
+        ));
+    }
+
+    #[test]
+    fn only_conflicts_are_hard() {
+        let mut mapping = KeyMapping::default();
+        bind(&mut mapping, LEFT_DOWN, "KeyW");
+        bind(&mut mapping, LEFT_RIGHT, "KeyW");
+
+        let errors = hard_conflicts(&mapping, "$.controllers[0].keyMapping");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].path,
+            "$.controllers[0].keyMapping.leftJoystick.up.binds[0]"
+        );
+    }
+}
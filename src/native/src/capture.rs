@@ -0,0 +1,197 @@
+// Only the service uses this, which is Windows only, but it's built everywhere for its tests
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::config::BindSource;
+use crate::keycode::KeyCode;
+
+/// How far an analog key has to be pressed to be captured when no threshold is given
+pub const DEFAULT_CAPTURE_THRESHOLD: f32 = 0.5;
+
+/// The key picked up by a bind capture, in the same form the service reads binds in
+#[derive(Serialize, Clone, Debug)]
+pub struct CapturedBind {
+    pub key: KeyCode,
+    /// The key's name if it has one, for showing in the UI
+    pub name: Option<&'static str>,
+    /// Digital when the key was only seen as a key state, as there's no analog value to read for it
+    pub source: BindSource,
+    /// The analog value that triggered the capture, 1 for a key state
+    pub value: f32,
+}
+
+/// Waits for the next key to be pressed so it can be bound. Anything already held when the capture starts is ignored
+/// until it's released, so the key that started the capture doesn't get bound
+pub struct BindCapture {
+    threshold: f32,
+    /// `None` until the first update, which is when we find out what's already held
+    held: Option<HashSet<KeyCode>>,
+}
+
+impl BindCapture {
+    pub fn new(threshold: f32) -> Self {
+        BindCapture {
+            threshold: threshold.clamp(f32::EPSILON, 1.0),
+            held: None,
+        }
+    }
+
+    /// Looks for a new press in the analog values and the keys that are down. A key with an analog value is only
+    /// captured once it passes the threshold, even if its key state is already down, so analog keys are always
+    /// bound as analog
+    pub fn update(
+        &mut self,
+        analog: &HashMap<u16, f32>,
+        pressed: &HashSet<KeyCode>,
+    ) -> Option<CapturedBind> {
+        let analog_value = |key: &KeyCode| analog.get(&key.0).copied().unwrap_or(0.0);
+        let is_down = |key: &KeyCode| pressed.contains(key) || analog_value(key) > 0.0;
+
+        let threshold = self.threshold;
+        let held = match self.held.as_mut() {
+            Some(held) => held,
+            None => {
+                let held = pressed
+                    .iter()
+                    .copied()
+                    .chain(analog.keys().map(|code| KeyCode(*code)))
+                    .filter(|key| is_down(key))
+                    .collect();
+                self.held = Some(held);
+                return None;
+            }
+        };
+        held.retain(|key| is_down(key));
+
+        // Take the furthest pressed key if several passed the threshold in the same poll
+        let analog_press = analog
+            .iter()
+            .map(|(code, value)| (KeyCode(*code), *value))
+            .filter(|(key, value)| *value >= threshold && !held.contains(key))
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        if let Some((key, value)) = analog_press {
+            return Some(CapturedBind {
+                key,
+                name: key.name(),
+                source: BindSource::Auto,
+                value,
+            });
+        }
+
+        pressed
+            .iter()
+            .find(|key| !held.contains(key) && analog_value(key) <= 0.0)
+            .map(|key| CapturedBind {
+                key: *key,
+                name: key.name(),
+                source: BindSource::Digital,
+                value: 1.0,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> KeyCode {
+        KeyCode::from_name(name).unwrap()
+    }
+
+    fn analog(values: &[(&str, f32)]) -> HashMap<u16, f32> {
+        values
+            .iter()
+            .map(|(name, value)| (key(name).0, *value))
+            .collect()
+    }
+
+    fn pressed(names: &[&str]) -> HashSet<KeyCode> {
+        names.iter().map(|name| key(name)).collect()
+    }
+
+    /// Starts a capture with nothing held
+    fn capture(threshold: f32) -> BindCapture {
+        let mut capture = BindCapture::new(threshold);
+        assert!(capture.update(&HashMap::new(), &HashSet::new()).is_none());
+        capture
+    }
+
+    fn captured(bind: Option<CapturedBind>) -> (KeyCode, BindSource, f32) {
+        let bind = bind.expect("nothing was captured");
+        assert_eq!(bind.name, bind.key.name());
+        (bind.key, bind.source, bind.value)
+    }
+
+    #[test]
+    fn analog_keys_are_captured_once_past_the_threshold() {
+        let mut capture = capture(0.5);
+
+        assert!(capture
+            .update(&analog(&[("KeyW", 0.4)]), &pressed(&["KeyW"]))
+            .is_none());
+        assert_eq!(
+            captured(capture.update(&analog(&[("KeyW", 0.5), ("KeyA", 0.7)]), &pressed(&[]))),
+            (key("KeyA"), BindSource::Auto, 0.7)
+        );
+    }
+
+    #[test]
+    fn keys_without_an_analog_value_are_captured_as_digital() {
+        let mut capture = capture(0.5);
+
+        assert_eq!(
+            captured(capture.update(&analog(&[("KeyW", 0.1)]), &pressed(&["MouseLeft"]))),
+            (key("MouseLeft"), BindSource::Digital, 1.0)
+        );
+    }
+
+    #[test]
+    fn threshold_is_clamped() {
+        let cases = [
+            (0.0, 0.0001, true),
+            (-1.0, 0.0001, true),
+            (5.0, 0.99, false),
+            (5.0, 1.0, true),
+        ];
+        for &(threshold, value, expected) in cases.iter() {
+            let mut capture = capture(threshold);
+            let bind = capture.update(&analog(&[("KeyW", value)]), &pressed(&[]));
+            assert_eq!(
+                bind.is_some(),
+                expected,
+                "threshold {} with {}",
+                threshold,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn keys_held_at_the_start_are_ignored_until_released() {
+        let mut capture = BindCapture::new(0.5);
+        let held_analog = analog(&[("KeyW", 0.8)]);
+        let held_keys = pressed(&["Enter"]);
+
+        assert!(capture.update(&held_analog, &held_keys).is_none());
+        assert!(capture.update(&held_analog, &held_keys).is_none());
+        assert!(capture
+            .update(&analog(&[("KeyW", 0.0)]), &HashSet::new())
+            .is_none());
+
+        assert_eq!(
+            captured(capture.update(&analog(&[("KeyW", 0.9)]), &HashSet::new())),
+            (key("KeyW"), BindSource::Auto, 0.9)
+        );
+
+        let mut capture = BindCapture::new(0.5);
+        assert!(capture.update(&HashMap::new(), &held_keys).is_none());
+        assert!(capture.update(&HashMap::new(), &HashSet::new()).is_none());
+        assert_eq!(
+            captured(capture.update(&HashMap::new(), &held_keys)),
+            (key("Enter"), BindSource::Digital, 1.0)
+        );
+    }
+}
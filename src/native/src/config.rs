@@ -1,8 +1,18 @@
-#[cfg(windows)]
-use multiinput::{KeyId, ToPrimitive};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+
+use crate::bindings;
+use crate::controller::{ControllerButton, JoystickDirection, ANALOG_BUTTON_THRESHOLD};
+use crate::keycode::KeyCode;
+use crate::migration;
+
+/// The version of the configuration format this build understands. Older configurations are upgraded
+/// to this version by the migrations in `migration.rs` before being deserialized
+pub const CONFIG_VERSION: u32 = 4;
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct JoystickAngleConfiguration {
     #[serde(rename = "upDiagonalAngle")]
     pub up_diagonal_angle: f32,
@@ -17,74 +27,1284 @@ pub struct JoystickAngleConfiguration {
     pub analog_range: (f32, f32),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Default for JoystickAngleConfiguration {
+    fn default() -> Self {
+        JoystickAngleConfiguration {
+            up_diagonal_angle: 0.67,
+            use_left_right_angle: false,
+            left_right_angle: 0.78,
+            analog_range: (0.0, 1.0),
+        }
+    }
+}
+
+/// Where a single bind reads its value from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BindSource {
+    /// Follows the input the service is currently using, analog or digital
+    Auto,
+    /// Always reads the key state, so non-analog keyboards, mouse buttons and pedals can be used alongside analog keys
+    Digital,
+}
+
+impl Default for BindSource {
+    fn default() -> Self {
+        BindSource::Auto
+    }
+}
+
+/// When a bind presses the button it's bound to. Stick directions don't use this, they follow how far the key is
+/// pressed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "mode")]
+pub enum Actuation {
+    /// Pressed once the key goes down to `press` and released once it comes back up past `release`. Keeping `release`
+    /// below `press` stops the button chattering while the key is held around the actuation point
+    Threshold { press: f32, release: f32 },
+    /// Pressed once the key goes down to `press`. From then on the button is released as soon as the key comes up by
+    /// `delta` from the deepest point it reached, and pressed again once it goes back down by `delta`, so it can be
+    /// tapped without letting the key all the way up. Coming back up past `press` always releases it
+    RapidTrigger { press: f32, delta: f32 },
+}
+
+impl Default for Actuation {
+    fn default() -> Self {
+        Actuation::Threshold {
+            press: ANALOG_BUTTON_THRESHOLD,
+            release: ANALOG_BUTTON_THRESHOLD,
+        }
+    }
+}
+
+impl Actuation {
+    fn validate(&self, path: &str, errors: &mut Vec<ConfigError>) {
+        let (press, other, other_name) = match *self {
+            Actuation::Threshold { press, release } => (press, release, "release"),
+            Actuation::RapidTrigger { press, delta } => (press, delta, "delta"),
+        };
+        for (name, value) in [("press", press), (other_name, other)].iter() {
+            if !value.is_finite() || *value <= 0.0 || *value > 1.0 {
+                errors.push(ConfigError::new(
+                    format!("{}.{}", path, name),
+                    format!("Must be greater than 0 and at most 1, got {}", value),
+                ));
+            }
+        }
+        if let Actuation::Threshold { press, release } = *self {
+            if release > press {
+                errors.push(ConfigError::new(
+                    format!("{}.release", path),
+                    format!("Must be no deeper than press ({}), got {}", press, release),
+                ));
+            }
+        }
+    }
+}
+
+/// A button a stick bind also presses once its key goes down far enough, e.g. to sprint with the key bottomed out
+/// while a partial press walks. The stick keeps moving either way. Binds read digitally always count as fully pressed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FullPress {
+    pub button: ControllerButton,
+    #[serde(default = "FullPress::default_actuation")]
+    pub actuation: Actuation,
+}
+
+impl FullPress {
+    fn default_actuation() -> Actuation {
+        Actuation::Threshold {
+            press: 0.9,
+            release: 0.8,
+        }
+    }
+}
+
+/// A key bound to a direction
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Bind {
+    pub key: KeyCode,
+    #[serde(default)]
+    pub source: BindSource,
+    /// Only used when the bind is for a button
+    #[serde(default)]
+    pub actuation: Actuation,
+    /// Only used when the bind is for a stick direction
+    #[serde(default, rename = "fullPress")]
+    pub full_press: Option<FullPress>,
+}
+
+impl Bind {
+    pub fn new(key: KeyCode) -> Self {
+        Bind {
+            key,
+            source: BindSource::default(),
+            actuation: Actuation::default(),
+            full_press: None,
+        }
+    }
+}
+
+/// How the values of a direction's binds are combined into one
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CombineRule {
+    /// The most pressed bind wins
+    Max,
+    /// The binds are added together, capped at fully pressed
+    SumClamped,
+    /// The first bind in the list that's pressed at all wins, so binds are listed in priority order
+    FirstNonZero,
+}
+
+impl Default for CombineRule {
+    fn default() -> Self {
+        CombineRule::Max
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct DirectionBinds {
+    pub binds: Vec<Bind>,
+    pub combine: CombineRule,
+}
+
+impl DirectionBinds {
+    fn from_keys(keys: &[&str]) -> Self {
+        DirectionBinds {
+            binds: keys
+                .iter()
+                .filter_map(|name| KeyCode::from_name(name))
+                .map(Bind::new)
+                .collect(),
+            combine: CombineRule::default(),
+        }
+    }
+
+    pub fn contains(&self, key: KeyCode) -> bool {
+        self.binds.iter().any(|bind| bind.key == key)
+    }
+
+    /// Combines the value of each bind, as given by `read`, according to the combine rule
+    pub fn combine<F: FnMut(&Bind) -> f32>(&self, read: F) -> f32 {
+        let mut values = self.binds.iter().map(read);
+        match self.combine {
+            CombineRule::Max => values.fold(0.0, f32::max),
+            CombineRule::SumClamped => values.sum::<f32>().min(1.0),
+            CombineRule::FirstNonZero => values.find(|&v| v > 0.0).unwrap_or(0.0),
+        }
+    }
+}
+
+/// Configs can also use the legacy shape, which is upgraded before deserializing, see `LegacyJoystickKeyMapping`
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct JoystickKeyMapping {
-    pub up: Option<u8>,
-    pub up_two: Option<u8>,
-    pub left: Option<u8>,
-    pub left_two: Option<u8>,
-    pub down: Option<u8>,
-    pub down_two: Option<u8>,
-    pub right: Option<u8>,
-    pub right_two: Option<u8>,
+    pub up: DirectionBinds,
+    pub down: DirectionBinds,
+    pub left: DirectionBinds,
+    pub right: DirectionBinds,
+}
+
+impl JoystickKeyMapping {
+    /// Every key bound to any direction
+    pub fn keys(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.up
+            .binds
+            .iter()
+            .chain(&self.down.binds)
+            .chain(&self.left.binds)
+            .chain(&self.right.binds)
+            .map(|bind| bind.key)
+    }
+
+    pub fn directions(&self) -> [(JoystickDirection, &DirectionBinds); 4] {
+        [
+            (JoystickDirection::Up, &self.up),
+            (JoystickDirection::Down, &self.down),
+            (JoystickDirection::Left, &self.left),
+            (JoystickDirection::Right, &self.right),
+        ]
+    }
+
+    fn validate(&self, path: &str, output: OutputType, errors: &mut Vec<ConfigError>) {
+        let directions = [
+            ("up", &self.up),
+            ("down", &self.down),
+            ("left", &self.left),
+            ("right", &self.right),
+        ];
+        for (name, binds) in directions.iter() {
+            for (i, bind) in binds.binds.iter().enumerate() {
+                let bind_path = format!("{}.{}.binds[{}]", path, name, i);
+                if bind.actuation != Actuation::default() {
+                    errors.push(ConfigError::new(
+                        format!("{}.actuation", bind_path),
+                        "Stick binds follow how far the key is pressed, only button binds have an actuation",
+                    ));
+                }
+                if let Some(full_press) = &bind.full_press {
+                    full_press
+                        .actuation
+                        .validate(&format!("{}.fullPress.actuation", bind_path), errors);
+                    if !full_press.button.exists_on(output) {
+                        errors.push(ConfigError::new(
+                            format!("{}.fullPress.button", bind_path),
+                            format!(
+                                "{:?} controllers don't have {:?}",
+                                output, full_press.button
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `JoystickKeyMapping` used to have exactly two binds per direction, e.g. `up` and `up_two`, with an optional source
+/// for each. That shape is still accepted so configs don't need to be rewritten
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LegacyJoystickKeyMapping {
+    up: Option<KeyCode>,
+    up_two: Option<KeyCode>,
+    left: Option<KeyCode>,
+    left_two: Option<KeyCode>,
+    down: Option<KeyCode>,
+    down_two: Option<KeyCode>,
+    right: Option<KeyCode>,
+    right_two: Option<KeyCode>,
+    sources: LegacyBindSources,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LegacyBindSources {
+    up: BindSource,
+    up_two: BindSource,
+    left: BindSource,
+    left_two: BindSource,
+    down: BindSource,
+    down_two: BindSource,
+    right: BindSource,
+    right_two: BindSource,
+}
+
+fn legacy_direction(binds: [(Option<KeyCode>, BindSource); 2]) -> DirectionBinds {
+    DirectionBinds {
+        binds: binds
+            .iter()
+            .filter_map(|&(key, source)| {
+                key.map(|key| Bind {
+                    source,
+                    ..Bind::new(key)
+                })
+            })
+            .collect(),
+        combine: CombineRule::Max,
+    }
+}
+
+impl LegacyJoystickKeyMapping {
+    /// The legacy shape has a key or nothing for each direction, where the current one has an object of binds, and
+    /// fields the current one doesn't have
+    fn is_legacy(value: &Value) -> bool {
+        let mapping = match value.as_object() {
+            Some(mapping) => mapping,
+            None => return false,
+        };
+        let legacy_only = ["up_two", "down_two", "left_two", "right_two", "sources"];
+        legacy_only.iter().any(|field| mapping.contains_key(*field))
+            || ["up", "down", "left", "right"]
+                .iter()
+                .filter_map(|field| mapping.get(*field))
+                .any(|direction| !direction.is_object())
+    }
+}
+
+impl From<LegacyJoystickKeyMapping> for JoystickKeyMapping {
+    fn from(mapping: LegacyJoystickKeyMapping) -> Self {
+        let sources = mapping.sources;
+        JoystickKeyMapping {
+            up: legacy_direction([(mapping.up, sources.up), (mapping.up_two, sources.up_two)]),
+            down: legacy_direction([
+                (mapping.down, sources.down),
+                (mapping.down_two, sources.down_two),
+            ]),
+            left: legacy_direction([
+                (mapping.left, sources.left),
+                (mapping.left_two, sources.left_two),
+            ]),
+            right: legacy_direction([
+                (mapping.right, sources.right),
+                (mapping.right_two, sources.right_two),
+            ]),
+        }
+    }
+}
+
+/// Rewrites every joystick key mapping that's still in the legacy shape into the current one. The shape is decided
+/// up front rather than by trying each in turn, so a mistake in either is reported at the field that's wrong
+fn upgrade_legacy_joysticks(config: &mut Value) -> Result<(), ConfigError> {
+    let controllers = match config.get_mut("controllers").and_then(Value::as_array_mut) {
+        Some(controllers) => controllers,
+        None => return Ok(()),
+    };
+    for (i, controller) in controllers.iter_mut().enumerate() {
+        for field in &["leftJoystick", "rightJoystick"] {
+            let joystick = match controller
+                .get_mut("keyMapping")
+                .and_then(|mapping| mapping.get_mut(*field))
+            {
+                Some(joystick) if LegacyJoystickKeyMapping::is_legacy(joystick) => joystick,
+                _ => continue,
+            };
+            let legacy: LegacyJoystickKeyMapping =
+                serde_path_to_error::deserialize(joystick.take()).map_err(|e| {
+                    let path = format!("$.controllers[{}].keyMapping.{}", i, field);
+                    ConfigError::new(error_path(&path, e.path()), e.into_inner().to_string())
+                })?;
+            *joystick = serde_json::to_value(JoystickKeyMapping::from(legacy))
+                .expect("Failed to serialize a joystick key mapping");
+        }
+    }
+    Ok(())
+}
+
+/// Appends where deserializing failed to the path of what was being deserialized
+fn error_path(root: &str, path: &serde_path_to_error::Path) -> String {
+    if path.iter().next().is_none() {
+        root.to_string()
+    } else {
+        format!("{}.{}", root, path)
+    }
+}
+
+/// Binds for the controller's buttons, a button is pressed while its binds combine to more than nothing.
+/// `touchpadClick` is ignored for Xbox 360 controllers as they don't have a touchpad
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ButtonKeyMapping {
+    pub a: DirectionBinds,
+    pub b: DirectionBinds,
+    pub x: DirectionBinds,
+    pub y: DirectionBinds,
+    #[serde(rename = "leftShoulder")]
+    pub left_shoulder: DirectionBinds,
+    #[serde(rename = "rightShoulder")]
+    pub right_shoulder: DirectionBinds,
+    #[serde(rename = "leftTrigger")]
+    pub left_trigger: DirectionBinds,
+    #[serde(rename = "rightTrigger")]
+    pub right_trigger: DirectionBinds,
+    #[serde(rename = "leftThumb")]
+    pub left_thumb: DirectionBinds,
+    #[serde(rename = "rightThumb")]
+    pub right_thumb: DirectionBinds,
+    #[serde(rename = "dpadUp")]
+    pub dpad_up: DirectionBinds,
+    #[serde(rename = "dpadDown")]
+    pub dpad_down: DirectionBinds,
+    #[serde(rename = "dpadLeft")]
+    pub dpad_left: DirectionBinds,
+    #[serde(rename = "dpadRight")]
+    pub dpad_right: DirectionBinds,
+    #[serde(rename = "touchpadClick")]
+    pub touchpad_click: DirectionBinds,
+    pub ps: DirectionBinds,
+    pub share: DirectionBinds,
+    pub options: DirectionBinds,
+}
+
+impl ButtonKeyMapping {
+    pub fn binds(&self, button: ControllerButton) -> &DirectionBinds {
+        match button {
+            ControllerButton::A => &self.a,
+            ControllerButton::B => &self.b,
+            ControllerButton::X => &self.x,
+            ControllerButton::Y => &self.y,
+            ControllerButton::LeftShoulder => &self.left_shoulder,
+            ControllerButton::RightShoulder => &self.right_shoulder,
+            ControllerButton::LeftTrigger => &self.left_trigger,
+            ControllerButton::RightTrigger => &self.right_trigger,
+            ControllerButton::LeftThumb => &self.left_thumb,
+            ControllerButton::RightThumb => &self.right_thumb,
+            ControllerButton::DpadUp => &self.dpad_up,
+            ControllerButton::DpadDown => &self.dpad_down,
+            ControllerButton::DpadLeft => &self.dpad_left,
+            ControllerButton::DpadRight => &self.dpad_right,
+            ControllerButton::TouchpadClick => &self.touchpad_click,
+            ControllerButton::PS => &self.ps,
+            ControllerButton::Share => &self.share,
+            ControllerButton::Options => &self.options,
+        }
+    }
+
+    /// The name of the button's field, for error paths
+    pub fn field_name(button: ControllerButton) -> &'static str {
+        match button {
+            ControllerButton::A => "a",
+            ControllerButton::B => "b",
+            ControllerButton::X => "x",
+            ControllerButton::Y => "y",
+            ControllerButton::LeftShoulder => "leftShoulder",
+            ControllerButton::RightShoulder => "rightShoulder",
+            ControllerButton::LeftTrigger => "leftTrigger",
+            ControllerButton::RightTrigger => "rightTrigger",
+            ControllerButton::LeftThumb => "leftThumb",
+            ControllerButton::RightThumb => "rightThumb",
+            ControllerButton::DpadUp => "dpadUp",
+            ControllerButton::DpadDown => "dpadDown",
+            ControllerButton::DpadLeft => "dpadLeft",
+            ControllerButton::DpadRight => "dpadRight",
+            ControllerButton::TouchpadClick => "touchpadClick",
+            ControllerButton::PS => "ps",
+            ControllerButton::Share => "share",
+            ControllerButton::Options => "options",
+        }
+    }
+
+    /// Every key bound to any button
+    pub fn keys(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        ControllerButton::ALL
+            .iter()
+            .flat_map(move |button| self.binds(*button).binds.iter())
+            .map(|bind| bind.key)
+    }
+
+    fn validate(&self, path: &str, errors: &mut Vec<ConfigError>) {
+        for button in ControllerButton::ALL.iter().copied() {
+            let name = Self::field_name(button);
+            for (i, bind) in self.binds(button).binds.iter().enumerate() {
+                let bind_path = format!("{}.{}.binds[{}]", path, name, i);
+                bind.actuation
+                    .validate(&format!("{}.actuation", bind_path), errors);
+                if bind.full_press.is_some() {
+                    errors.push(ConfigError::new(
+                        format!("{}.fullPress", bind_path),
+                        "Only stick binds have a full press, button binds already press their button",
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Gives a key a second use when it's tapped rather than held. A press let go of within `windowMs` is a tap, which
+/// presses `button` for `pressMs`. A longer press is a hold and does whatever the key is bound to.
+///
+/// Until the window has passed the key counts as not pressed by its binds, so it doesn't move the stick and loses to
+/// the opposite direction. Once it's a hold it behaves exactly as it would without the tap. A tap never moves the
+/// stick, and the button it presses is held on top of whatever the button's own binds do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TapBind {
+    pub key: KeyCode,
+    pub button: ControllerButton,
+    #[serde(default = "TapBind::default_window_ms", rename = "windowMs")]
+    pub window_ms: u32,
+    #[serde(default = "TapBind::default_press_ms", rename = "pressMs")]
+    pub press_ms: u32,
+}
+
+impl TapBind {
+    pub const MAX_WINDOW_MS: u32 = 2000;
+    pub const MAX_PRESS_MS: u32 = 1000;
+
+    fn default_window_ms() -> u32 {
+        200
+    }
+
+    fn default_press_ms() -> u32 {
+        50
+    }
+
+    fn validate(&self, path: &str, errors: &mut Vec<ConfigError>) {
+        let times = [
+            ("windowMs", self.window_ms, Self::MAX_WINDOW_MS),
+            ("pressMs", self.press_ms, Self::MAX_PRESS_MS),
+        ];
+        for (name, value, max) in times.iter() {
+            if *value == 0 || value > max {
+                errors.push(ConfigError::new(
+                    format!("{}.{}", path, name),
+                    format!("Must be between 1 and {}, got {}", max, value),
+                ));
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct KeyMapping {
     #[serde(rename = "leftJoystick")]
     pub left_joystick: JoystickKeyMapping,
+    /// Moves the right stick as far as the keys are pressed, or sets its turn rate when `rightJoystickTurn` is on
+    #[serde(rename = "rightJoystick")]
+    pub right_joystick: JoystickKeyMapping,
+    pub buttons: ButtonKeyMapping,
+    pub taps: Vec<TapBind>,
+}
+
+impl KeyMapping {
+    /// Every key bound to anything
+    pub fn keys(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.left_joystick
+            .keys()
+            .chain(self.right_joystick.keys())
+            .chain(self.buttons.keys())
+            .chain(self.taps.iter().map(|tap| tap.key))
+    }
+
+    /// Every stick bind that also fully presses a button
+    pub fn full_presses(&self) -> Vec<(&Bind, &FullPress)> {
+        self.left_joystick
+            .directions()
+            .iter()
+            .chain(self.right_joystick.directions().iter())
+            .flat_map(|(_, binds)| binds.binds.iter())
+            .filter_map(|bind| {
+                bind.full_press
+                    .as_ref()
+                    .map(|full_press| (bind, full_press))
+            })
+            .collect()
+    }
+
+    fn validate_taps(&self, path: &str, output: OutputType, errors: &mut Vec<ConfigError>) {
+        for (i, tap) in self.taps.iter().enumerate() {
+            let tap_path = format!("{}[{}]", path, i);
+            tap.validate(&tap_path, errors);
+            if !tap.button.exists_on(output) {
+                errors.push(ConfigError::new(
+                    format!("{}.button", tap_path),
+                    format!("{:?} controllers don't have {:?}", output, tap.button),
+                ));
+            }
+            if let Some(first) = self.taps[..i].iter().position(|t| t.key == tap.key) {
+                errors.push(ConfigError::new(
+                    format!("{}.key", tap_path),
+                    format!("{} already has a tap in taps[{}]", tap.key, first),
+                ));
+            }
+        }
+    }
 }
 
 impl Default for KeyMapping {
     fn default() -> Self {
         KeyMapping {
-            #[cfg(windows)]
             left_joystick: JoystickKeyMapping {
-                up: KeyId::to_u8(&KeyId::W),
-                up_two: None,
-                down: KeyId::to_u8(&KeyId::S),
-                down_two: None,
-                left: KeyId::to_u8(&KeyId::A),
-                left_two: None,
-                right: KeyId::to_u8(&KeyId::D),
-                right_two: None,
-            },
-            #[cfg(not(windows))]
-            left_joystick: JoystickKeyMapping {
-                up: Some(0),
-                up_two: None,
-                down: Some(0),
-                down_two: None,
-                left: Some(0),
-                left_two: None,
-                right: Some(0),
-                right_two: None,
+                up: DirectionBinds::from_keys(&["KeyW"]),
+                down: DirectionBinds::from_keys(&["KeyS"]),
+                left: DirectionBinds::from_keys(&["KeyA"]),
+                right: DirectionBinds::from_keys(&["KeyD"]),
             },
+            right_joystick: JoystickKeyMapping::default(),
+            buttons: ButtonKeyMapping::default(),
+            taps: vec![],
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ServiceConfiguration {
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum OutputType {
+    Xbox360,
+    DualShock4,
+}
+
+impl Default for OutputType {
+    fn default() -> Self {
+        #[cfg(feature = "ds4")]
+        return OutputType::DualShock4;
+        #[cfg(not(feature = "ds4"))]
+        return OutputType::Xbox360;
+    }
+}
+
+/// Drives the right stick from mouse movement, for games that don't support the mouse
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MouseStickConfiguration {
+    pub enabled: bool,
+    /// How far the stick is pushed for each 1000 counts per second the mouse moves
+    pub sensitivity: f32,
+    /// Exponent applied to the stick deflection, above 1 gives finer control over small movements
+    pub curve: f32,
+    /// Where the output starts from once the mouse moves at all, set to the game's deadzone so slow movements
+    /// aren't swallowed by it
+    #[serde(rename = "deadzoneCompensation")]
+    pub deadzone_compensation: f32,
+    /// How long the stick takes to return to the centre from full deflection after the mouse stops
+    #[serde(rename = "decayMs")]
+    pub decay_ms: u32,
+    #[serde(rename = "invertX")]
+    pub invert_x: bool,
+    #[serde(rename = "invertY")]
+    pub invert_y: bool,
+}
+
+impl Default for MouseStickConfiguration {
+    fn default() -> Self {
+        MouseStickConfiguration {
+            enabled: false,
+            sensitivity: 0.5,
+            curve: 1.0,
+            deadzone_compensation: 0.0,
+            decay_ms: 50,
+            invert_x: false,
+            invert_y: false,
+        }
+    }
+}
+
+impl MouseStickConfiguration {
+    pub const MAX_CURVE: f32 = 10.0;
+    pub const MAX_DECAY_MS: u32 = 5000;
+
+    fn validate(&self, path: &str, errors: &mut Vec<ConfigError>) {
+        // The mouse is only read through raw input, so without it the stick would silently never move
+        if self.enabled && !cfg!(feature = "rawinput") {
+            errors.push(ConfigError::new(
+                format!("{}.enabled", path),
+                "The mouse stick isn't available, this build doesn't read raw input",
+            ));
+        }
+        if !self.sensitivity.is_finite() || self.sensitivity <= 0.0 {
+            errors.push(ConfigError::new(
+                format!("{}.sensitivity", path),
+                format!("Must be greater than 0, got {}", self.sensitivity),
+            ));
+        }
+        if !self.curve.is_finite() || self.curve <= 0.0 || self.curve > Self::MAX_CURVE {
+            errors.push(ConfigError::new(
+                format!("{}.curve", path),
+                format!(
+                    "Must be greater than 0 and at most {}, got {}",
+                    Self::MAX_CURVE,
+                    self.curve
+                ),
+            ));
+        }
+        validate_unit_range(
+            errors,
+            &format!("{}.deadzoneCompensation", path),
+            self.deadzone_compensation,
+        );
+        if self.decay_ms > Self::MAX_DECAY_MS {
+            errors.push(ConfigError::new(
+                format!("{}.decayMs", path),
+                format!(
+                    "Must be at most {}, got {}",
+                    Self::MAX_DECAY_MS,
+                    self.decay_ms
+                ),
+            ));
+        }
+    }
+}
+
+/// Makes the right stick keys control how fast the camera turns, for games where mapping key travel straight to
+/// the stick feels sluggish
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TurnRateConfiguration {
+    pub enabled: bool,
+    /// The stick deflection with the keys fully pressed
+    #[serde(rename = "maxRate")]
+    pub max_rate: f32,
+    /// Exponent applied to the key travel, above 1 gives finer control over slow turns
+    pub curve: f32,
+    /// How quickly the turn rate builds up, in full deflections per second. 0 applies it straight away
+    pub acceleration: f32,
+    /// How long the keys have to be held before `boost` applies, no boost if this isn't set
+    #[serde(rename = "boostAfterMs")]
+    pub boost_after_ms: Option<u32>,
+    /// What the turn rate is multiplied by once boosted, the stick still can't go past full deflection
+    pub boost: f32,
+}
+
+impl Default for TurnRateConfiguration {
+    fn default() -> Self {
+        TurnRateConfiguration {
+            enabled: false,
+            max_rate: 1.0,
+            curve: 1.0,
+            acceleration: 4.0,
+            boost_after_ms: None,
+            boost: 1.5,
+        }
+    }
+}
+
+impl TurnRateConfiguration {
+    pub const MAX_CURVE: f32 = 10.0;
+    pub const MAX_BOOST: f32 = 4.0;
+    pub const MAX_BOOST_AFTER_MS: u32 = 10000;
+
+    fn validate(&self, path: &str, errors: &mut Vec<ConfigError>) {
+        validate_unit_range(errors, &format!("{}.maxRate", path), self.max_rate);
+        if !self.curve.is_finite() || self.curve <= 0.0 || self.curve > Self::MAX_CURVE {
+            errors.push(ConfigError::new(
+                format!("{}.curve", path),
+                format!(
+                    "Must be greater than 0 and at most {}, got {}",
+                    Self::MAX_CURVE,
+                    self.curve
+                ),
+            ));
+        }
+        if !self.acceleration.is_finite() || self.acceleration < 0.0 {
+            errors.push(ConfigError::new(
+                format!("{}.acceleration", path),
+                format!("Must be 0 or more, got {}", self.acceleration),
+            ));
+        }
+        if !self.boost.is_finite() || self.boost < 1.0 || self.boost > Self::MAX_BOOST {
+            errors.push(ConfigError::new(
+                format!("{}.boost", path),
+                format!(
+                    "Must be between 1 and {}, got {}",
+                    Self::MAX_BOOST,
+                    self.boost
+                ),
+            ));
+        }
+        if let Some(boost_after_ms) = self.boost_after_ms {
+            if boost_after_ms > Self::MAX_BOOST_AFTER_MS {
+                errors.push(ConfigError::new(
+                    format!("{}.boostAfterMs", path),
+                    format!(
+                        "Must be at most {}, got {}",
+                        Self::MAX_BOOST_AFTER_MS,
+                        boost_after_ms
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+/// Everything needed to drive one virtual controller
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ControllerProfile {
     #[serde(rename = "leftJoystickStrafingAngles")]
     pub left_joystick_strafing_angles: JoystickAngleConfiguration,
     #[serde(rename = "keyMapping")]
     pub key_mapping: KeyMapping,
-    #[serde(rename = "useAnalogInput")]
-    pub use_analog_input: bool,
+    pub output: OutputType,
+    #[serde(rename = "mouseStick")]
+    pub mouse_stick: MouseStickConfiguration,
+    #[serde(rename = "rightJoystickTurn")]
+    pub right_joystick_turn: TurnRateConfiguration,
+}
+
+/// Where the service reads key presses from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum InputPolicy {
+    /// Only read from the Analog SDK, the controller stays neutral while no analog keyboard is available
+    Analog,
+    /// Only read the keyboard's key states, ignoring any analog keyboards
+    Digital,
+    /// Read from the Analog SDK when an analog keyboard is available and fall back to key states when it isn't
+    AnalogWithFallback,
+}
+
+impl Default for InputPolicy {
+    fn default() -> Self {
+        InputPolicy::Digital
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PollMode {
+    /// Sleep until the next poll, accuracy depends on the OS timer resolution
+    Sleep,
+    /// Sleep until just before the next poll and then spin for the rest, trading CPU time for accuracy
+    Precise,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PollConfiguration {
+    /// How many times per second inputs are polled
+    #[serde(rename = "rateHz")]
+    pub rate_hz: u32,
+    pub mode: PollMode,
+}
+
+impl Default for PollConfiguration {
+    fn default() -> Self {
+        PollConfiguration {
+            rate_hz: 1000,
+            mode: PollMode::Sleep,
+        }
+    }
+}
+
+impl PollConfiguration {
+    pub const MIN_RATE_HZ: u32 = 10;
+    pub const MAX_RATE_HZ: u32 = 8000;
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(1) / self.rate_hz.max(1)
+    }
+
+    fn validate(&self, path: &str, errors: &mut Vec<ConfigError>) {
+        if self.rate_hz < Self::MIN_RATE_HZ || self.rate_hz > Self::MAX_RATE_HZ {
+            errors.push(ConfigError::new(
+                format!("{}.rateHz", path),
+                format!(
+                    "Must be between {} and {}, got {}",
+                    Self::MIN_RATE_HZ,
+                    Self::MAX_RATE_HZ,
+                    self.rate_hz
+                ),
+            ));
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ServiceConfiguration {
+    pub version: u32,
+    /// Each of these gets its own virtual controller, in order
+    pub controllers: Vec<ControllerProfile>,
+    #[serde(rename = "inputPolicy")]
+    pub input_policy: InputPolicy,
+    pub poll: PollConfiguration,
+    /// The analog device to read from, all devices are read if this isn't set. Device IDs are 64 bit so they don't
+    /// fit in a JS number and are sent as strings instead
+    #[serde(rename = "analogDeviceId", with = "device_id_string")]
+    pub analog_device_id: Option<u64>,
+    /// Whether to reject configs where a key is bound to opposite directions rather than just warning about it
+    #[serde(rename = "rejectBindingConflicts")]
+    pub reject_binding_conflicts: bool,
 }
 
 impl Default for ServiceConfiguration {
     fn default() -> Self {
         ServiceConfiguration {
-            left_joystick_strafing_angles: JoystickAngleConfiguration {
-                up_diagonal_angle: 0.67,
-                use_left_right_angle: false,
-                left_right_angle: 0.78,
-                analog_range: (0.0, 1.0),
-            },
-            key_mapping: KeyMapping::default(),
-            use_analog_input: false,
+            version: CONFIG_VERSION,
+            controllers: vec![ControllerProfile::default()],
+            input_policy: InputPolicy::default(),
+            poll: PollConfiguration::default(),
+            analog_device_id: None,
+            reject_binding_conflicts: false,
+        }
+    }
+}
+
+mod device_id_string {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(id: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match id {
+            Some(id) => serializer.serialize_str(&id.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(id) => id.parse().map(Some).map_err(D::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A problem with a single field of the configuration. `path` is the JSON path of the field as JS sees it
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub path: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigError {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+fn validate_unit_range(errors: &mut Vec<ConfigError>, path: &str, value: f32) {
+    if !value.is_finite() || value < 0.0 || value > 1.0 {
+        errors.push(ConfigError::new(
+            path,
+            format!("Must be between 0 and 1, got {}", value),
+        ));
+    }
+}
+
+impl JoystickAngleConfiguration {
+    fn validate(&self, path: &str, errors: &mut Vec<ConfigError>) {
+        validate_unit_range(
+            errors,
+            &format!("{}.upDiagonalAngle", path),
+            self.up_diagonal_angle,
+        );
+        validate_unit_range(
+            errors,
+            &format!("{}.leftRightAngle", path),
+            self.left_right_angle,
+        );
+
+        let (range_start, range_end) = self.analog_range;
+        validate_unit_range(errors, &format!("{}.analogRange[0]", path), range_start);
+        validate_unit_range(errors, &format!("{}.analogRange[1]", path), range_end);
+        // Only compare the ends if they're both valid numbers, otherwise the errors above already cover it
+        if range_start.is_finite() && range_end.is_finite() && range_start >= range_end {
+            errors.push(ConfigError::new(
+                format!("{}.analogRange", path),
+                format!(
+                    "The start of the range ({}) must be less than the end ({})",
+                    range_start, range_end
+                ),
+            ));
+        }
+    }
+}
+
+impl ServiceConfiguration {
+    /// The most virtual controllers that can be created, ViGEm starts running out of player slots past this
+    pub const MAX_CONTROLLERS: usize = 4;
+
+    /// Whether the Analog SDK should be loaded for this config
+    pub fn uses_analog(&self) -> bool {
+        self.input_policy != InputPolicy::Digital
+    }
+
+    /// Whether any controller reads from the mouse, either for the mouse stick or a bind to a mouse button
+    pub fn uses_mouse(&self) -> bool {
+        self.controllers.iter().any(|controller| {
+            controller.mouse_stick.enabled
+                || controller.key_mapping.keys().any(KeyCode::is_mouse_button)
+        })
+    }
+
+    /// Parses a configuration sent from JS, upgrading it to `CONFIG_VERSION` first if it was saved by an older version
+    pub fn from_json(json: &str) -> Result<Self, ConfigError> {
+        let value: Value = serde_json::from_str(json)
+            .map_err(|e| ConfigError::new("$", format!("Config is not valid JSON: {}", e)))?;
+        let mut value =
+            migration::migrate(value).map_err(|e| ConfigError::new("$", format!("{:#}", e)))?;
+        upgrade_legacy_joysticks(&mut value)?;
+        serde_path_to_error::deserialize(value)
+            .map_err(|e| ConfigError::new(error_path("$", e.path()), e.into_inner().to_string()))
+    }
+
+    /// Parses and validates a configuration, collecting every problem found rather than stopping at the first one.
+    /// Failures to parse the JSON or migrate it are reported against the root path `$`, and failures to deserialize
+    /// it against the field that's wrong
+    pub fn parse(json: &str) -> Result<Self, Vec<ConfigError>> {
+        let config = Self::from_json(json).map_err(|e| vec![e])?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Finds binding problems in every controller's key mapping, see `bindings::analyze`
+    pub fn analyze_bindings(&self) -> Vec<bindings::BindingIssue> {
+        self.controllers
+            .iter()
+            .enumerate()
+            .flat_map(|(i, controller)| {
+                bindings::analyze(
+                    &controller.key_mapping,
+                    &format!("$.controllers[{}].keyMapping", i),
+                )
+            })
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = vec![];
+        if self.controllers.is_empty() || self.controllers.len() > Self::MAX_CONTROLLERS {
+            errors.push(ConfigError::new(
+                "$.controllers",
+                format!(
+                    "Must have between 1 and {} controllers, got {}",
+                    Self::MAX_CONTROLLERS,
+                    self.controllers.len()
+                ),
+            ));
+        }
+        for (i, controller) in self.controllers.iter().enumerate() {
+            controller.left_joystick_strafing_angles.validate(
+                &format!("$.controllers[{}].leftJoystickStrafingAngles", i),
+                &mut errors,
+            );
+            controller
+                .mouse_stick
+                .validate(&format!("$.controllers[{}].mouseStick", i), &mut errors);
+            controller.right_joystick_turn.validate(
+                &format!("$.controllers[{}].rightJoystickTurn", i),
+                &mut errors,
+            );
+            controller.key_mapping.left_joystick.validate(
+                &format!("$.controllers[{}].keyMapping.leftJoystick", i),
+                controller.output,
+                &mut errors,
+            );
+            controller.key_mapping.right_joystick.validate(
+                &format!("$.controllers[{}].keyMapping.rightJoystick", i),
+                controller.output,
+                &mut errors,
+            );
+            controller.key_mapping.buttons.validate(
+                &format!("$.controllers[{}].keyMapping.buttons", i),
+                &mut errors,
+            );
+            controller.key_mapping.validate_taps(
+                &format!("$.controllers[{}].keyMapping.taps", i),
+                controller.output,
+                &mut errors,
+            );
+            if self.reject_binding_conflicts {
+                errors.extend(bindings::hard_conflicts(
+                    &controller.key_mapping,
+                    &format!("$.controllers[{}].keyMapping", i),
+                ));
+            }
+        }
+        self.poll.validate("$.poll", &mut errors);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_json_error(json: &str) -> ConfigError {
+        ServiceConfiguration::from_json(json).unwrap_err()
+    }
+
+    #[test]
+    fn legacy_joystick_shape_is_upgraded() {
+        let config = ServiceConfiguration::from_json(
+            r#"{"version":4,"controllers":[{"keyMapping":{
+                "leftJoystick":{"up":"KeyW","up_two":"ArrowUp","down":null,"sources":{"up_two":"Digital"}}
+            }}]}"#,
+        )
+        .unwrap();
+
+        let up = &config.controllers[0].key_mapping.left_joystick.up;
+        assert_eq!(
+            up.binds,
+            vec![
+                Bind::new(KeyCode::from_name("KeyW").unwrap()),
+                Bind {
+                    source: BindSource::Digital,
+                    ..Bind::new(KeyCode::from_name("ArrowUp").unwrap())
+                },
+            ]
+        );
+        assert!(config.controllers[0]
+            .key_mapping
+            .left_joystick
+            .down
+            .binds
+            .is_empty());
+    }
+
+    #[test]
+    fn errors_in_the_current_shape_point_at_the_field() {
+        let error = from_json_error(
+            r#"{"version":4,"controllers":[{},{"keyMapping":{
+                "rightJoystick":{"up":{"binds":[{"key":"KeyW"},{"key":"NotAKey"}]}}
+            }}]}"#,
+        );
+        assert_eq!(
+            error.path,
+            "$.controllers[1].keyMapping.rightJoystick.up.binds[1].key"
+        );
+
+        let error = from_json_error(
+            r#"{"version":4,"controllers":[{"keyMapping":{"leftJoystick":{"upp":{}}}}]}"#,
+        );
+        assert_eq!(error.path, "$.controllers[0].keyMapping.leftJoystick.upp");
+        assert!(
+            error.message.contains("unknown field `upp`"),
+            "{}",
+            error.message
+        );
+    }
+
+    #[test]
+    fn errors_in_the_legacy_shape_point_at_the_field() {
+        let error = from_json_error(
+            r#"{"version":4,"controllers":[{"keyMapping":{
+                "leftJoystick":{"up":"KeyW","sources":{"up":"Sideways"}}
+            }}]}"#,
+        );
+        assert_eq!(
+            error.path,
+            "$.controllers[0].keyMapping.leftJoystick.sources.up"
+        );
+    }
+
+    #[test]
+    fn errors_outside_the_config_are_at_the_root() {
+        assert_eq!(from_json_error("{").path, "$");
+        assert_eq!(from_json_error(r#"{"version":99}"#).path, "$");
+        assert_eq!(
+            from_json_error(r#"{"version":4,"inputPolicy":"Sometimes"}"#).path,
+            "$.inputPolicy"
+        );
+    }
+
+    #[test]
+    fn mouse_stick_needs_raw_input() {
+        let mut config = ServiceConfiguration::default();
+        config.controllers[0].mouse_stick.enabled = true;
+
+        let errors = config.validate().err().unwrap_or_default();
+        let paths: Vec<_> = errors.iter().map(|error| error.path.as_str()).collect();
+
+        if cfg!(feature = "rawinput") {
+            assert_eq!(paths, Vec::<&str>::new());
+        } else {
+            assert_eq!(paths, vec!["$.controllers[0].mouseStick.enabled"]);
+        }
+    }
+
+    fn validate_errors(json: &str) -> Vec<ConfigError> {
+        ServiceConfiguration::from_json(json)
+            .unwrap()
+            .validate()
+            .err()
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn full_press_button_must_exist_on_the_output() {
+        let json = |output: &str| {
+            format!(
+                r#"{{"version":4,"controllers":[{{"output":"{}","keyMapping":{{"leftJoystick":{{
+                    "up":{{"binds":[{{"key":"KeyW","fullPress":{{"button":"TouchpadClick"}}}}]}}
+                }}}}}}]}}"#,
+                output
+            )
+        };
+
+        assert_eq!(validate_errors(&json("DualShock4")), vec![]);
+        assert_eq!(
+            validate_errors(&json("Xbox360")),
+            vec![ConfigError::new(
+                "$.controllers[0].keyMapping.leftJoystick.up.binds[0].fullPress.button",
+                "Xbox360 controllers don't have TouchpadClick"
+            )]
+        );
+    }
+
+    #[test]
+    fn binds_only_have_the_settings_for_what_they_are_bound_to() {
+        let errors = validate_errors(
+            r#"{"version":4,"controllers":[{"output":"Xbox360","keyMapping":{
+                "rightJoystick":{"left":{"binds":[
+                    {"key":"KeyJ"},
+                    {"key":"KeyH","actuation":{"mode":"RapidTrigger","press":0.5,"delta":0.1}}
+                ]}},
+                "buttons":{"a":{"binds":[{"key":"Space","fullPress":{"button":"B"}}]}}
+            }}]}"#,
+        );
+
+        assert_eq!(
+            errors,
+            vec![
+                ConfigError::new(
+                    "$.controllers[0].keyMapping.rightJoystick.left.binds[1].actuation",
+                    "Stick binds follow how far the key is pressed, only button binds have an actuation"
+                ),
+                ConfigError::new(
+                    "$.controllers[0].keyMapping.buttons.a.binds[0].fullPress",
+                    "Only stick binds have a full press, button binds already press their button"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn each_validation_rule_reports_its_field() {
+        fn controllers(count: usize) -> Vec<ControllerProfile> {
+            (0..count).map(|_| ControllerProfile::default()).collect()
+        }
+        fn angles(config: &mut ServiceConfiguration) -> &mut JoystickAngleConfiguration {
+            &mut config.controllers[0].left_joystick_strafing_angles
+        }
+        type Change = fn(&mut ServiceConfiguration);
+        let cases: [(&str, Change, &str, &str); 8] = [
+            (
+                "no controllers",
+                |config| config.controllers = controllers(0),
+                "$.controllers",
+                "Must have between 1 and 4 controllers, got 0",
+            ),
+            (
+                "too many controllers",
+                |config| config.controllers = controllers(5),
+                "$.controllers",
+                "Must have between 1 and 4 controllers, got 5",
+            ),
+            (
+                "up diagonal angle above 1",
+                |config| angles(config).up_diagonal_angle = 1.5,
+                "$.controllers[0].leftJoystickStrafingAngles.upDiagonalAngle",
+                "Must be between 0 and 1, got 1.5",
+            ),
+            (
+                "left right angle below 0",
+                |config| angles(config).left_right_angle = -0.1,
+                "$.controllers[0].leftJoystickStrafingAngles.leftRightAngle",
+                "Must be between 0 and 1, got -0.1",
+            ),
+            (
+                "analog range start isn't a number",
+                |config| angles(config).analog_range = (f32::NAN, 1.0),
+                "$.controllers[0].leftJoystickStrafingAngles.analogRange[0]",
+                "Must be between 0 and 1, got NaN",
+            ),
+            (
+                "analog range end above 1",
+                |config| angles(config).analog_range = (0.0, 2.0),
+                "$.controllers[0].leftJoystickStrafingAngles.analogRange[1]",
+                "Must be between 0 and 1, got 2",
+            ),
+            (
+                "analog range backwards",
+                |config| angles(config).analog_range = (0.8, 0.2),
+                "$.controllers[0].leftJoystickStrafingAngles.analogRange",
+                "The start of the range (0.8) must be less than the end (0.2)",
+            ),
+            (
+                "analog range empty",
+                |config| angles(config).analog_range = (0.5, 0.5),
+                "$.controllers[0].leftJoystickStrafingAngles.analogRange",
+                "The start of the range (0.5) must be less than the end (0.5)",
+            ),
+        ];
+
+        assert_eq!(ServiceConfiguration::default().validate(), Ok(()));
+        for &(case, change, path, message) in cases.iter() {
+            let mut config = ServiceConfiguration::default();
+            change(&mut config);
+            assert_eq!(
+                config.validate(),
+                Err(vec![ConfigError::new(path, message)]),
+                "{}",
+                case
+            );
         }
     }
 }
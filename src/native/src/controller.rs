@@ -1,12 +1,20 @@
+use std::collections::{HashMap, HashSet};
+
 #[allow(unused_imports)]
 use log::*;
+use serde::{Deserialize, Serialize};
 #[cfg(windows)]
-use vigem::{DS4Button, DSReport, XUSBReport};
+use vigem::{DS4Button, DS4SpecialButton, DSReport, XButton, XUSBReport};
 #[cfg(windows)]
 use winapi::um::winuser::GetAsyncKeyState;
 
-use crate::config::{JoystickAngleConfiguration, JoystickKeyMapping};
+use crate::config::{
+    Actuation, Bind, DirectionBinds, JoystickAngleConfiguration, JoystickKeyMapping, KeyMapping,
+    OutputType,
+};
+use crate::keycode::KeyCode;
 
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum JoystickDirection {
     Up,
     Down,
@@ -14,6 +22,92 @@ pub enum JoystickDirection {
     Right,
 }
 
+impl JoystickDirection {
+    pub const ALL: [JoystickDirection; 4] = [
+        JoystickDirection::Up,
+        JoystickDirection::Down,
+        JoystickDirection::Left,
+        JoystickDirection::Right,
+    ];
+
+    /// The name of the direction's field, for error paths
+    pub fn name(self) -> &'static str {
+        match self {
+            JoystickDirection::Up => "up",
+            JoystickDirection::Down => "down",
+            JoystickDirection::Left => "left",
+            JoystickDirection::Right => "right",
+        }
+    }
+
+    pub fn opposite(self) -> JoystickDirection {
+        match self {
+            JoystickDirection::Up => JoystickDirection::Down,
+            JoystickDirection::Down => JoystickDirection::Up,
+            JoystickDirection::Left => JoystickDirection::Right,
+            JoystickDirection::Right => JoystickDirection::Left,
+        }
+    }
+}
+
+/// Controller buttons that keys can be bound to. The face buttons are named after the Xbox 360 ones, so on a
+/// DualShock4 A is cross, B is circle, X is square and Y is triangle. PS, Share and Options are guide, back and start
+/// on an Xbox 360 controller, which has no touchpad
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ControllerButton {
+    A,
+    B,
+    X,
+    Y,
+    LeftShoulder,
+    RightShoulder,
+    /// Pulled all the way
+    LeftTrigger,
+    RightTrigger,
+    /// Clicking in the left stick, L3
+    LeftThumb,
+    RightThumb,
+    DpadUp,
+    DpadDown,
+    DpadLeft,
+    DpadRight,
+    TouchpadClick,
+    PS,
+    Share,
+    Options,
+}
+
+impl ControllerButton {
+    pub const ALL: [ControllerButton; 18] = [
+        ControllerButton::A,
+        ControllerButton::B,
+        ControllerButton::X,
+        ControllerButton::Y,
+        ControllerButton::LeftShoulder,
+        ControllerButton::RightShoulder,
+        ControllerButton::LeftTrigger,
+        ControllerButton::RightTrigger,
+        ControllerButton::LeftThumb,
+        ControllerButton::RightThumb,
+        ControllerButton::DpadUp,
+        ControllerButton::DpadDown,
+        ControllerButton::DpadLeft,
+        ControllerButton::DpadRight,
+        ControllerButton::TouchpadClick,
+        ControllerButton::PS,
+        ControllerButton::Share,
+        ControllerButton::Options,
+    ];
+
+    /// Whether the controller has this button
+    pub fn exists_on(self, output: OutputType) -> bool {
+        self != ControllerButton::TouchpadClick || output == OutputType::DualShock4
+    }
+}
+
+/// How far an analog key has to be pressed to press the button it's bound to, unless the bind says otherwise
+pub const ANALOG_BUTTON_THRESHOLD: f32 = 0.5;
+
 #[derive(Debug)]
 struct JoystickDirectionState(f32);
 
@@ -53,12 +147,194 @@ impl JoystickDirectionState {
     }
 }
 
+/// Whether an analog key is holding down a button. Unlike a stick direction this has to remember where the key has
+/// been, as the point it releases at depends on how it got there
+#[derive(Debug, Default)]
+pub struct ButtonActuator {
+    pressed: bool,
+    /// For rapid trigger, the deepest point since the button was pressed or the highest since it was released
+    extreme: f32,
+}
+
+impl ButtonActuator {
+    /// Moves the key to `value` and returns whether the button is now held
+    pub fn update(&mut self, actuation: &Actuation, value: f32) -> bool {
+        match *actuation {
+            Actuation::Threshold { press, release } => {
+                self.pressed = if self.pressed {
+                    value >= release
+                } else {
+                    value >= press
+                };
+            }
+            Actuation::RapidTrigger { press, delta } => {
+                if value < press {
+                    self.pressed = false;
+                    self.extreme = value;
+                } else if self.pressed {
+                    self.extreme = self.extreme.max(value);
+                    if value <= self.extreme - delta {
+                        self.pressed = false;
+                        self.extreme = value;
+                    }
+                } else if self.extreme < press || value >= self.extreme + delta {
+                    // Either the key has just come down past `press`, or it's gone back down after a rapid release
+                    self.pressed = true;
+                    self.extreme = value;
+                } else {
+                    self.extreme = self.extreme.min(value);
+                }
+            }
+        }
+        self.pressed
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct DirectionValues {
+    pub up: f32,
+    pub down: f32,
+    pub left: f32,
+    pub right: f32,
+}
+
+/// Snapshot of every stage a joystick's output goes through, so the UI can show exactly what the game receives
+#[derive(Serialize, Debug, Clone)]
+pub struct JoystickTelemetry {
+    /// The input of each direction as it was read from the keys
+    pub raw: DirectionValues,
+    /// The input of each direction after the analog range has been applied
+    pub ranged: DirectionValues,
+    /// The final stick position, with x & y between -1 -> 1
+    pub output: (f32, f32),
+    /// The axis values as they're written into the report for the active controller type
+    pub report: (i32, i32),
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ControllerTelemetry {
+    pub left_joystick: JoystickTelemetry,
+    pub right_joystick: JoystickTelemetry,
+    /// The buttons currently held down
+    pub buttons: Vec<ControllerButton>,
+}
+
+/// The bind an actuator follows, by where it is in the key mapping
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum ActuatorSource {
+    /// A button bind, by the bind's index in the button's binds
+    Button(ControllerButton, usize),
+    /// The full press of a stick bind, by the bind's index in the direction's binds
+    FullPress {
+        right_joystick: bool,
+        direction: JoystickDirection,
+        index: usize,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct ButtonState {
+    pressed: HashSet<ControllerButton>,
+    /// Pressed by a tap, on top of `pressed`
+    tapped: HashSet<ControllerButton>,
+    /// One for each analog bind that can press a button
+    actuators: HashMap<ActuatorSource, ButtonActuator>,
+}
+
+impl ButtonState {
+    /// Returns whether the button changed
+    pub fn set(&mut self, button: ControllerButton, pressed: bool) -> bool {
+        if pressed {
+            self.pressed.insert(button)
+        } else {
+            self.pressed.remove(&button)
+        }
+    }
+
+    /// Holds each button while any of its binds, or any stick bind that fully presses it, are actuated. `read` gives
+    /// how far each bind's key is pressed. The combine rule isn't used, as each bind has its own actuation. Returns
+    /// whether any button changed
+    pub fn update_analog<F: FnMut(&Bind) -> f32>(
+        &mut self,
+        mapping: &KeyMapping,
+        mut read: F,
+    ) -> bool {
+        // Every bind is updated, even once its button is known to be held, so none of them miss any travel
+        let mut held = HashSet::new();
+        for button in ControllerButton::ALL.iter().copied() {
+            for (index, bind) in mapping.buttons.binds(button).binds.iter().enumerate() {
+                let actuator = self
+                    .actuators
+                    .entry(ActuatorSource::Button(button, index))
+                    .or_default();
+                if actuator.update(&bind.actuation, read(bind)) {
+                    held.insert(button);
+                }
+            }
+        }
+        let joysticks = [
+            (false, &mapping.left_joystick),
+            (true, &mapping.right_joystick),
+        ];
+        for (right_joystick, joystick) in joysticks.iter().copied() {
+            for (direction, binds) in joystick.directions().iter() {
+                for (index, bind) in binds.binds.iter().enumerate() {
+                    let full_press = match &bind.full_press {
+                        Some(full_press) => full_press,
+                        None => continue,
+                    };
+                    let source = ActuatorSource::FullPress {
+                        right_joystick,
+                        direction: *direction,
+                        index,
+                    };
+                    let actuator = self.actuators.entry(source).or_default();
+                    if actuator.update(&full_press.actuation, read(bind)) {
+                        held.insert(full_press.button);
+                    }
+                }
+            }
+        }
+
+        let mut changed = false;
+        for button in ControllerButton::ALL.iter().copied() {
+            changed |= self.set(button, held.contains(&button));
+        }
+        changed
+    }
+
+    /// Sets the buttons being pressed by taps. Returns whether they changed
+    pub fn set_tapped(&mut self, tapped: HashSet<ControllerButton>) -> bool {
+        if tapped != self.tapped {
+            self.tapped = tapped;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_pressed(&self, button: ControllerButton) -> bool {
+        self.pressed.contains(&button) || self.tapped.contains(&button)
+    }
+
+    /// The buttons held down, in the order of `ControllerButton::ALL`
+    pub fn pressed(&self) -> Vec<ControllerButton> {
+        ControllerButton::ALL
+            .iter()
+            .copied()
+            .filter(|button| self.is_pressed(*button))
+            .collect()
+    }
+}
+
 #[derive(Debug)]
 pub struct JoystickState {
     up: JoystickDirectionState,
     down: JoystickDirectionState,
     left: JoystickDirectionState,
     right: JoystickDirectionState,
+    /// Set when the stick is driven by something that gives a position rather than directions, e.g. the mouse
+    position: Option<(f32, f32)>,
 }
 
 trait UpdateValue {
@@ -76,6 +352,21 @@ impl UpdateValue for bool {
     }
 }
 
+/// Whether the key or mouse button is currently held down
+#[allow(unused_variables)]
+pub fn is_key_pressed(key: KeyCode) -> bool {
+    #[cfg(windows)]
+    match key.to_vk() {
+        Some(vk) => {
+            let state = unsafe { GetAsyncKeyState(vk as i32) as u32 };
+            state & 0x8000 != 0
+        }
+        None => false,
+    }
+    #[cfg(not(windows))]
+    false
+}
+
 mod utils {
     /// Accepts x,y as values between -1 -> 1
     pub fn process_circular_direction(
@@ -167,17 +458,33 @@ impl JoystickState {
             down: JoystickDirectionState::new(),
             left: JoystickDirectionState::new(),
             right: JoystickDirectionState::new(),
+            position: None,
         }
     }
 
-    pub fn get_key_state(&mut self, bind: u8) -> bool {
-        #[cfg(windows)]
-        {
-            let state = unsafe { GetAsyncKeyState(bind as i32) as u32 };
-            state & 0x8000 != 0
+    /// Sets the stick straight to a position, with x & y between -1 -> 1. The directions are updated to match so
+    /// telemetry still shows what's going on
+    pub fn set_position(&mut self, x: f32, y: f32) -> bool {
+        let changed = self.position != Some((x, y));
+        self.position = Some((x, y));
+        self.up.update_analog(y.max(0.0));
+        self.down.update_analog((-y).max(0.0));
+        self.left.update_analog((-x).max(0.0));
+        self.right.update_analog(x.max(0.0));
+        changed
+    }
+
+    /// Goes back to being driven by the directions, returns whether the stick was being set by position
+    pub fn clear_position(&mut self) -> bool {
+        if self.position.take().is_some() {
+            self.up.update_analog(0.0);
+            self.down.update_analog(0.0);
+            self.left.update_analog(0.0);
+            self.right.update_analog(0.0);
+            true
+        } else {
+            false
         }
-        #[cfg(not(windows))]
-        false
     }
 
     pub fn set_direction_state_digital(
@@ -205,41 +512,31 @@ impl JoystickState {
     pub fn update_key_state(
         &mut self,
         direction: JoystickDirection,
-        bind_one: Option<&u8>,
-        bind_two: Option<&u8>,
+        binds: &DirectionBinds,
+        is_held: &dyn Fn(KeyCode) -> bool,
     ) -> bool {
-        let mut key_state = false;
-        if let Some(bind_one) = bind_one {
-            key_state |= self.get_key_state(*bind_one);
-        }
-        if let Some(bind_two) = bind_two {
-            key_state |= self.get_key_state(*bind_two);
-        }
+        let key_state = binds.combine(|bind| if is_held(bind.key) { 1.0 } else { 0.0 }) > 0.0;
         self.set_direction_state_digital(direction, key_state)
     }
 
+    /// Sets each direction from whether its keys are held, as told by `is_held`
     #[allow(dead_code)]
-    pub fn update_key_states(&mut self, mappings: &JoystickKeyMapping) -> bool {
-        self.update_key_state(
-            JoystickDirection::Up,
-            mappings.up.as_ref(),
-            mappings.up_two.as_ref(),
-        ) | self.update_key_state(
-            JoystickDirection::Down,
-            mappings.down.as_ref(),
-            mappings.down_two.as_ref(),
-        ) | self.update_key_state(
-            JoystickDirection::Left,
-            mappings.left.as_ref(),
-            mappings.left_two.as_ref(),
-        ) | self.update_key_state(
-            JoystickDirection::Right,
-            mappings.right.as_ref(),
-            mappings.right_two.as_ref(),
-        )
+    pub fn update_key_states(
+        &mut self,
+        mappings: &JoystickKeyMapping,
+        is_held: &dyn Fn(KeyCode) -> bool,
+    ) -> bool {
+        self.update_key_state(JoystickDirection::Up, &mappings.up, is_held)
+            | self.update_key_state(JoystickDirection::Down, &mappings.down, is_held)
+            | self.update_key_state(JoystickDirection::Left, &mappings.left, is_held)
+            | self.update_key_state(JoystickDirection::Right, &mappings.right, is_held)
     }
 
     pub fn get_basic_direction(&self, config: Option<&JoystickAngleConfiguration>) -> (f32, f32) {
+        if let Some(position) = self.position {
+            return position;
+        }
+
         if config.is_none() {
             return (0.0, 0.0);
         }
@@ -280,6 +577,47 @@ impl JoystickState {
         utils::process_circular_direction(x, y, angle, Some(left_right_angle))
     }
 
+    pub fn get_telemetry(
+        &self,
+        config: Option<&JoystickAngleConfiguration>,
+        output: OutputType,
+    ) -> JoystickTelemetry {
+        let raw = DirectionValues {
+            up: self.up.get(),
+            down: self.down.get(),
+            left: self.left.get(),
+            right: self.right.get(),
+        };
+        let ranged = match config {
+            Some(config) => DirectionValues {
+                up: self.up.get_with_range(&config.analog_range),
+                down: self.down.get_with_range(&config.analog_range),
+                left: self.left.get_with_range(&config.analog_range),
+                right: self.right.get_with_range(&config.analog_range),
+            },
+            None => raw,
+        };
+
+        let position = self.get_basic_direction(config);
+        let report = match output {
+            OutputType::DualShock4 => (
+                utils::float_to_ds4_js_axis(position.0) as i32,
+                utils::float_to_ds4_js_axis(-position.1) as i32,
+            ),
+            OutputType::Xbox360 => (
+                utils::float_to_xusb_js_axis(position.0) as i32,
+                utils::float_to_xusb_js_axis(position.1) as i32,
+            ),
+        };
+
+        JoystickTelemetry {
+            raw,
+            ranged,
+            output: position,
+            report,
+        }
+    }
+
     #[allow(dead_code)]
     pub fn get_xusb_direction(&self, config: Option<&JoystickAngleConfiguration>) -> (i16, i16) {
         let (x, y) = self.get_basic_direction(config);
@@ -303,6 +641,7 @@ impl JoystickState {
 pub struct ControllerState {
     pub left_joystick: JoystickState,
     pub right_joystick: JoystickState,
+    pub buttons: ButtonState,
 }
 
 impl ControllerState {
@@ -310,6 +649,45 @@ impl ControllerState {
         Self {
             left_joystick: JoystickState::new(),
             right_joystick: JoystickState::new(),
+            buttons: ButtonState::default(),
+        }
+    }
+
+    /// Holds each button while its binds are, or while any stick bind that fully presses it is, as binds read
+    /// digitally always count as fully pressed. Returns whether any button changed
+    pub fn update_button_key_states(
+        &mut self,
+        mapping: &KeyMapping,
+        is_held: &dyn Fn(KeyCode) -> bool,
+    ) -> bool {
+        let full_pressed: HashSet<ControllerButton> = mapping
+            .full_presses()
+            .into_iter()
+            .filter(|(bind, _)| is_held(bind.key))
+            .map(|(_, full_press)| full_press.button)
+            .collect();
+        let mut changed = false;
+        for button in ControllerButton::ALL.iter().copied() {
+            let pressed = full_pressed.contains(&button)
+                || mapping
+                    .buttons
+                    .binds(button)
+                    .combine(|bind| if is_held(bind.key) { 1.0 } else { 0.0 })
+                    > 0.0;
+            changed |= self.buttons.set(button, pressed);
+        }
+        changed
+    }
+
+    pub fn get_telemetry(
+        &self,
+        left_config: Option<&JoystickAngleConfiguration>,
+        output: OutputType,
+    ) -> ControllerTelemetry {
+        ControllerTelemetry {
+            left_joystick: self.left_joystick.get_telemetry(left_config, output),
+            right_joystick: self.right_joystick.get_telemetry(None, output),
+            buttons: self.buttons.pressed(),
         }
     }
 
@@ -338,13 +716,44 @@ impl ControllerState {
     pub fn get_xusb_report(&self, config: Option<&JoystickAngleConfiguration>) -> XUSBReport {
         let (lx, ly) = self.left_joystick.get_xusb_direction(config);
         let (rx, ry) = self.right_joystick.get_xusb_direction(None);
-        XUSBReport {
+        let mut report = XUSBReport {
             s_thumb_lx: lx,
             s_thumb_ly: ly,
             s_thumb_rx: rx,
             s_thumb_ry: ry,
             ..XUSBReport::default()
+        };
+        for button in self.buttons.pressed() {
+            let flag = match button {
+                ControllerButton::A => XButton::A,
+                ControllerButton::B => XButton::B,
+                ControllerButton::X => XButton::X,
+                ControllerButton::Y => XButton::Y,
+                ControllerButton::LeftShoulder => XButton::LEFT_SHOULDER,
+                ControllerButton::RightShoulder => XButton::RIGHT_SHOULDER,
+                ControllerButton::LeftTrigger => {
+                    report.b_left_trigger = u8::MAX;
+                    continue;
+                }
+                ControllerButton::RightTrigger => {
+                    report.b_right_trigger = u8::MAX;
+                    continue;
+                }
+                ControllerButton::LeftThumb => XButton::LEFT_THUMB,
+                ControllerButton::RightThumb => XButton::RIGHT_THUMB,
+                ControllerButton::DpadUp => XButton::DPAD_UP,
+                ControllerButton::DpadDown => XButton::DPAD_DOWN,
+                ControllerButton::DpadLeft => XButton::DPAD_LEFT,
+                ControllerButton::DpadRight => XButton::DPAD_RIGHT,
+                // Xbox 360 controllers don't have a touchpad
+                ControllerButton::TouchpadClick => continue,
+                ControllerButton::PS => XButton::GUIDE,
+                ControllerButton::Share => XButton::BACK,
+                ControllerButton::Options => XButton::START,
+            };
+            report.w_buttons |= flag.bits();
         }
+        report
     }
 
     #[cfg(windows)]
@@ -372,12 +781,191 @@ impl ControllerState {
     pub fn get_ds4_report(&self, config: Option<&JoystickAngleConfiguration>) -> DSReport {
         let (lx, ly) = self.left_joystick.get_ds4_direction(config);
         let (rx, ry) = self.right_joystick.get_ds4_direction(None);
-        DSReport {
+        let mut report = DSReport {
             b_thumb_lx: lx,
             b_thumb_ly: ly,
             b_thumb_rx: rx,
             b_thumb_ry: ry,
             ..DSReport::default()
+        };
+        for button in self.buttons.pressed() {
+            match button {
+                ControllerButton::A => report.w_buttons |= DS4Button::CROSS.bits(),
+                ControllerButton::B => report.w_buttons |= DS4Button::CIRCLE.bits(),
+                ControllerButton::X => report.w_buttons |= DS4Button::SQUARE.bits(),
+                ControllerButton::Y => report.w_buttons |= DS4Button::TRIANGLE.bits(),
+                ControllerButton::LeftShoulder => {
+                    report.w_buttons |= DS4Button::SHOULDER_LEFT.bits()
+                }
+                ControllerButton::RightShoulder => {
+                    report.w_buttons |= DS4Button::SHOULDER_RIGHT.bits()
+                }
+                ControllerButton::LeftTrigger => {
+                    report.w_buttons |= DS4Button::TRIGGER_LEFT.bits();
+                    report.b_trigger_l = u8::MAX;
+                }
+                ControllerButton::RightTrigger => {
+                    report.w_buttons |= DS4Button::TRIGGER_RIGHT.bits();
+                    report.b_trigger_r = u8::MAX;
+                }
+                ControllerButton::LeftThumb => report.w_buttons |= DS4Button::THUMB_LEFT.bits(),
+                ControllerButton::RightThumb => report.w_buttons |= DS4Button::THUMB_RIGHT.bits(),
+                // The d-pad is a hat switch rather than buttons, it's set below
+                ControllerButton::DpadUp
+                | ControllerButton::DpadDown
+                | ControllerButton::DpadLeft
+                | ControllerButton::DpadRight => {}
+                ControllerButton::TouchpadClick => {
+                    report.b_special |= DS4SpecialButton::SPECIAL_TOUCHPAD.bits()
+                }
+                ControllerButton::PS => report.b_special |= DS4SpecialButton::SPECIAL_PS.bits(),
+                ControllerButton::Share => report.w_buttons |= DS4Button::SHARE.bits(),
+                ControllerButton::Options => report.w_buttons |= DS4Button::OPTIONS.bits(),
+            }
+        }
+        report.w_buttons = (report.w_buttons & !0xf) | self.ds4_dpad();
+        report
+    }
+
+    /// The DualShock4 hat switch value for the d-pad buttons held, which goes clockwise from 0 for up to 7 for up
+    /// left, with 8 for none. Opposite directions cancel out
+    #[cfg(windows)]
+    fn ds4_dpad(&self) -> u16 {
+        let axis = |negative, positive| match (
+            self.buttons.is_pressed(negative),
+            self.buttons.is_pressed(positive),
+        ) {
+            (true, false) => -1,
+            (false, true) => 1,
+            _ => 0,
+        };
+        let x = axis(ControllerButton::DpadLeft, ControllerButton::DpadRight);
+        let y = axis(ControllerButton::DpadDown, ControllerButton::DpadUp);
+        match (x, y) {
+            (0, 1) => 0,
+            (1, 1) => 1,
+            (1, 0) => 2,
+            (1, -1) => 3,
+            (0, -1) => 4,
+            (-1, -1) => 5,
+            (-1, 0) => 6,
+            (-1, 1) => 7,
+            _ => 8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: Actuation = Actuation::Threshold {
+        press: 0.5,
+        release: 0.25,
+    };
+    const RAPID_TRIGGER: Actuation = Actuation::RapidTrigger {
+        press: 0.5,
+        delta: 0.25,
+    };
+
+    /// Feeds each value to a new actuator in turn, checking whether the button is held after each one
+    fn run(name: &str, actuation: &Actuation, steps: &[(f32, bool)]) {
+        let mut actuator = ButtonActuator::default();
+        for (i, &(value, held)) in steps.iter().enumerate() {
+            assert_eq!(
+                actuator.update(actuation, value),
+                held,
+                "{}: step {}, the key at {}",
+                name,
+                i,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn threshold() {
+        let cases: &[(&str, &[(f32, bool)])] = &[
+            ("released", &[(0.0, false), (0.25, false)]),
+            ("just below press", &[(0.49, false)]),
+            ("press", &[(0.49, false), (0.5, true), (1.0, true)]),
+            (
+                "held down to release",
+                &[(0.5, true), (0.3, true), (0.25, true), (0.24, false)],
+            ),
+            (
+                "release needs press again",
+                &[
+                    (1.0, true),
+                    (0.0, false),
+                    (0.3, false),
+                    (0.49, false),
+                    (0.5, true),
+                ],
+            ),
+            (
+                "re-press after a partial release",
+                &[(1.0, true), (0.2, false), (0.4, false), (0.6, true)],
+            ),
+        ];
+        for (name, steps) in cases {
+            run(name, &THRESHOLD, steps);
+        }
+    }
+
+    #[test]
+    fn threshold_without_hysteresis() {
+        let actuation = Actuation::Threshold {
+            press: 0.5,
+            release: 0.5,
+        };
+        run(
+            "no hysteresis",
+            &actuation,
+            &[(0.5, true), (0.49, false), (0.5, true), (0.0, false)],
+        );
+    }
+
+    #[test]
+    fn rapid_trigger() {
+        let cases: &[(&str, &[(f32, bool)])] = &[
+            ("just below press", &[(0.25, false), (0.49, false)]),
+            ("press", &[(0.5, true), (1.0, true)]),
+            (
+                "released by coming up delta from the deepest point",
+                &[(0.5, true), (1.0, true), (0.8125, true), (0.75, false)],
+            ),
+            (
+                "re-press after a partial release",
+                &[
+                    (1.0, true),
+                    (0.75, false),
+                    (0.625, false),
+                    (0.8125, false),
+                    (0.875, true),
+                ],
+            ),
+            (
+                "repeated partial presses",
+                &[
+                    (0.75, true),
+                    (0.5, false),
+                    (0.75, true),
+                    (0.5, false),
+                    (0.75, true),
+                ],
+            ),
+            (
+                "coming up past press always releases",
+                &[(0.625, true), (0.49, false), (0.5, true)],
+            ),
+            (
+                "holding still keeps the button as it is",
+                &[(0.875, true), (0.875, true), (0.625, false), (0.625, false)],
+            ),
+        ];
+        for (name, steps) in cases {
+            run(name, &RAPID_TRIGGER, steps);
         }
     }
 }
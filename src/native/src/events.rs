@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::*;
+use neon::prelude::*;
+use serde::Serialize;
+
+use crate::analog::{AnalogDevice, AnalogSDKState};
+use crate::capture::CapturedBind;
+use crate::feedback::LightbarColor;
+use crate::service::{InputSource, ServiceState, Telemetry};
+
+/// Events pushed from the service to every JS subscriber. They're sent as JSON in the form `{ type, value }`
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", content = "value")]
+pub enum ServiceEvent {
+    ServiceStateChanged(ServiceState),
+    SDKStateChanged(AnalogSDKState),
+    /// The controllers switched between analog and digital input, e.g. falling back when the analog keyboard is
+    /// unplugged
+    InputSourceChanged(InputSource),
+    DeviceConnected(AnalogDevice),
+    DeviceDisconnected(AnalogDevice),
+    PollError(String),
+    /// A game changed the rumble of a controller, `controller` being its index in the config
+    Rumble {
+        controller: usize,
+        large_motor: u8,
+        small_motor: u8,
+    },
+    /// The player LED of an Xbox 360 controller changed
+    LedChanged {
+        controller: usize,
+        led_number: u8,
+    },
+    /// A game changed the lightbar colour of a DualShock4 controller
+    LightbarChanged {
+        controller: usize,
+        color: LightbarColor,
+    },
+    ConfigReloaded,
+    Telemetry(Telemetry),
+    /// The key picked up by a bind capture, which ends the capture
+    BindCaptured(CapturedBind),
+}
+
+struct Subscriber {
+    id: u32,
+    callback: Arc<Root<JsFunction>>,
+    queue: Arc<EventQueue>,
+}
+
+lazy_static! {
+    static ref SUBSCRIBERS: Mutex<Vec<Subscriber>> = Mutex::new(vec![]);
+    static ref NEXT_SUBSCRIBER_ID: AtomicU32 = AtomicU32::new(0);
+}
+
+/// Registers a JS callback to receive every `ServiceEvent`, returning an id that can be given to `unsubscribe`
+pub fn subscribe(callback: Root<JsFunction>, queue: EventQueue) -> u32 {
+    let id = NEXT_SUBSCRIBER_ID.fetch_add(1, Ordering::SeqCst);
+    SUBSCRIBERS.lock().unwrap().push(Subscriber {
+        id,
+        callback: Arc::new(callback),
+        queue: Arc::new(queue),
+    });
+    id
+}
+
+pub fn unsubscribe<'a, C: Context<'a>>(cx: &mut C, id: u32) -> bool {
+    let subscriber = {
+        let mut subscribers = SUBSCRIBERS.lock().unwrap();
+        match subscribers.iter().position(|s| s.id == id) {
+            Some(index) => subscribers.remove(index),
+            None => return false,
+        }
+    };
+
+    // Dropping a root off the JS thread sends it back there to be dropped, so drop it here while we're on it. If an
+    // event is still in flight it holds the other reference and will drop the root once it's done with it
+    if let Ok(callback) = Arc::try_unwrap(subscriber.callback) {
+        callback.drop(cx);
+    }
+    true
+}
+
+/// Queues the event to be delivered to every subscriber. This doesn't block on JS so it's safe to call while polling
+pub fn emit(event: ServiceEvent) {
+    // Serializing telemetry isn't free, so it's done without holding up subscribing and unsubscribing
+    let subscribers: Vec<_> = SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|subscriber| (subscriber.callback.clone(), subscriber.queue.clone()))
+        .collect();
+    if subscribers.is_empty() {
+        return;
+    }
+
+    let json = match serde_json::to_string(&event) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Failed to serialize event {:?}: {}", event, e);
+            return;
+        }
+    };
+
+    for (callback, queue) in subscribers {
+        let json = json.clone();
+        queue.send(move |mut cx| {
+            let result = {
+                let this = cx.undefined();
+                let args = vec![cx.string(json).upcast::<JsValue>()];
+                let callback = callback.to_inner(&mut cx);
+                callback.call(&mut cx, this, args)
+            };
+
+            // If we were unsubscribed while this was in flight then we're the last holder of the root
+            if let Ok(callback) = Arc::try_unwrap(callback) {
+                callback.drop(&mut cx);
+            }
+
+            result?;
+            Ok(())
+        });
+    }
+}
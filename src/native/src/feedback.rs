@@ -0,0 +1,138 @@
+use std::sync::Mutex;
+
+use serde::Serialize;
+use vigem::{
+    notification::*,
+    raw::{DS4_LIGHTBAR_COLOR, LPVOID, PVIGEM_CLIENT, PVIGEM_TARGET, UCHAR},
+};
+
+use crate::events::{self, ServiceEvent};
+
+#[derive(Serialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct LightbarColor {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+/// What the game last asked a controller to do. The LED number only comes from Xbox 360 controllers and the lightbar
+/// only from DualShock4 controllers
+#[derive(Serialize, Clone, Default, PartialEq, Debug)]
+pub struct ControllerFeedback {
+    pub large_motor: u8,
+    pub small_motor: u8,
+    pub led_number: Option<u8>,
+    pub lightbar: Option<LightbarColor>,
+}
+
+lazy_static! {
+    /// Written from ViGEm's notification thread, indexed the same as the config's controllers
+    static ref FEEDBACK: Mutex<Vec<ControllerFeedback>> = Mutex::new(vec![]);
+}
+
+/// The feedback for each controller, in the same order as the config
+pub fn get() -> Vec<ControllerFeedback> {
+    FEEDBACK.lock().unwrap().clone()
+}
+
+/// Clears the feedback for a controller that's just been created, making room for it if needed
+pub fn reset(controller: usize) {
+    let mut feedback = FEEDBACK.lock().unwrap();
+    if feedback.len() <= controller {
+        feedback.resize(controller + 1, ControllerFeedback::default());
+    } else {
+        feedback[controller] = ControllerFeedback::default();
+    }
+}
+
+/// Drops the feedback of controllers past `count`, for when controllers are removed
+pub fn truncate(count: usize) {
+    FEEDBACK.lock().unwrap().truncate(count);
+}
+
+/// Stores the new feedback and lets JS know about whatever changed
+fn update(controller: usize, update: impl FnOnce(&mut ControllerFeedback)) {
+    let (old, new) = {
+        let mut feedback = FEEDBACK.lock().unwrap();
+        // The controller has been removed since the notification was sent
+        let current = match feedback.get_mut(controller) {
+            Some(current) => current,
+            None => return,
+        };
+        let old = current.clone();
+        update(current);
+        (old, current.clone())
+    };
+
+    if (old.large_motor, old.small_motor) != (new.large_motor, new.small_motor) {
+        events::emit(ServiceEvent::Rumble {
+            controller,
+            large_motor: new.large_motor,
+            small_motor: new.small_motor,
+        });
+    }
+    if let Some(led_number) = new.led_number.filter(|_| old.led_number != new.led_number) {
+        events::emit(ServiceEvent::LedChanged {
+            controller,
+            led_number,
+        });
+    }
+    if let Some(color) = new.lightbar.filter(|_| old.lightbar != new.lightbar) {
+        events::emit(ServiceEvent::LightbarChanged { controller, color });
+    }
+}
+
+/// Registered for Xbox 360 targets with the controller's index as the user data
+pub unsafe extern "C" fn handle_x360_notification(
+    client: PVIGEM_CLIENT,
+    target: PVIGEM_TARGET,
+    large_motor: UCHAR,
+    small_motor: UCHAR,
+    led_number: UCHAR,
+    user_data: LPVOID,
+) {
+    // make a safe absraction over all arguments
+    let notification: X360Notification<usize> = X360Notification::new(
+        client,
+        target,
+        large_motor,
+        small_motor,
+        led_number,
+        user_data,
+    );
+
+    update(*notification.get_user_data(), |feedback| {
+        feedback.large_motor = notification.large_motor;
+        feedback.small_motor = notification.small_motor;
+        feedback.led_number = Some(notification.led_number);
+    });
+}
+
+/// Registered for DualShock4 targets with the controller's index as the user data
+pub unsafe extern "C" fn handle_ds4_notification(
+    client: PVIGEM_CLIENT,
+    target: PVIGEM_TARGET,
+    large_motor: UCHAR,
+    small_motor: UCHAR,
+    lightbar_color: DS4_LIGHTBAR_COLOR,
+    user_data: LPVOID,
+) {
+    let notification: DS4Notification<usize> = DS4Notification::new(
+        client,
+        target,
+        large_motor,
+        small_motor,
+        lightbar_color,
+        user_data,
+    );
+
+    update(*notification.get_user_data(), |feedback| {
+        feedback.large_motor = notification.large_motor;
+        feedback.small_motor = notification.small_motor;
+        feedback.lightbar = Some(LightbarColor {
+            red: notification.lightbar_color.Red,
+            green: notification.lightbar_color.Green,
+            blue: notification.lightbar_color.Blue,
+        });
+    });
+}
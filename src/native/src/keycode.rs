@@ -0,0 +1,342 @@
+use std::fmt;
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A key identified by its HID usage code from the keyboard/keypad page, which doesn't depend on the platform or the
+/// keyboard layout. Mouse buttons don't have a keyboard usage, so they use codes from the reserved range after it
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct KeyCode(pub u16);
+
+struct KeyInfo {
+    hid: u16,
+    /// Matches `KeyboardEvent.code` where there's an equivalent
+    name: &'static str,
+    /// Windows virtual-key code
+    vk: Option<u8>,
+}
+
+const fn key(hid: u16, name: &'static str, vk: Option<u8>) -> KeyInfo {
+    KeyInfo { hid, name, vk }
+}
+
+#[rustfmt::skip]
+const KEYS: &[KeyInfo] = &[
+    key(0x04, "KeyA", Some(0x41)),
+    key(0x05, "KeyB", Some(0x42)),
+    key(0x06, "KeyC", Some(0x43)),
+    key(0x07, "KeyD", Some(0x44)),
+    key(0x08, "KeyE", Some(0x45)),
+    key(0x09, "KeyF", Some(0x46)),
+    key(0x0A, "KeyG", Some(0x47)),
+    key(0x0B, "KeyH", Some(0x48)),
+    key(0x0C, "KeyI", Some(0x49)),
+    key(0x0D, "KeyJ", Some(0x4A)),
+    key(0x0E, "KeyK", Some(0x4B)),
+    key(0x0F, "KeyL", Some(0x4C)),
+    key(0x10, "KeyM", Some(0x4D)),
+    key(0x11, "KeyN", Some(0x4E)),
+    key(0x12, "KeyO", Some(0x4F)),
+    key(0x13, "KeyP", Some(0x50)),
+    key(0x14, "KeyQ", Some(0x51)),
+    key(0x15, "KeyR", Some(0x52)),
+    key(0x16, "KeyS", Some(0x53)),
+    key(0x17, "KeyT", Some(0x54)),
+    key(0x18, "KeyU", Some(0x55)),
+    key(0x19, "KeyV", Some(0x56)),
+    key(0x1A, "KeyW", Some(0x57)),
+    key(0x1B, "KeyX", Some(0x58)),
+    key(0x1C, "KeyY", Some(0x59)),
+    key(0x1D, "KeyZ", Some(0x5A)),
+    key(0x1E, "Digit1", Some(0x31)),
+    key(0x1F, "Digit2", Some(0x32)),
+    key(0x20, "Digit3", Some(0x33)),
+    key(0x21, "Digit4", Some(0x34)),
+    key(0x22, "Digit5", Some(0x35)),
+    key(0x23, "Digit6", Some(0x36)),
+    key(0x24, "Digit7", Some(0x37)),
+    key(0x25, "Digit8", Some(0x38)),
+    key(0x26, "Digit9", Some(0x39)),
+    key(0x27, "Digit0", Some(0x30)),
+    key(0x28, "Enter", Some(0x0D)),
+    key(0x29, "Escape", Some(0x1B)),
+    key(0x2A, "Backspace", Some(0x08)),
+    key(0x2B, "Tab", Some(0x09)),
+    key(0x2C, "Space", Some(0x20)),
+    key(0x2D, "Minus", Some(0xBD)),
+    key(0x2E, "Equal", Some(0xBB)),
+    key(0x2F, "BracketLeft", Some(0xDB)),
+    key(0x30, "BracketRight", Some(0xDD)),
+    key(0x31, "Backslash", Some(0xDC)),
+    key(0x33, "Semicolon", Some(0xBA)),
+    key(0x34, "Quote", Some(0xDE)),
+    key(0x35, "Backquote", Some(0xC0)),
+    key(0x36, "Comma", Some(0xBC)),
+    key(0x37, "Period", Some(0xBE)),
+    key(0x38, "Slash", Some(0xBF)),
+    key(0x39, "CapsLock", Some(0x14)),
+    key(0x3A, "F1", Some(0x70)),
+    key(0x3B, "F2", Some(0x71)),
+    key(0x3C, "F3", Some(0x72)),
+    key(0x3D, "F4", Some(0x73)),
+    key(0x3E, "F5", Some(0x74)),
+    key(0x3F, "F6", Some(0x75)),
+    key(0x40, "F7", Some(0x76)),
+    key(0x41, "F8", Some(0x77)),
+    key(0x42, "F9", Some(0x78)),
+    key(0x43, "F10", Some(0x79)),
+    key(0x44, "F11", Some(0x7A)),
+    key(0x45, "F12", Some(0x7B)),
+    key(0x46, "PrintScreen", Some(0x2C)),
+    key(0x47, "ScrollLock", Some(0x91)),
+    key(0x48, "Pause", Some(0x13)),
+    key(0x49, "Insert", Some(0x2D)),
+    key(0x4A, "Home", Some(0x24)),
+    key(0x4B, "PageUp", Some(0x21)),
+    key(0x4C, "Delete", Some(0x2E)),
+    key(0x4D, "End", Some(0x23)),
+    key(0x4E, "PageDown", Some(0x22)),
+    key(0x4F, "ArrowRight", Some(0x27)),
+    key(0x50, "ArrowLeft", Some(0x25)),
+    key(0x51, "ArrowDown", Some(0x28)),
+    key(0x52, "ArrowUp", Some(0x26)),
+    key(0x53, "NumLock", Some(0x90)),
+    key(0x54, "NumpadDivide", Some(0x6F)),
+    key(0x55, "NumpadMultiply", Some(0x6A)),
+    key(0x56, "NumpadSubtract", Some(0x6D)),
+    key(0x57, "NumpadAdd", Some(0x6B)),
+    // Shares its virtual-key code with Enter
+    key(0x58, "NumpadEnter", None),
+    key(0x59, "Numpad1", Some(0x61)),
+    key(0x5A, "Numpad2", Some(0x62)),
+    key(0x5B, "Numpad3", Some(0x63)),
+    key(0x5C, "Numpad4", Some(0x64)),
+    key(0x5D, "Numpad5", Some(0x65)),
+    key(0x5E, "Numpad6", Some(0x66)),
+    key(0x5F, "Numpad7", Some(0x67)),
+    key(0x60, "Numpad8", Some(0x68)),
+    key(0x61, "Numpad9", Some(0x69)),
+    key(0x62, "Numpad0", Some(0x60)),
+    key(0x63, "NumpadDecimal", Some(0x6E)),
+    key(0x64, "IntlBackslash", Some(0xE2)),
+    key(0x65, "ContextMenu", Some(0x5D)),
+    key(0x68, "F13", Some(0x7C)),
+    key(0x69, "F14", Some(0x7D)),
+    key(0x6A, "F15", Some(0x7E)),
+    key(0x6B, "F16", Some(0x7F)),
+    key(0x6C, "F17", Some(0x80)),
+    key(0x6D, "F18", Some(0x81)),
+    key(0x6E, "F19", Some(0x82)),
+    key(0x6F, "F20", Some(0x83)),
+    key(0x70, "F21", Some(0x84)),
+    key(0x71, "F22", Some(0x85)),
+    key(0x72, "F23", Some(0x86)),
+    key(0x73, "F24", Some(0x87)),
+    key(0xE0, "ControlLeft", Some(0xA2)),
+    key(0xE1, "ShiftLeft", Some(0xA0)),
+    key(0xE2, "AltLeft", Some(0xA4)),
+    key(0xE3, "MetaLeft", Some(0x5B)),
+    key(0xE4, "ControlRight", Some(0xA3)),
+    key(0xE5, "ShiftRight", Some(0xA1)),
+    key(0xE6, "AltRight", Some(0xA5)),
+    key(0xE7, "MetaRight", Some(0x5C)),
+    key(0xF0, "MouseLeft", Some(0x01)),
+    key(0xF1, "MouseRight", Some(0x02)),
+    key(0xF2, "MouseMiddle", Some(0x04)),
+    key(0xF3, "MouseBack", Some(0x05)),
+    key(0xF4, "MouseForward", Some(0x06)),
+];
+
+/// The generic modifier virtual-key codes don't say which side was pressed, so they're treated as the left one
+const VK_ALIASES: &[(u8, u16)] = &[(0x10, 0xE1), (0x11, 0xE0), (0x12, 0xE2)];
+
+impl KeyCode {
+    fn info(self) -> Option<&'static KeyInfo> {
+        KEYS.iter().find(|key| key.hid == self.0)
+    }
+
+    /// Every key in the translation table
+    #[cfg_attr(feature = "rawinput", allow(dead_code))]
+    pub fn all() -> impl Iterator<Item = KeyCode> {
+        KEYS.iter().map(|key| KeyCode(key.hid))
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        KEYS.iter()
+            .find(|key| key.name == name)
+            .map(|key| KeyCode(key.hid))
+    }
+
+    pub fn name(self) -> Option<&'static str> {
+        self.info().map(|key| key.name)
+    }
+
+    pub fn from_vk(vk: u8) -> Option<Self> {
+        KEYS.iter()
+            .find(|key| key.vk == Some(vk))
+            .map(|key| key.hid)
+            .or_else(|| {
+                VK_ALIASES
+                    .iter()
+                    .find(|(alias, _)| *alias == vk)
+                    .map(|(_, hid)| *hid)
+            })
+            .map(KeyCode)
+    }
+
+    pub fn to_vk(self) -> Option<u8> {
+        self.info().and_then(|key| key.vk)
+    }
+
+    /// Whether this is one of the mouse buttons at the end of the table rather than a key
+    pub fn is_mouse_button(self) -> bool {
+        matches!(self.name(), Some(name) if name.starts_with("Mouse"))
+    }
+}
+
+impl fmt::Display for KeyCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "{:#04x}", self.0),
+        }
+    }
+}
+
+impl Serialize for KeyCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.0)
+    }
+}
+
+/// Accepts either the HID usage code or a name like `"KeyW"`
+impl<'de> Deserialize<'de> for KeyCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeyCodeVisitor;
+
+        impl<'de> Visitor<'de> for KeyCodeVisitor {
+            type Value = KeyCode;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a HID usage code or key name")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<KeyCode, E> {
+                if value > u16::MAX as u64 {
+                    return Err(E::invalid_value(de::Unexpected::Unsigned(value), &self));
+                }
+                Ok(KeyCode(value as u16))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<KeyCode, E> {
+                KeyCode::from_name(value)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+            }
+        }
+
+        deserializer.deserialize_any(KeyCodeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn no_key_is_in_the_table_twice() {
+        let mut hids = HashSet::new();
+        let mut names = HashSet::new();
+        let mut vks = HashSet::new();
+        for key in KEYS {
+            assert!(hids.insert(key.hid), "HID {:#04x} is repeated", key.hid);
+            assert!(names.insert(key.name), "{} is repeated", key.name);
+            if let Some(vk) = key.vk {
+                assert!(vks.insert(vk), "VK {:#04x} is repeated", vk);
+            }
+        }
+        for (vk, hid) in VK_ALIASES {
+            assert!(!vks.contains(vk), "alias VK {:#04x} is in the table", vk);
+            assert!(hids.contains(hid), "alias HID {:#04x} isn't a key", hid);
+        }
+    }
+
+    #[test]
+    fn vk_round_trips_through_hid() {
+        for key in KEYS {
+            let code = KeyCode(key.hid);
+            match key.vk {
+                Some(vk) => {
+                    assert_eq!(KeyCode::from_vk(vk), Some(code), "{}", key.name);
+                    assert_eq!(code.to_vk(), Some(vk), "{}", key.name);
+                }
+                None => assert_eq!(code.to_vk(), None, "{}", key.name),
+            }
+        }
+    }
+
+    #[test]
+    fn names_round_trip_through_hid() {
+        for key in KEYS {
+            let code = KeyCode::from_name(key.name);
+            assert_eq!(code, Some(KeyCode(key.hid)), "{}", key.name);
+            assert_eq!(code.unwrap().name(), Some(key.name));
+            assert_eq!(code.unwrap().to_string(), key.name);
+        }
+    }
+
+    #[test]
+    fn generic_modifiers_are_the_left_one() {
+        let cases = [
+            (0x10, "ShiftLeft", 0xA0),
+            (0x11, "ControlLeft", 0xA2),
+            (0x12, "AltLeft", 0xA4),
+        ];
+        for &(vk, name, side_vk) in cases.iter() {
+            let code = KeyCode::from_vk(vk).unwrap();
+            assert_eq!(code.name(), Some(name), "VK {:#04x}", vk);
+            // Going back gives the code for the side rather than the generic one
+            assert_eq!(code.to_vk(), Some(side_vk), "{}", name);
+        }
+    }
+
+    #[test]
+    fn mouse_buttons() {
+        let cases = [
+            ("MouseLeft", true),
+            ("MouseForward", true),
+            ("KeyM", false),
+            ("NumpadEnter", false),
+        ];
+        for &(name, expected) in cases.iter() {
+            let code = KeyCode::from_name(name).unwrap();
+            assert_eq!(code.is_mouse_button(), expected, "{}", name);
+        }
+        assert!(!KeyCode(0x99).is_mouse_button());
+    }
+
+    #[test]
+    fn unknown_keys() {
+        assert_eq!(KeyCode::from_vk(0xFF), None);
+        assert_eq!(KeyCode::from_name("keyw"), None);
+        assert_eq!(KeyCode(0x99).name(), None);
+        assert_eq!(KeyCode(0x99).to_vk(), None);
+        assert_eq!(KeyCode(0x99).to_string(), "0x99");
+    }
+
+    #[test]
+    fn deserializes_from_a_name_or_hid() {
+        let cases = [
+            ("\"KeyW\"", Some(KeyCode(0x1A))),
+            ("26", Some(KeyCode(0x1A))),
+            ("153", Some(KeyCode(0x99))),
+            ("\"keyw\"", None),
+            ("65536", None),
+        ];
+        for &(json, expected) in cases.iter() {
+            let code: Option<KeyCode> = serde_json::from_str(json).ok();
+            assert_eq!(code, expected, "{}", json);
+        }
+    }
+}
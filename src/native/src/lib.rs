@@ -7,28 +7,53 @@ use log::*;
 use neon::prelude::*;
 use simplelog::*;
 use std::fs::{create_dir_all, OpenOptions};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::Mutex;
 use std::time::Duration;
 
+// Modules gated on `any(windows, test)` are only used by the Windows service but don't need anything Windows
+// specific, so they're built for tests everywhere
+#[cfg(any(windows, test))]
+mod analog;
+#[cfg(any(feature = "rawinput", test))]
+mod bind_table;
+mod bindings;
+#[cfg(any(windows, test))]
+mod capture;
 mod config;
 mod controller;
 #[cfg(windows)]
+mod events;
+#[cfg(windows)]
+mod feedback;
+mod keycode;
+mod migration;
+#[cfg(any(windows, test))]
+mod mouse;
+#[cfg(windows)]
+mod runner;
+#[cfg(windows)]
 mod service;
+#[cfg(any(windows, test))]
+mod stats;
+#[cfg(any(windows, test))]
+mod tap;
+#[cfg(any(windows, test))]
+mod turn;
+#[cfg(any(windows, test))]
+mod watchdog;
 
-use config::ServiceConfiguration;
 #[cfg(windows)]
-use service::Service;
-
-lazy_static! {
-    static ref MSG_THREAD_RUNNING: AtomicBool = AtomicBool::new(false);
-    static ref MESSAGE_LOOP: Mutex<Option<thread::JoinHandle<()>>> = Mutex::new(None);
-}
+use analog::{AnalogSDKInfo, AnalogSDKState};
+use config::{ConfigError, ServiceConfiguration};
+#[cfg(windows)]
+use runner::ServiceRunner;
+#[cfg(windows)]
+use service::{InputSource, ServiceState};
 
+// Only ever touched from the JS thread, the service itself lives on the runner's thread
 #[cfg(windows)]
 lazy_static! {
-    static ref SERVICE: Arc<Mutex<Service>> = Arc::new(Mutex::new(Service::new()));
+    static ref SERVICE: Mutex<Option<ServiceRunner>> = Mutex::new(None);
 }
 
 #[neon::main]
@@ -76,81 +101,133 @@ fn main(mut cx: ModuleContext) -> NeonResult<()> {
     cx.export_function("start_service", start_service)?;
     cx.export_function("stop_service", stop_service)?;
     cx.export_function("get_xinput_slot", get_xinput_slot)?;
+    cx.export_function("get_xinput_slots", get_xinput_slots)?;
     cx.export_function("get_sdk_state", get_sdk_state)?;
+    cx.export_function("get_sdk_info", get_sdk_info)?;
+    cx.export_function("get_input_source", get_input_source)?;
+    cx.export_function("get_service_state", get_service_state)?;
     cx.export_function("set_config", set_config)?;
+    cx.export_function("analyze_bindings", analyze_bindings)?;
     cx.export_function("start_gamepad_detection", start_gamepad_detection)?;
     cx.export_function("end_gamepad_detection", end_gamepad_detection)?;
+    cx.export_function("start_bind_capture", start_bind_capture)?;
+    cx.export_function("end_bind_capture", end_bind_capture)?;
+    cx.export_function("get_telemetry", get_telemetry)?;
+    cx.export_function("get_controller_feedback", get_controller_feedback)?;
+    cx.export_function("set_telemetry_interval", set_telemetry_interval)?;
+    cx.export_function("get_poll_stats", get_poll_stats)?;
+    cx.export_function("reset_poll_stats", reset_poll_stats)?;
+    cx.export_function("subscribe", subscribe)?;
+    cx.export_function("unsubscribe", unsubscribe)?;
 
     Ok(())
 }
 
-fn start_service(mut cx: FunctionContext) -> JsResult<JsBoolean> {
-    if MESSAGE_LOOP.lock().unwrap().is_some() {
-        // This means the service has already been started
-        warn!("start_service was called when the service was already running, ignoring...");
-        return Ok(cx.boolean(true));
+/// Throws a JS `Error` named `ConfigValidationError` with an `errors` property holding the `{ path, message }` of each
+/// problem, so the UI can point at the offending fields
+fn throw_config_errors<'a, T>(
+    cx: &mut FunctionContext<'a>,
+    errors: &[ConfigError],
+) -> NeonResult<T> {
+    warn!("Rejected invalid config {:?}", errors);
+
+    let js_errors = JsArray::new(cx, errors.len() as u32);
+    for (i, config_error) in errors.iter().enumerate() {
+        let obj = cx.empty_object();
+        let path = cx.string(&config_error.path);
+        obj.set(cx, "path", path)?;
+        let message = cx.string(&config_error.message);
+        obj.set(cx, "message", message)?;
+        js_errors.set(cx, i as u32, obj)?;
     }
 
-    MSG_THREAD_RUNNING.store(true, Ordering::SeqCst);
+    let summary = errors
+        .iter()
+        .map(|e| format!("{}: {}", e.path, e.message))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let err = cx.error(format!("Invalid config: {}", summary))?;
+    let name = cx.string("ConfigValidationError");
+    err.set(cx, "name", name)?;
+    err.set(cx, "errors", js_errors)?;
+    cx.throw(err)
+}
+
+fn start_service(mut cx: FunctionContext) -> JsResult<JsBoolean> {
     let config_arg = cx.argument::<JsString>(0)?.value(&mut cx);
     info!("Received config {}", config_arg);
-    let config: ServiceConfiguration = serde_json::from_str(&config_arg[..]).unwrap();
+    let config = match ServiceConfiguration::parse(&config_arg) {
+        Ok(config) => config,
+        Err(errors) => return throw_config_errors(&mut cx, &errors),
+    };
 
-    // We can unwrap this because panics get given up to javascript as regular errors
     #[cfg(windows)]
-    match std::panic::catch_unwind(|| SERVICE.lock().unwrap().init(config)) {
-        Ok(res) => res.unwrap(),
-        Err(e) => {
-            error!("The service init panicked {:#?}", e);
-            return Ok(cx.boolean(false));
+    {
+        let mut service = SERVICE.lock().unwrap();
+        if service.is_some() {
+            // This means the service has already been started
+            warn!("start_service was called when the service was already running, ignoring...");
+            return Ok(cx.boolean(true));
         }
-    }
-
-    // let callback = cx.argument::<JsFunction>(0)?.root(&mut cx);
-    // let queue = cx.queue();
-    info!("Starting service");
-    #[cfg(windows)]
-    MESSAGE_LOOP.lock().unwrap().replace(thread::spawn(move || {
-        while MSG_THREAD_RUNNING.load(Ordering::SeqCst) {
-            if let Err(e) = SERVICE.lock().unwrap().poll() {
-                error!("Error occurred during polling {:#?}", e);
 
-                // queue.send(move |mut cx| {
-                //     let callback = callback.into_inner(&mut cx);
-                //     let this = cx.undefined();
-                //     let args = vec![cx.error(format!("{:#?}", e)).unwrap().upcast::<JsValue>()];
-                //     callback.call(&mut cx, this, args)?;
-                //     Ok(())
-                // });
+        info!("Starting service");
+        match ServiceRunner::start(config) {
+            Ok(runner) => {
+                service.replace(runner);
+            }
+            Err(e) => {
+                error!("The service failed to start {:#}", e);
+                return cx.throw_error(format!("{:#}", e));
             }
-            thread::sleep(Duration::from_millis(1));
         }
-    }));
+    }
+
+    #[cfg(not(windows))]
+    let _ = config;
 
     return Ok(cx.boolean(true));
 }
 
 fn stop_service(mut cx: FunctionContext) -> JsResult<JsNull> {
-    MSG_THREAD_RUNNING.store(false, Ordering::SeqCst);
     info!("Stopping service");
     #[cfg(windows)]
-    {
-        if let Some(thread) = MESSAGE_LOOP.lock().unwrap().take() {
-            thread.join().expect("Thread failed to join");
-        }
+    if let Some(runner) = SERVICE.lock().unwrap().take() {
+        runner.stop();
     }
-    #[cfg(windows)]
-    SERVICE.lock().unwrap().stop();
     return Ok(cx.null());
 }
 
+/// Returns the xinput slot of the first controller
 fn get_xinput_slot(mut cx: FunctionContext) -> JsResult<JsValue> {
     #[cfg(windows)]
-    let slot = SERVICE.lock().unwrap().get_xinput_slot();
+    if let Some(runner) = SERVICE.lock().unwrap().as_ref() {
+        match runner.get_xinput_slots() {
+            Ok(slots) => {
+                if let Some(Some(slot)) = slots.first() {
+                    return Ok(cx.number(*slot).upcast());
+                }
+            }
+            Err(e) => error!("Failed to get xinput slot {:#}", e),
+        }
+    }
+
+    return Ok(cx.null().upcast());
+}
 
+/// Returns the xinput slot of every controller as a JSON array, with null for controllers that don't have one
+fn get_xinput_slots(mut cx: FunctionContext) -> JsResult<JsValue> {
     #[cfg(windows)]
-    if let Some(slot) = slot {
-        return Ok(cx.number(slot).upcast());
+    if let Some(runner) = SERVICE.lock().unwrap().as_ref() {
+        match runner.get_xinput_slots() {
+            Ok(slots) => {
+                return Ok(cx
+                    .string(
+                        serde_json::to_string(&slots).expect("Failed to serialize xinput slots"),
+                    )
+                    .upcast())
+            }
+            Err(e) => error!("Failed to get xinput slots {:#}", e),
+        }
     }
 
     return Ok(cx.null().upcast());
@@ -159,7 +236,10 @@ fn get_xinput_slot(mut cx: FunctionContext) -> JsResult<JsValue> {
 fn get_sdk_state(mut cx: FunctionContext) -> JsResult<JsValue> {
     #[cfg(windows)]
     {
-        let state = SERVICE.lock().unwrap().get_sdk_state();
+        let state = match SERVICE.lock().unwrap().as_ref() {
+            Some(runner) => runner.snapshot().sdk_state.clone(),
+            None => AnalogSDKState::Uninitialized,
+        };
 
         return Ok(cx
             .string(serde_json::to_string(&state).expect("Failed to serialize sdk state"))
@@ -170,23 +250,235 @@ fn get_sdk_state(mut cx: FunctionContext) -> JsResult<JsValue> {
     return Ok(cx.null().upcast());
 }
 
+fn get_sdk_info(mut cx: FunctionContext) -> JsResult<JsValue> {
+    #[cfg(windows)]
+    {
+        let info = match SERVICE.lock().unwrap().as_ref() {
+            Some(runner) => runner.snapshot().sdk_info.clone(),
+            None => AnalogSDKInfo::default(),
+        };
+
+        return Ok(cx
+            .string(serde_json::to_string(&info).expect("Failed to serialize sdk info"))
+            .upcast());
+    }
+
+    #[cfg(not(windows))]
+    return Ok(cx.null().upcast());
+}
+
+fn get_input_source(mut cx: FunctionContext) -> JsResult<JsValue> {
+    #[cfg(windows)]
+    {
+        let source = match SERVICE.lock().unwrap().as_ref() {
+            Some(runner) => runner.snapshot().input_source,
+            None => InputSource::Digital,
+        };
+
+        return Ok(cx
+            .string(serde_json::to_string(&source).expect("Failed to serialize input source"))
+            .upcast());
+    }
+
+    #[cfg(not(windows))]
+    return Ok(cx.null().upcast());
+}
+
+fn get_service_state(mut cx: FunctionContext) -> JsResult<JsValue> {
+    #[cfg(windows)]
+    {
+        let state = match SERVICE.lock().unwrap().as_ref() {
+            Some(runner) => runner.snapshot().state.clone(),
+            None => ServiceState::Stopped,
+        };
+
+        return Ok(cx
+            .string(serde_json::to_string(&state).expect("Failed to serialize service state"))
+            .upcast());
+    }
+
+    #[cfg(not(windows))]
+    return Ok(cx.null().upcast());
+}
+
+fn get_telemetry(mut cx: FunctionContext) -> JsResult<JsValue> {
+    #[cfg(windows)]
+    if let Some(runner) = SERVICE.lock().unwrap().as_ref() {
+        if let Some(telemetry) = runner.snapshot().telemetry.as_ref() {
+            return Ok(cx
+                .string(serde_json::to_string(telemetry).expect("Failed to serialize telemetry"))
+                .upcast());
+        }
+    }
+
+    return Ok(cx.null().upcast());
+}
+
+/// Returns the rumble, LED and lightbar state of each controller as a JSON array, in the same order as the config
+fn get_controller_feedback(mut cx: FunctionContext) -> JsResult<JsValue> {
+    #[cfg(windows)]
+    {
+        let feedback = feedback::get();
+        return Ok(cx
+            .string(serde_json::to_string(&feedback).expect("Failed to serialize feedback"))
+            .upcast());
+    }
+
+    #[cfg(not(windows))]
+    return Ok(cx.null().upcast());
+}
+
+/// Takes the interval in milliseconds between telemetry events, or null to stop them
+fn set_telemetry_interval(mut cx: FunctionContext) -> JsResult<JsNull> {
+    let interval = cx
+        .argument_opt(0)
+        .and_then(|arg| arg.downcast::<JsNumber, _>(&mut cx).ok())
+        .map(|ms| Duration::from_millis(ms.value(&mut cx) as u64));
+    #[cfg(windows)]
+    if let Some(runner) = SERVICE.lock().unwrap().as_ref() {
+        if let Err(e) = runner.set_telemetry_interval(interval) {
+            error!("Failed to set telemetry interval {:#}", e);
+        }
+    }
+    #[cfg(not(windows))]
+    let _ = interval;
+    return Ok(cx.null());
+}
+
+fn get_poll_stats(mut cx: FunctionContext) -> JsResult<JsValue> {
+    #[cfg(windows)]
+    if let Some(runner) = SERVICE.lock().unwrap().as_ref() {
+        if let Some(stats) = runner.snapshot().poll_stats.as_ref() {
+            return Ok(cx
+                .string(serde_json::to_string(stats).expect("Failed to serialize poll stats"))
+                .upcast());
+        }
+    }
+
+    return Ok(cx.null().upcast());
+}
+
+fn reset_poll_stats(mut cx: FunctionContext) -> JsResult<JsNull> {
+    #[cfg(windows)]
+    if let Some(runner) = SERVICE.lock().unwrap().as_ref() {
+        if let Err(e) = runner.reset_poll_stats() {
+            error!("Failed to reset poll stats {:#}", e);
+        }
+    }
+    return Ok(cx.null());
+}
+
 fn set_config(mut cx: FunctionContext) -> JsResult<JsNull> {
     let config_arg = cx.argument::<JsString>(0)?.value(&mut cx);
-    let config: ServiceConfiguration = serde_json::from_str(&config_arg[..]).unwrap();
+    let config = match ServiceConfiguration::parse(&config_arg) {
+        Ok(config) => config,
+        Err(errors) => return throw_config_errors(&mut cx, &errors),
+    };
     info!("Received config {:?}", config);
     #[cfg(windows)]
-    SERVICE.lock().unwrap().set_config(config).unwrap();
+    if let Some(runner) = SERVICE.lock().unwrap().as_ref() {
+        if let Err(e) = runner.set_config(config) {
+            error!("Failed to apply config {:#}", e);
+            return cx.throw_error(format!("{:#}", e));
+        }
+    }
     return Ok(cx.null());
 }
 
+/// Returns the conflicts, shadowed binds and unbound directions in each controller's key mapping as a JSON array.
+/// Takes a whole config so older key formats get migrated the same way `set_config` would
+fn analyze_bindings(mut cx: FunctionContext) -> JsResult<JsString> {
+    let config_arg = cx.argument::<JsString>(0)?.value(&mut cx);
+    let config = match ServiceConfiguration::from_json(&config_arg) {
+        Ok(config) => config,
+        Err(e) => return throw_config_errors(&mut cx, &[e]),
+    };
+    let issues = config.analyze_bindings();
+    Ok(cx.string(serde_json::to_string(&issues).expect("Failed to serialize binding issues")))
+}
+
+#[cfg(windows)]
+fn set_gamepad_detection_state(enabled: bool) {
+    if let Some(runner) = SERVICE.lock().unwrap().as_ref() {
+        if let Err(e) = runner.set_gamepad_detection_state(enabled) {
+            error!("Failed to set gamepad detection state {:#}", e);
+        }
+    }
+}
+
 fn start_gamepad_detection(mut cx: FunctionContext) -> JsResult<JsNull> {
     #[cfg(windows)]
-    SERVICE.lock().unwrap().set_gamepad_detection_state(true);
+    set_gamepad_detection_state(true);
     return Ok(cx.null());
 }
 
 fn end_gamepad_detection(mut cx: FunctionContext) -> JsResult<JsNull> {
     #[cfg(windows)]
-    SERVICE.lock().unwrap().set_gamepad_detection_state(false);
+    set_gamepad_detection_state(false);
+    return Ok(cx.null());
+}
+
+/// Takes the analog value a key has to pass to be captured, between 0 and 1, defaulting to
+/// `DEFAULT_CAPTURE_THRESHOLD`. The key is reported through a `BindCaptured` event. Throws if the threshold is out of
+/// range or the service isn't running, as keys are only read while it is
+fn start_bind_capture(mut cx: FunctionContext) -> JsResult<JsNull> {
+    let threshold = cx
+        .argument_opt(0)
+        .and_then(|arg| arg.downcast::<JsNumber, _>(&mut cx).ok())
+        .map(|threshold| threshold.value(&mut cx));
+    if let Some(threshold) = threshold {
+        if !threshold.is_finite() || threshold < 0.0 || threshold > 1.0 {
+            return cx.throw_range_error(format!(
+                "The capture threshold must be between 0 and 1, got {}",
+                threshold
+            ));
+        }
+    }
+
+    #[cfg(windows)]
+    if let Some(runner) = SERVICE.lock().unwrap().as_ref() {
+        let threshold = threshold.map_or(capture::DEFAULT_CAPTURE_THRESHOLD, |t| t as f32);
+        if let Err(e) = runner.start_bind_capture(threshold) {
+            error!("Failed to start bind capture {:#}", e);
+            return cx.throw_error(format!("{:#}", e));
+        }
+        return Ok(cx.null());
+    }
+
+    cx.throw_error("The service isn't running, start it before capturing a bind")
+}
+
+fn end_bind_capture(mut cx: FunctionContext) -> JsResult<JsNull> {
+    #[cfg(windows)]
+    if let Some(runner) = SERVICE.lock().unwrap().as_ref() {
+        if let Err(e) = runner.end_bind_capture() {
+            error!("Failed to end bind capture {:#}", e);
+        }
+    }
     return Ok(cx.null());
 }
+
+fn subscribe(mut cx: FunctionContext) -> JsResult<JsValue> {
+    #[cfg(windows)]
+    {
+        let callback = cx.argument::<JsFunction>(0)?.root(&mut cx);
+        let queue = cx.queue();
+        let id = events::subscribe(callback, queue);
+        return Ok(cx.number(id).upcast());
+    }
+
+    #[cfg(not(windows))]
+    return Ok(cx.null().upcast());
+}
+
+fn unsubscribe(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    #[cfg(windows)]
+    {
+        let id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+        let removed = events::unsubscribe(&mut cx, id);
+        return Ok(cx.boolean(removed));
+    }
+
+    #[cfg(not(windows))]
+    return Ok(cx.boolean(false));
+}
@@ -0,0 +1,358 @@
+use std::convert::TryFrom;
+
+use anyhow::{bail, Context, Result};
+use log::*;
+use serde_json::{Map, Value};
+
+use crate::config::CONFIG_VERSION;
+use crate::keycode::KeyCode;
+
+type Migration = fn(&mut Map<String, Value>) -> Result<()>;
+
+/// Migration steps, where the entry at index `n` upgrades a config from version `n` to `n + 1`.
+/// The length of this must always match `CONFIG_VERSION`
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4];
+
+/// Upgrades a raw config to `CONFIG_VERSION` by running every migration step after the version it was saved with.
+/// Configs without a `version` field predate versioning and are treated as version 0
+pub fn migrate(mut value: Value) -> Result<Value> {
+    let config = value
+        .as_object_mut()
+        .context("Config must be a JSON object")?;
+
+    let version = match config.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .context("Config version must be a positive integer")? as u32,
+    };
+
+    if version > CONFIG_VERSION {
+        bail!(
+            "Config version {} is newer than the supported version {}",
+            version,
+            CONFIG_VERSION
+        );
+    }
+
+    for (from, step) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        debug!("Migrating config from version {} to {}", from, from + 1);
+        step(config).with_context(|| format!("Failed to migrate config from version {}", from))?;
+        config.insert("version".to_string(), Value::from(from + 1));
+    }
+
+    Ok(value)
+}
+
+/// Version 0 covers every config sent before versioning was introduced. The oldest of these still used
+/// `leftJoystickAngles` with a single `rightUpAngle`, which is now `leftJoystickStrafingAngles.upDiagonalAngle`
+fn v0_to_v1(config: &mut Map<String, Value>) -> Result<()> {
+    if let Some(mut angles) = config.remove("leftJoystickAngles") {
+        if !config.contains_key("leftJoystickStrafingAngles") {
+            let angles_obj = angles
+                .as_object_mut()
+                .context("leftJoystickAngles must be an object")?;
+            if let Some(right_up_angle) = angles_obj.remove("rightUpAngle") {
+                angles_obj.insert("upDiagonalAngle".to_string(), right_up_angle);
+                // The left/right angle didn't exist back then, so keep it off to preserve the old behaviour
+                angles_obj.insert("useLeftRightAngle".to_string(), Value::Bool(false));
+            }
+            config.insert("leftJoystickStrafingAngles".to_string(), angles);
+        }
+    }
+
+    Ok(())
+}
+
+/// Version 2 added support for multiple controllers, so the single controller's settings move into the first entry
+/// of `controllers`
+fn v1_to_v2(config: &mut Map<String, Value>) -> Result<()> {
+    if config.contains_key("controllers") {
+        return Ok(());
+    }
+
+    let mut controller = Map::new();
+    for key in &["leftJoystickStrafingAngles", "keyMapping"] {
+        if let Some(value) = config.remove(*key) {
+            controller.insert(key.to_string(), value);
+        }
+    }
+    config.insert(
+        "controllers".to_string(),
+        Value::Array(vec![Value::Object(controller)]),
+    );
+
+    Ok(())
+}
+
+/// Version 3 replaced `useAnalogInput` with `inputPolicy`. Analog input used to fall back to key states if the SDK
+/// couldn't be loaded, so that's kept as the policy for anyone who had it on
+fn v2_to_v3(config: &mut Map<String, Value>) -> Result<()> {
+    if let Some(use_analog_input) = config.remove("useAnalogInput") {
+        if !config.contains_key("inputPolicy") {
+            let use_analog_input = use_analog_input
+                .as_bool()
+                .context("useAnalogInput must be a boolean")?;
+            let policy = if use_analog_input {
+                "AnalogWithFallback"
+            } else {
+                "Digital"
+            };
+            config.insert("inputPolicy".to_string(), Value::from(policy));
+        }
+    }
+
+    Ok(())
+}
+
+/// Version 4 switched binds from Windows virtual-key codes to HID usage codes. Binds given by name are already
+/// layout-independent, so only numeric keys need translating. Keys without a HID equivalent are dropped, as there's
+/// nothing they could be read from anymore
+fn v3_to_v4(config: &mut Map<String, Value>) -> Result<()> {
+    let controllers = match config.get_mut("controllers") {
+        Some(controllers) => controllers
+            .as_array_mut()
+            .context("controllers must be an array")?,
+        None => return Ok(()),
+    };
+
+    for controller in controllers.iter_mut() {
+        let mapping = match controller
+            .get_mut("keyMapping")
+            .and_then(Value::as_object_mut)
+        {
+            Some(mapping) => mapping,
+            None => continue,
+        };
+
+        for joystick in &["leftJoystick", "rightJoystick"] {
+            if let Some(joystick) = mapping.get_mut(*joystick).and_then(Value::as_object_mut) {
+                for (field, value) in joystick.iter_mut() {
+                    match value {
+                        // The legacy shape, with up to two keys per direction in `up`, `up_two` etc.
+                        Value::Number(_) => *value = vk_to_hid(field, value),
+                        Value::Object(direction) => binds_to_hid(field, direction),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        if let Some(buttons) = mapping.get_mut("buttons").and_then(Value::as_object_mut) {
+            for (field, button) in buttons.iter_mut() {
+                if let Some(button) = button.as_object_mut() {
+                    binds_to_hid(field, button);
+                }
+            }
+        }
+
+        if let Some(taps) = mapping.get_mut("taps").and_then(Value::as_array_mut) {
+            keys_to_hid("taps", taps);
+        }
+    }
+
+    Ok(())
+}
+
+/// Translates the keys of a `DirectionBinds`
+fn binds_to_hid(field: &str, direction: &mut Map<String, Value>) {
+    if let Some(binds) = direction.get_mut("binds").and_then(Value::as_array_mut) {
+        keys_to_hid(field, binds);
+    }
+}
+
+/// Translates the `key` of each entry, dropping the ones that have no HID usage code
+fn keys_to_hid(field: &str, entries: &mut Vec<Value>) {
+    for entry in entries.iter_mut() {
+        if let Some(key) = entry.get_mut("key") {
+            *key = vk_to_hid(field, key);
+        }
+    }
+    entries.retain(|entry| entry.get("key") != Some(&Value::Null));
+}
+
+/// Translates a numeric virtual-key code to its HID usage code, or null if it has none. Anything else is kept as-is
+fn vk_to_hid(field: &str, value: &Value) -> Value {
+    let vk = match value.as_u64() {
+        Some(vk) => vk,
+        None => return value.clone(),
+    };
+    match u8::try_from(vk).ok().and_then(KeyCode::from_vk) {
+        Some(key) => Value::from(key.0),
+        None => {
+            warn!(
+                "Dropping {} bind with virtual key {:#x}, which has no HID usage code",
+                field, vk
+            );
+            Value::Null
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::ServiceConfiguration;
+
+    fn hid(name: &str) -> Value {
+        Value::from(KeyCode::from_name(name).unwrap().0)
+    }
+
+    #[test]
+    fn v0_moves_the_old_angles_and_wraps_the_controller() {
+        let migrated = migrate(json!({
+            "leftJoystickAngles": { "rightUpAngle": 0.5 },
+            "keyMapping": { "leftJoystick": { "up": 0x57 } },
+            "useAnalogInput": true,
+        }))
+        .unwrap();
+
+        assert_eq!(migrated["version"], json!(CONFIG_VERSION));
+        let controllers = migrated["controllers"].as_array().unwrap();
+        assert_eq!(controllers.len(), 1);
+        assert_eq!(
+            controllers[0]["leftJoystickStrafingAngles"],
+            json!({ "upDiagonalAngle": 0.5, "useLeftRightAngle": false })
+        );
+        assert_eq!(
+            controllers[0]["keyMapping"]["leftJoystick"]["up"],
+            hid("KeyW")
+        );
+        assert!(migrated.get("leftJoystickAngles").is_none());
+        assert!(migrated.get("keyMapping").is_none());
+    }
+
+    #[test]
+    fn v1_keeps_existing_controllers() {
+        let controllers = json!([{ "output": "DualShock4" }, { "output": "Xbox360" }]);
+        let migrated = migrate(json!({ "version": 1, "controllers": controllers })).unwrap();
+        assert_eq!(migrated["controllers"], controllers);
+    }
+
+    #[test]
+    fn v2_turns_use_analog_input_into_a_policy() {
+        let cases = [
+            (json!(true), "AnalogWithFallback"),
+            (json!(false), "Digital"),
+        ];
+        for (use_analog_input, policy) in cases.iter() {
+            let migrated = migrate(json!({
+                "version": 2,
+                "controllers": [],
+                "useAnalogInput": use_analog_input,
+            }))
+            .unwrap();
+            assert_eq!(migrated["inputPolicy"], json!(policy));
+            assert!(migrated.get("useAnalogInput").is_none());
+        }
+
+        // An explicit policy wins over the old setting
+        let migrated = migrate(json!({
+            "version": 2,
+            "useAnalogInput": true,
+            "inputPolicy": "Analog",
+        }))
+        .unwrap();
+        assert_eq!(migrated["inputPolicy"], json!("Analog"));
+
+        assert!(migrate(json!({ "version": 2, "useAnalogInput": 1 })).is_err());
+    }
+
+    #[test]
+    fn v3_converts_every_bind_location_to_hid() {
+        let migrated = migrate(json!({
+            "version": 3,
+            "controllers": [
+                {
+                    "keyMapping": {
+                        "leftJoystick": {
+                            "up": 0x57,
+                            "up_two": 0x26,
+                            "sources": { "up": "Digital" },
+                            "down": { "binds": [{ "key": 0x53 }, { "key": 0xff }, { "key": "KeyX" }] },
+                        },
+                        "rightJoystick": { "left": { "binds": [{ "key": 0x25 }] } },
+                        "buttons": { "ps": { "binds": [{ "key": 0x50, "source": "Digital" }] } },
+                        "taps": [{ "key": 0x20, "button": "A" }, { "key": 0xff, "button": "B" }],
+                    }
+                },
+                { "keyMapping": { "leftJoystick": { "left": 0x41 } } },
+            ],
+        }))
+        .unwrap();
+
+        let mapping = &migrated["controllers"][0]["keyMapping"];
+        assert_eq!(mapping["leftJoystick"]["up"], hid("KeyW"));
+        assert_eq!(mapping["leftJoystick"]["up_two"], hid("ArrowUp"));
+        assert_eq!(
+            mapping["leftJoystick"]["sources"],
+            json!({ "up": "Digital" })
+        );
+        // 0xff has no HID usage code so it's dropped, while names are kept as they are
+        assert_eq!(
+            mapping["leftJoystick"]["down"]["binds"],
+            json!([{ "key": hid("KeyS") }, { "key": "KeyX" }])
+        );
+        assert_eq!(
+            mapping["rightJoystick"]["left"]["binds"],
+            json!([{ "key": hid("ArrowLeft") }])
+        );
+        assert_eq!(
+            mapping["buttons"]["ps"]["binds"],
+            json!([{ "key": hid("KeyP"), "source": "Digital" }])
+        );
+        assert_eq!(
+            mapping["taps"],
+            json!([{ "key": hid("Space"), "button": "A" }])
+        );
+        assert_eq!(
+            migrated["controllers"][1]["keyMapping"]["leftJoystick"]["left"],
+            hid("KeyA")
+        );
+    }
+
+    #[test]
+    fn current_configs_are_not_migrated_again() {
+        // HID KeyA (0x04) is VK_MBUTTON and HID KeyW (0x1a) has no virtual key, so converting these twice would
+        // change or drop them
+        let config = json!({
+            "version": CONFIG_VERSION,
+            "controllers": [{ "keyMapping": { "leftJoystick": { "up": hid("KeyW"), "left": hid("KeyA") } } }],
+        });
+        assert_eq!(migrate(config.clone()).unwrap(), config);
+    }
+
+    #[test]
+    fn hid_round_trip() {
+        let config = ServiceConfiguration::from_json(
+            &json!({
+                "version": 3,
+                "controllers": [{ "keyMapping": { "leftJoystick": { "up": 0x57, "left": 0x41 } } }],
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let saved = serde_json::to_value(&config).unwrap();
+        assert_eq!(saved["version"], json!(CONFIG_VERSION));
+
+        let reloaded = ServiceConfiguration::from_json(&saved.to_string()).unwrap();
+        let joystick = &reloaded.controllers[0].key_mapping.left_joystick;
+        assert_eq!(
+            joystick.up.binds[0].key,
+            KeyCode::from_name("KeyW").unwrap()
+        );
+        assert_eq!(
+            joystick.left.binds[0].key,
+            KeyCode::from_name("KeyA").unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_newer_and_invalid_versions() {
+        assert!(migrate(json!({ "version": CONFIG_VERSION + 1 })).is_err());
+        assert!(migrate(json!({ "version": "3" })).is_err());
+        assert!(migrate(json!([])).is_err());
+    }
+}
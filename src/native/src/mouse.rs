@@ -0,0 +1,255 @@
+// Only the service uses this, which is Windows only, but it's built everywhere for its tests
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::config::MouseStickConfiguration;
+
+/// How far back mouse motion is averaged over to work out its speed. Polls usually happen more often than the mouse
+/// reports, so the speed has to be taken over a few reports rather than since the last poll. Being a multiple of the
+/// usual report intervals (1, 2, 4 & 8ms) it always holds the same number of reports, so steady movement gives a
+/// steady speed
+const SPEED_WINDOW: Duration = Duration::from_millis(24);
+
+/// Turns relative mouse motion into a stick position. Motion is read as a speed, so moving the mouse faster pushes
+/// the stick further. The stick follows a faster movement straight away, but only eases towards a slower one or back
+/// to the centre over the configured decay time rather than snapping to it
+pub struct MouseStick {
+    /// The stick position before the curve and deadzone compensation are applied
+    position: (f32, f32),
+    /// The motion received within the last `SPEED_WINDOW`, oldest first
+    recent: VecDeque<(Instant, (i32, i32))>,
+    last_update: Option<Instant>,
+}
+
+impl MouseStick {
+    pub fn new() -> Self {
+        MouseStick {
+            position: (0.0, 0.0),
+            recent: VecDeque::new(),
+            last_update: None,
+        }
+    }
+
+    /// Takes the motion in mouse counts since the last update and returns the stick position, with x & y
+    /// between -1 -> 1 and up being positive
+    pub fn update(
+        &mut self,
+        motion: (i32, i32),
+        config: &MouseStickConfiguration,
+        now: Instant,
+    ) -> (f32, f32) {
+        let elapsed = match self.last_update.replace(now) {
+            Some(last_update) => now.saturating_duration_since(last_update).as_secs_f32(),
+            None => 0.0,
+        };
+
+        if motion != (0, 0) {
+            self.recent.push_back((now, motion));
+        }
+        while let Some(&(at, _)) = self.recent.front() {
+            if now.saturating_duration_since(at) < SPEED_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        let counts = self
+            .recent
+            .iter()
+            .fold((0, 0), |(x, y), (_, motion)| (x + motion.0, y + motion.1));
+
+        // Sensitivity is the deflection for 1000 counts a second, mouse y goes down the screen
+        let scale = config.sensitivity / (SPEED_WINDOW.as_secs_f32() * 1000.0);
+        let target = clamp_length((counts.0 as f32 * scale, -counts.1 as f32 * scale));
+
+        let (x, y) = self.position;
+        let (to_x, to_y) = (target.0 - x, target.1 - y);
+        let distance = (to_x * to_x + to_y * to_y).sqrt();
+        let max_step = if config.decay_ms == 0 {
+            distance
+        } else {
+            elapsed * 1000.0 / config.decay_ms as f32
+        };
+        self.position = if length(target) >= length(self.position) || distance <= max_step {
+            target
+        } else {
+            let scale = max_step / distance;
+            (x + to_x * scale, y + to_y * scale)
+        };
+
+        self.output(config)
+    }
+
+    /// Applies the curve, deadzone compensation and inversion to the current position
+    fn output(&self, config: &MouseStickConfiguration) -> (f32, f32) {
+        let (x, y) = self.position;
+        let length = length(self.position);
+        if length == 0.0 {
+            return (0.0, 0.0);
+        }
+
+        let curved = length.powf(config.curve).min(1.0);
+        // Skip over the game's deadzone so the smallest movements still turn the camera
+        let compensated =
+            config.deadzone_compensation + (1.0 - config.deadzone_compensation) * curved;
+        let scale = compensated / length;
+        let x = if config.invert_x { -x } else { x };
+        let y = if config.invert_y { -y } else { y };
+        (x * scale, y * scale)
+    }
+}
+
+fn length((x, y): (f32, f32)) -> f32 {
+    (x * x + y * y).sqrt()
+}
+
+/// Scales the position back onto the edge of the stick's range if it's past it
+fn clamp_length(position: (f32, f32)) -> (f32, f32) {
+    let length = length(position);
+    if length > 1.0 {
+        (position.0 / length, position.1 / length)
+    } else {
+        position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MouseStickConfiguration {
+        MouseStickConfiguration {
+            enabled: true,
+            sensitivity: 0.5,
+            curve: 1.0,
+            deadzone_compensation: 0.0,
+            decay_ms: 48,
+            invert_x: false,
+            invert_y: false,
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn assert_near(actual: (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-4 && (actual.1 - expected.1).abs() < 1e-4,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    /// Polls every millisecond for `duration_ms`, with the mouse reporting `report` every 8ms like a 125Hz mouse.
+    /// Returns the output of each poll
+    fn run(
+        stick: &mut MouseStick,
+        config: &MouseStickConfiguration,
+        start: Instant,
+        duration_ms: u64,
+        report: (i32, i32),
+    ) -> Vec<(f32, f32)> {
+        (0..duration_ms)
+            .map(|t| {
+                let motion = if t % 8 == 0 { report } else { (0, 0) };
+                stick.update(motion, config, start + ms(t))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn steady_motion_gives_a_steady_position_between_reports() {
+        let config = config();
+        let mut stick = MouseStick::new();
+
+        // 8 counts every 8ms is 1000 counts a second, so the sensitivity itself
+        let outputs = run(&mut stick, &config, Instant::now(), 200, (8, 0));
+
+        for output in &outputs[SPEED_WINDOW.as_millis() as usize..] {
+            assert_near(*output, (0.5, 0.0));
+        }
+    }
+
+    #[test]
+    fn mouse_y_goes_down_and_can_be_inverted() {
+        let start = Instant::now();
+        let cases = [
+            (false, false, (0.25, -0.25)),
+            (true, false, (-0.25, -0.25)),
+            (false, true, (0.25, 0.25)),
+            (true, true, (-0.25, 0.25)),
+        ];
+        for &(invert_x, invert_y, expected) in cases.iter() {
+            let config = MouseStickConfiguration {
+                invert_x,
+                invert_y,
+                ..config()
+            };
+            let mut stick = MouseStick::new();
+            let outputs = run(&mut stick, &config, start, 100, (4, 4));
+            assert_near(*outputs.last().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn curve_and_deadzone_compensation() {
+        let start = Instant::now();
+        let cases = [
+            (1.0, 0.0, 0.5),
+            (2.0, 0.0, 0.25),
+            (0.5, 0.0, 0.5f32.sqrt()),
+            (1.0, 0.2, 0.2 + 0.8 * 0.5),
+            (2.0, 0.2, 0.2 + 0.8 * 0.25),
+        ];
+        for &(curve, deadzone_compensation, expected) in cases.iter() {
+            let config = MouseStickConfiguration {
+                curve,
+                deadzone_compensation,
+                ..config()
+            };
+            let mut stick = MouseStick::new();
+            let outputs = run(&mut stick, &config, start, 100, (8, 0));
+            assert_near(*outputs.last().unwrap(), (expected, 0.0));
+        }
+    }
+
+    #[test]
+    fn fast_motion_is_clamped_to_the_edge() {
+        let config = config();
+        let mut stick = MouseStick::new();
+
+        let outputs = run(&mut stick, &config, Instant::now(), 100, (300, -400));
+
+        assert_near(*outputs.last().unwrap(), (0.6, 0.8));
+    }
+
+    #[test]
+    fn decays_to_the_centre_once_the_mouse_stops() {
+        let config = config();
+        let start = Instant::now();
+        let mut stick = MouseStick::new();
+        run(&mut stick, &config, start, 96, (8, 0));
+
+        // It falls by the full range every 48ms, even though the reports leave the speed window faster than that
+        assert_near(stick.update((0, 0), &config, start + ms(107)), (0.25, 0.0));
+        assert_near(stick.update((0, 0), &config, start + ms(113)), (0.125, 0.0));
+        assert_near(stick.update((0, 0), &config, start + ms(119)), (0.0, 0.0));
+        assert_near(stick.update((0, 0), &config, start + ms(500)), (0.0, 0.0));
+    }
+
+    #[test]
+    fn no_decay_time_snaps_back() {
+        let config = MouseStickConfiguration {
+            decay_ms: 0,
+            ..config()
+        };
+        let start = Instant::now();
+        let mut stick = MouseStick::new();
+        run(&mut stick, &config, start, 96, (8, 0));
+
+        assert_near(stick.update((0, 0), &config, start + ms(112)), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,407 @@
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use arc_swap::ArcSwap;
+use log::*;
+use winapi::um::timeapi::{timeBeginPeriod, timeEndPeriod};
+
+use crate::analog::{AnalogSDKInfo, AnalogSDKState};
+use crate::config::{PollConfiguration, PollMode, ServiceConfiguration};
+use crate::events::{self, ServiceEvent};
+use crate::service::{InputSource, Service, ServiceState, Telemetry};
+use crate::stats::{PollStats, PollStatsSummary};
+use crate::watchdog::{StallMonitor, Watchdog};
+
+/// In precise mode we stop sleeping this long before the next poll is due and spin for the rest
+const SPIN_THRESHOLD: Duration = Duration::from_millis(2);
+/// How often the poll stats are published for JS
+const STATS_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the poll stats are written to the log
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the telemetry in the snapshot is refreshed. Building it every poll would be wasted when JS reads it at
+/// most once a frame
+const SNAPSHOT_TELEMETRY_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Requests from JS to the service thread. Anything that needs an answer carries a `Sender` to reply on
+enum Command {
+    SetConfig(ServiceConfiguration, Sender<Result<()>>),
+    SetGamepadDetection(bool),
+    SetTelemetryInterval(Option<Duration>),
+    GetXInputSlots(Sender<Vec<Option<u32>>>),
+    StartBindCapture(f32),
+    EndBindCapture,
+    ResetPollStats,
+    Stop,
+}
+
+/// State published by the service thread so JS can read it without waiting on the service. It's republished as soon as
+/// the service's state changes, and otherwise only as often as the telemetry and stats need refreshing
+#[derive(Clone)]
+pub struct ServiceSnapshot {
+    pub state: ServiceState,
+    pub sdk_state: AnalogSDKState,
+    pub sdk_info: AnalogSDKInfo,
+    pub input_source: InputSource,
+    pub telemetry: Option<Telemetry>,
+    pub poll_stats: Option<PollStatsSummary>,
+}
+
+impl ServiceSnapshot {
+    fn new() -> Self {
+        ServiceSnapshot {
+            state: ServiceState::Stopped,
+            sdk_state: AnalogSDKState::Uninitialized,
+            sdk_info: AnalogSDKInfo::default(),
+            input_source: InputSource::Digital,
+            telemetry: None,
+            poll_stats: None,
+        }
+    }
+}
+
+/// Works out when each poll should happen and waits for it according to the `PollMode`
+struct PollScheduler {
+    interval: Duration,
+    mode: PollMode,
+    next_poll: Instant,
+}
+
+impl PollScheduler {
+    fn new(config: &PollConfiguration) -> Self {
+        let mut scheduler = PollScheduler {
+            interval: config.interval(),
+            mode: PollMode::Sleep,
+            next_poll: Instant::now(),
+        };
+        scheduler.set_config(config);
+        scheduler
+    }
+
+    fn set_config(&mut self, config: &PollConfiguration) {
+        self.interval = config.interval();
+        if self.mode != config.mode {
+            // The default timer resolution on Windows is ~15ms, so ask for 1ms while being precise
+            unsafe {
+                if config.mode == PollMode::Precise {
+                    timeBeginPeriod(1);
+                } else {
+                    timeEndPeriod(1);
+                }
+            }
+            self.mode = config.mode;
+        }
+    }
+
+    /// Waits until the next poll is due, returning how late we were woken up for it
+    fn wait(&mut self) -> Duration {
+        let now = Instant::now();
+        if self.next_poll > now {
+            let remaining = self.next_poll - now;
+            match self.mode {
+                PollMode::Sleep => thread::sleep(remaining),
+                PollMode::Precise => {
+                    if remaining > SPIN_THRESHOLD {
+                        thread::sleep(remaining - SPIN_THRESHOLD);
+                    }
+                    while Instant::now() < self.next_poll {
+                        std::hint::spin_loop();
+                    }
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let lateness = now.saturating_duration_since(self.next_poll);
+        // If we fell behind, schedule from now rather than trying to catch up with a burst of polls
+        self.next_poll = (self.next_poll + self.interval).max(now);
+        lateness
+    }
+}
+
+impl Drop for PollScheduler {
+    fn drop(&mut self) {
+        if self.mode == PollMode::Precise {
+            unsafe {
+                timeEndPeriod(1);
+            }
+        }
+    }
+}
+
+/// Handle to a service running on its own thread. The `Service` is created, polled and dropped on that thread,
+/// so JS calls only ever queue commands or read the latest snapshot and can't hold up input processing
+pub struct ServiceRunner {
+    commands: Sender<Command>,
+    snapshot: Arc<ArcSwap<ServiceSnapshot>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ServiceRunner {
+    /// Spawns the service thread and waits for the service to initialise with the given config
+    pub fn start(config: ServiceConfiguration) -> Result<Self> {
+        let (commands, receiver) = channel();
+        let (init_sender, init_receiver) = channel();
+        let snapshot = Arc::new(ArcSwap::from_pointee(ServiceSnapshot::new()));
+
+        let thread_snapshot = snapshot.clone();
+        let thread = thread::Builder::new()
+            .name("service".to_string())
+            .spawn(move || {
+                let mut service = Service::new();
+                match service.init(config) {
+                    Ok(()) => {
+                        if init_sender.send(Ok(())).is_ok() {
+                            run(service, receiver, thread_snapshot);
+                        } else {
+                            service.stop();
+                        }
+                    }
+                    Err(e) => {
+                        // Nothing to run, so clean up whatever did get initialised and let the thread end
+                        service.stop();
+                        let _ = init_sender.send(Err(e));
+                    }
+                }
+            })
+            .context("Failed to spawn service thread")?;
+
+        // The sender is dropped without a reply if the service panicked during init
+        let init_result = init_receiver
+            .recv()
+            .unwrap_or_else(|_| Err(anyhow!("The service panicked during init")));
+        if let Err(e) = init_result {
+            let _ = thread.join();
+            return Err(e);
+        }
+
+        Ok(ServiceRunner {
+            commands,
+            snapshot,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn set_config(&self, config: ServiceConfiguration) -> Result<()> {
+        let (sender, receiver) = channel();
+        self.send(Command::SetConfig(config, sender))?;
+        receiver
+            .recv()
+            .context("Service stopped before applying the config")?
+    }
+
+    pub fn set_gamepad_detection_state(&self, enabled: bool) -> Result<()> {
+        self.send(Command::SetGamepadDetection(enabled))
+    }
+
+    pub fn set_telemetry_interval(&self, interval: Option<Duration>) -> Result<()> {
+        self.send(Command::SetTelemetryInterval(interval))
+    }
+
+    pub fn get_xinput_slots(&self) -> Result<Vec<Option<u32>>> {
+        let (sender, receiver) = channel();
+        self.send(Command::GetXInputSlots(sender))?;
+        receiver
+            .recv()
+            .context("Service stopped before getting the xinput slots")
+    }
+
+    pub fn start_bind_capture(&self, threshold: f32) -> Result<()> {
+        self.send(Command::StartBindCapture(threshold))
+    }
+
+    pub fn end_bind_capture(&self) -> Result<()> {
+        self.send(Command::EndBindCapture)
+    }
+
+    pub fn reset_poll_stats(&self) -> Result<()> {
+        self.send(Command::ResetPollStats)
+    }
+
+    pub fn snapshot(&self) -> Arc<ServiceSnapshot> {
+        self.snapshot.load_full()
+    }
+
+    pub fn stop(mut self) {
+        if self.send(Command::Stop).is_err() {
+            warn!("Service thread had already stopped");
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Service thread panicked");
+            }
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| anyhow!("Service thread is no longer running"))
+    }
+}
+
+fn run(mut service: Service, receiver: Receiver<Command>, snapshot: Arc<ArcSwap<ServiceSnapshot>>) {
+    let mut scheduler = PollScheduler::new(service.get_poll_config());
+    let mut stats = PollStats::new();
+    let mut poll_stats = None;
+    let mut last_stats_publish = Instant::now();
+    let mut last_stats_log = Instant::now();
+    let mut last_snapshot_publish = Instant::now();
+    let mut watchdog = Watchdog::new();
+    let stall_monitor = {
+        let snapshot = snapshot.clone();
+        StallMonitor::start(move |stalled_for| on_stall(&snapshot, stalled_for))
+    };
+
+    loop {
+        if stall_monitor.heartbeat() {
+            warn!("Poll thread has resumed after stalling");
+            // Whatever was held when the thread stalled may well have been let go since, so start again from the
+            // centre rather than leave it held until the next poll. The stall monitor told JS the service faulted, so
+            // put that back too
+            if let Err(e) = service.output_neutral() {
+                error!("Failed to output neutral report after stalling {:#}", e);
+            }
+            events::emit(ServiceEvent::ServiceStateChanged(service.get_state()));
+        }
+
+        // Handle everything that's been queued since the last poll before polling again
+        loop {
+            match receiver.try_recv() {
+                Ok(Command::SetConfig(config, reply)) => {
+                    // This can reload the analog SDK and recreate controllers, which can take a while
+                    let result = stall_monitor.paused(|| service.set_config(config));
+                    if result.is_ok() {
+                        // A new config is a fresh chance for things to work, so allow recovery attempts again
+                        watchdog.reset_recovery();
+                        if let ServiceState::Faulted { .. } = service.get_state() {
+                            watchdog.recover_now(Instant::now());
+                        }
+                    }
+                    let _ = reply.send(result);
+                    scheduler.set_config(service.get_poll_config());
+                }
+                Ok(Command::SetGamepadDetection(enabled)) => {
+                    service.set_gamepad_detection_state(enabled)
+                }
+                Ok(Command::SetTelemetryInterval(interval)) => {
+                    service.set_telemetry_interval(interval)
+                }
+                Ok(Command::GetXInputSlots(reply)) => {
+                    let _ = reply.send(service.get_xinput_slots());
+                }
+                Ok(Command::StartBindCapture(threshold)) => {
+                    if let Err(e) = service.start_bind_capture(threshold) {
+                        error!("Failed to start bind capture {:#}", e);
+                    }
+                }
+                Ok(Command::EndBindCapture) => service.end_bind_capture(),
+                Ok(Command::ResetPollStats) => {
+                    stats = PollStats::new();
+                }
+                Ok(Command::Stop) | Err(TryRecvError::Disconnected) => {
+                    info!("Poll stats: {:?}", stats.summary());
+                    // Stop the stall monitor first, so it can't take a slow stop for a stall and publish a fault over
+                    // the stopped snapshot
+                    drop(stall_monitor);
+                    service.stop();
+                    snapshot.store(Arc::new(ServiceSnapshot::new()));
+                    return;
+                }
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        if let ServiceState::Faulted { .. } = service.get_state() {
+            if watchdog.should_attempt_recovery(Instant::now()) {
+                // Recovering reconnects to ViGEm and reloads the analog SDK, which can take a while
+                match stall_monitor.paused(|| service.recover()) {
+                    Ok(()) => service.set_state(ServiceState::Running),
+                    Err(e) => {
+                        error!("Failed to recover the service {:#}", e);
+                        watchdog.recovery_failed(Instant::now());
+                    }
+                }
+            }
+        } else {
+            let poll_start = Instant::now();
+            match service.poll() {
+                Ok(report_sent) => {
+                    watchdog.record_success(Instant::now());
+                    if report_sent {
+                        stats.latency.record(poll_start.elapsed());
+                    }
+                }
+                Err(e) => {
+                    let outcome = watchdog.record_failure(Instant::now());
+                    if let Some(suppressed) = outcome.report {
+                        if suppressed > 0 {
+                            error!(
+                                "Error occurred during polling ({} similar errors suppressed) {:#?}",
+                                suppressed, e
+                            );
+                        } else {
+                            error!("Error occurred during polling {:#?}", e);
+                        }
+                        events::emit(ServiceEvent::PollError(format!("{:#}", e)));
+                    }
+
+                    if outcome.fault {
+                        if let Err(e) = service.output_neutral() {
+                            error!("Failed to output neutral report {:#}", e);
+                        }
+                        service.set_state(ServiceState::Faulted {
+                            reason: format!("{:#}", e),
+                        });
+                    }
+                }
+            }
+        }
+
+        let mut publish = false;
+        if last_stats_publish.elapsed() >= STATS_PUBLISH_INTERVAL {
+            last_stats_publish = Instant::now();
+            poll_stats = Some(stats.summary());
+            publish = true;
+        }
+        if last_stats_log.elapsed() >= STATS_LOG_INTERVAL {
+            last_stats_log = Instant::now();
+            info!("Poll stats: {:?}", stats.summary());
+        }
+
+        // The SDK info only changes when the SDK is (un)initialised, which always changes the SDK state too
+        let published = snapshot.load();
+        publish |= published.state != service.get_state()
+            || published.sdk_state != *service.get_sdk_state()
+            || published.input_source != service.get_input_source()
+            || last_snapshot_publish.elapsed() >= SNAPSHOT_TELEMETRY_INTERVAL;
+        drop(published);
+        if publish {
+            last_snapshot_publish = Instant::now();
+            snapshot.store(Arc::new(ServiceSnapshot {
+                state: service.get_state(),
+                sdk_state: service.get_sdk_state().clone(),
+                sdk_info: service.get_sdk_info().clone(),
+                input_source: service.get_input_source(),
+                telemetry: Some(service.get_telemetry()),
+                poll_stats: poll_stats.clone(),
+            }));
+        }
+
+        stats.jitter.record(scheduler.wait());
+    }
+}
+
+/// Called by the stall monitor from its own thread. The controllers belong to the poll thread, so all this can do is
+/// publish the service as faulted until the poll thread comes back and centres them
+fn on_stall(snapshot: &ArcSwap<ServiceSnapshot>, stalled_for: Duration) {
+    let state = ServiceState::Faulted {
+        reason: format!("The poll thread hasn't responded in {:?}", stalled_for),
+    };
+    let mut stalled = ServiceSnapshot::clone(&snapshot.load());
+    stalled.state = state.clone();
+    snapshot.store(Arc::new(stalled));
+    events::emit(ServiceEvent::ServiceStateChanged(state));
+}
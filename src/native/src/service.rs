@@ -1,128 +1,465 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use log::*;
 #[cfg(windows)]
 #[cfg(feature = "rawinput")]
 use multiinput::*;
-use sdk::{DeviceInfo, WootingAnalogResult};
 use serde::{Deserialize, Serialize};
 #[cfg(windows)]
-use vigem::{
-    notification::*,
-    raw::{LPVOID, PVIGEM_CLIENT, PVIGEM_TARGET, UCHAR},
-    *,
-};
-use wooting_analog_wrapper as sdk;
+use vigem::*;
 
-use crate::config::ServiceConfiguration;
+use crate::analog::{AnalogDevice, AnalogInput, AnalogSDKInfo, AnalogSDKState, WootingAnalogSdk};
+#[cfg(feature = "rawinput")]
+use crate::bind_table::{BindTable, KeyBindState};
+#[cfg(feature = "rawinput")]
+use crate::bindings::BindTarget;
+use crate::capture::BindCapture;
+use crate::config::{
+    Bind, BindSource, ControllerProfile, DirectionBinds, InputPolicy, KeyMapping, OutputType,
+    PollConfiguration, ServiceConfiguration, TapBind,
+};
 use crate::controller::*;
+use crate::events::{self, ServiceEvent};
+use crate::feedback;
+use crate::keycode::KeyCode;
+use crate::mouse::MouseStick;
+use crate::tap::Taps;
+use crate::turn::TurnRate;
+
+/// Which inputs are currently driving the controllers
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub enum InputSource {
+    Analog,
+    Digital,
+}
+
+/// The fastest rate telemetry will be pushed to JS at, regardless of the requested interval
+const MIN_TELEMETRY_INTERVAL: Duration = Duration::from_millis(8);
 
-#[derive(Clone, Serialize, Deserialize, PartialEq)]
-#[serde(tag = "type", content = "value")]
-pub enum AnalogSDKState {
-    Uninitialized,
-    Error(sdk::WootingAnalogResult),
-    DevicesConnected(Vec<String>),
-    NoDevices,
+#[derive(Serialize, Clone, Debug)]
+pub struct Telemetry {
+    /// The analog value of each bound key, only populated while analog input is in use
+    pub keys: HashMap<u16, f32>,
+    /// One for each controller, in the same order as the config
+    pub controllers: Vec<ControllerTelemetry>,
 }
 
-#[cfg(windows)]
-unsafe extern "C" fn _handle(
-    client: PVIGEM_CLIENT,
-    target: PVIGEM_TARGET,
-    large_motor: UCHAR,
-    small_motor: UCHAR,
-    led_number: UCHAR,
-    user_data: LPVOID,
-) {
-    // make a safe absraction over all arguments
-    let notification: X360Notification<i32> = X360Notification::new(
-        client,
-        target,
-        large_motor,
-        small_motor,
-        led_number,
-        user_data,
-    );
-
-    // get target and client which we got in our callback
-    let target = notification.get_target();
-
-    println!(
-        "Large motor is: {}, small is : {}",
-        notification.large_motor, notification.small_motor
-    );
-    println!("Led number: {}", notification.led_number);
-    dbg!(target.state());
-
-    // Get userdata(I dont know what it is)
-    dbg!(notification.userdata());
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(tag = "type")]
+pub enum ServiceState {
+    Stopped,
+    Running,
+    /// Polling kept failing, so the controller has been set to neutral while recovery is attempted
+    Faulted {
+        reason: String,
+    },
 }
 
+/// The key a raw input keyboard or mouse button event is for, and whether it went down or up
 #[cfg(feature = "rawinput")]
-pub struct KeyBindState {
-    up: bool,
-    up_two: bool,
-    down: bool,
-    down_two: bool,
-    left: bool,
-    left_two: bool,
-    right: bool,
-    right_two: bool,
+fn key_event(event: &RawEvent) -> Option<(KeyCode, State)> {
+    match event {
+        RawEvent::KeyboardEvent(_, key, state) => KeyId::to_u8(key)
+            .and_then(KeyCode::from_vk)
+            .map(|key| (key, *state)),
+        RawEvent::MouseButtonEvent(_, button, state) => {
+            // Mouse buttons are keyed by their virtual-key codes, like the keys
+            let vk = match button {
+                MouseButton::Left => 0x01,
+                MouseButton::Right => 0x02,
+                MouseButton::Middle => 0x04,
+                MouseButton::Button4 => 0x05,
+                MouseButton::Button5 => 0x06,
+            };
+            KeyCode::from_vk(vk).map(|key| (key, *state))
+        }
+        _ => None,
+    }
 }
 
-#[cfg(feature = "rawinput")]
-impl KeyBindState {
-    pub fn new() -> Self {
-        KeyBindState {
-            up: false,
-            up_two: false,
-            down: false,
-            down_two: false,
-            left: false,
-            left_two: false,
-            right: false,
-            right_two: false,
+/// Reads a single bind, from the analog data or the key state depending on its source
+fn get_bind_value(analog_data: &HashMap<u16, f32>, bind: &Bind) -> f32 {
+    match bind.source {
+        BindSource::Auto => *analog_data.get(&bind.key.0).unwrap_or(&0.0),
+        BindSource::Digital => {
+            if is_key_pressed(bind.key) {
+                1.0
+            } else {
+                0.0
+            }
         }
     }
 }
 
-pub struct Service {
+/// A virtual controller along with the input state driving it
+struct Pad {
+    target: Target,
+    output: OutputType,
+    state: ControllerState,
     #[cfg(feature = "rawinput")]
     key_bind_state: KeyBindState,
+    mouse: MouseStick,
+    turn: TurnRate,
+    taps: Taps,
+}
+
+impl Pad {
+    /// `index` is the controller's position in the config, which its feedback is reported against
+    fn new(vigem: &mut Vigem, output: OutputType, index: usize) -> Result<Self> {
+        let mut target = match output {
+            OutputType::DualShock4 => {
+                // Make a new target which represent DualShock4 controller
+                let t = Target::new(TargetType::DualShock4);
+                // DS4 vid/pid
+                t.set_vid(0x054C);
+                t.set_pid(0x05C4);
+                t
+            }
+            OutputType::Xbox360 => {
+                // Make a new target which represent XBOX360 controller
+                let t = Target::new(TargetType::Xbox360);
+                t.set_vid(0x31e3);
+                t.set_pid(0xFFFF);
+                t
+            }
+        };
+
+        // Get controller state - as target isnt connected state is "Initialized"
+        debug!("Controller state {:?}", target.state());
+
+        // Add target to VigemBUS
+        vigem
+            .target_add(&mut target)
+            .context("Failed to add target to ViGEmBus")?;
+
+        info!(
+            "Added {:?} Controller target to ViGEm with state {:?}",
+            output,
+            target.state()
+        );
+
+        feedback::reset(index);
+        let registered = match output {
+            OutputType::DualShock4 => vigem.ds4_register_notification::<usize>(
+                &target,
+                Some(feedback::handle_ds4_notification),
+                index,
+            ),
+            OutputType::Xbox360 => vigem.x360_register_notification::<usize>(
+                &target,
+                Some(feedback::handle_x360_notification),
+                index,
+            ),
+        };
+        // The controller still works without feedback, so this isn't worth failing over
+        if let Err(e) = registered {
+            warn!("Failed to register for {:?} notifications {:?}", output, e);
+        }
+
+        Ok(Pad {
+            target,
+            output,
+            state: ControllerState::new(),
+            #[cfg(feature = "rawinput")]
+            key_bind_state: KeyBindState::new(),
+            mouse: MouseStick::new(),
+            turn: TurnRate::new(),
+            taps: Taps::new(),
+        })
+    }
+
+    fn update_controller(&mut self, profile: &ControllerProfile) -> Result<()> {
+        let angles = Some(&profile.left_joystick_strafing_angles);
+        match self.output {
+            OutputType::DualShock4 => self.target.update(&self.state.get_ds4_report(angles))?,
+            OutputType::Xbox360 => self.target.update(&self.state.get_xusb_report(angles))?,
+        }
+        Ok(())
+    }
+
+    fn output_controller_detection(&mut self) -> Result<()> {
+        let tiny_axis_y: f32 = 0.6;
+
+        let _ = self
+            .state
+            .left_joystick
+            .set_direction_state_analog(JoystickDirection::Left, 0.0)
+            | self
+                .state
+                .left_joystick
+                .set_direction_state_analog(JoystickDirection::Right, 0.0)
+            | self
+                .state
+                .left_joystick
+                .set_direction_state_analog(JoystickDirection::Up, tiny_axis_y)
+            | self
+                .state
+                .left_joystick
+                .set_direction_state_analog(JoystickDirection::Down, 0.0);
+
+        match self.output {
+            OutputType::DualShock4 => self
+                .target
+                .update(&self.state.get_ds4_report_from_axis(0.0, tiny_axis_y))?,
+            OutputType::Xbox360 => self
+                .target
+                .update(&self.state.get_xusb_report_from_axis(0.0, tiny_axis_y))?,
+        }
+        Ok(())
+    }
+
+    /// Updates the key's state and the target it's bound to, which comes from the `BindTable`
+    #[cfg(feature = "rawinput")]
+    fn process_key_event(
+        &mut self,
+        profile: &ControllerProfile,
+        key: KeyCode,
+        state: State,
+        target: BindTarget,
+    ) -> bool {
+        self.key_bind_state.process_key_event(
+            &mut self.state,
+            &self.taps,
+            &profile.key_mapping,
+            key,
+            state == State::Pressed,
+            target,
+        )
+    }
+
+    /// Sets the target from whether the keys bound to it are held
+    #[cfg(feature = "rawinput")]
+    fn update_target(&mut self, profile: &ControllerProfile, target: BindTarget) -> bool {
+        self.key_bind_state
+            .update_target(&mut self.state, &self.taps, &profile.key_mapping, target)
+    }
+
+    /// Updates the targets bound to keys with a tap, for when whether those keys count as held has changed
+    #[cfg(feature = "rawinput")]
+    fn update_tap_targets(&mut self, profile: &ControllerProfile) -> bool {
+        let taps = &profile.key_mapping.taps;
+        let mut changed = false;
+        for target in BindTarget::all() {
+            if taps
+                .iter()
+                .any(|tap| target.binds(&profile.key_mapping).contains(tap.key))
+            {
+                changed |= self.update_target(profile, target);
+            }
+        }
+        changed
+    }
+
+    /// Whether the key is held down according to raw input, or the key state without it
+    fn is_key_down(&self, key: KeyCode) -> bool {
+        #[cfg(feature = "rawinput")]
+        return self.key_bind_state.is_pressed(key);
+        #[cfg(not(feature = "rawinput"))]
+        return is_key_pressed(key);
+    }
+
+    /// Whether the key counts as held by its binds, which for a key that can be tapped is only once it's a hold
+    fn is_key_held(&self, taps: &[TapBind], key: KeyCode) -> bool {
+        let value = if self.is_key_down(key) { 1.0 } else { 0.0 };
+        self.taps.gate(taps, key, value) > 0.0
+    }
+
+    /// How far a bind is pressed, from the analog values while analog input is in use or the key state otherwise
+    fn read_bind(
+        &self,
+        taps: &[TapBind],
+        bind: &Bind,
+        input_source: InputSource,
+        analog_data: &HashMap<u16, f32>,
+    ) -> f32 {
+        match input_source {
+            InputSource::Analog => {
+                self.taps
+                    .gate(taps, bind.key, get_bind_value(analog_data, bind))
+            }
+            InputSource::Digital => {
+                if self.is_key_held(taps, bind.key) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    /// Works out which presses of the keys with a tap are taps and which are holds, and presses the buttons of the
+    /// taps. This has to run before the binds are read, as it decides whether those keys count as held
+    fn update_taps(
+        &mut self,
+        profile: &ControllerProfile,
+        input_source: InputSource,
+        analog_data: &HashMap<u16, f32>,
+        now: Instant,
+    ) -> bool {
+        let taps = &profile.key_mapping.taps;
+        let values: Vec<f32> = taps
+            .iter()
+            .map(|tap| match input_source {
+                InputSource::Analog => *analog_data.get(&tap.key.0).unwrap_or(&0.0),
+                InputSource::Digital => {
+                    if self.is_key_down(tap.key) {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                }
+            })
+            .collect();
+        let changed = self.taps.update(taps.iter().zip(values), now);
+        let tapped = self.taps.tapped_buttons(taps).collect();
+        changed | self.state.buttons.set_tapped(tapped)
+    }
+
+    /// The right stick is set by position, as its keys, their turn rate and the mouse can all be moving it at once
+    fn update_right_joystick(
+        &mut self,
+        profile: &ControllerProfile,
+        input_source: InputSource,
+        analog_data: &HashMap<u16, f32>,
+        mouse_motion: (i32, i32),
+        now: Instant,
+    ) -> bool {
+        let mapping = &profile.key_mapping.right_joystick;
+        let uses_keys = mapping.keys().next().is_some();
+        if !uses_keys && !profile.mouse_stick.enabled {
+            return self.state.right_joystick.clear_position();
+        }
+
+        let (mut x, mut y) = (0.0, 0.0);
+        if uses_keys {
+            let taps = &profile.key_mapping.taps;
+            let travel = |binds: &DirectionBinds| {
+                binds.combine(|bind| self.read_bind(taps, bind, input_source, analog_data))
+            };
+            let keys = (
+                travel(&mapping.right) - travel(&mapping.left),
+                travel(&mapping.up) - travel(&mapping.down),
+            );
+            let (keys_x, keys_y) = if profile.right_joystick_turn.enabled {
+                self.turn.update(keys, &profile.right_joystick_turn, now)
+            } else {
+                keys
+            };
+            x += keys_x;
+            y += keys_y;
+        }
+        if profile.mouse_stick.enabled {
+            let (mouse_x, mouse_y) = self.mouse.update(mouse_motion, &profile.mouse_stick, now);
+            x += mouse_x;
+            y += mouse_y;
+        }
+
+        let length: f32 = (x * x + y * y).sqrt();
+        if length > 1.0 {
+            x /= length;
+            y /= length;
+        }
+        self.state.right_joystick.set_position(x, y)
+    }
+
+    fn update_direction_analog(
+        &mut self,
+        direction: JoystickDirection,
+        analog_data: &HashMap<u16, f32>,
+        binds: &DirectionBinds,
+        taps: &[TapBind],
+    ) -> bool {
+        let analog =
+            binds.combine(|bind| self.read_bind(taps, bind, InputSource::Analog, analog_data));
+        self.state
+            .left_joystick
+            .set_direction_state_analog(direction, analog)
+    }
+
+    fn update_analog_inputs(&mut self, mapping: &KeyMapping, analog: &HashMap<u16, f32>) -> bool {
+        let joystick = &mapping.left_joystick;
+        let taps = &mapping.taps;
+        let mut changed =
+            self.update_direction_analog(JoystickDirection::Left, analog, &joystick.left, taps)
+                | self.update_direction_analog(JoystickDirection::Up, analog, &joystick.up, taps)
+                | self.update_direction_analog(
+                    JoystickDirection::Down,
+                    analog,
+                    &joystick.down,
+                    taps,
+                )
+                | self.update_direction_analog(
+                    JoystickDirection::Right,
+                    analog,
+                    &joystick.right,
+                    taps,
+                );
+        let tap_state = &self.taps;
+        changed |= self.state.buttons.update_analog(mapping, |bind| {
+            tap_state.gate(taps, bind.key, get_bind_value(analog, bind))
+        });
+        changed
+    }
+}
+
+pub struct Service {
     vigem: Vigem,
-    controller: Option<Target>,
+    pads: Vec<Pad>,
     #[cfg(feature = "rawinput")]
     input_manager: RawInputManager,
-    controller_state: ControllerState,
+    /// Which targets each key is bound to, for dispatching raw input events
+    #[cfg(feature = "rawinput")]
+    bind_table: BindTable,
+    /// Mouse counts moved since the last poll
+    #[cfg(feature = "rawinput")]
+    mouse_motion: (i32, i32),
+    #[cfg(feature = "rawinput")]
+    mice_registered: bool,
     initd: bool,
     config: ServiceConfiguration,
-    analog_initialised: bool,
+    analog: AnalogInput,
+    /// The SDK state JS was last told about
     sdk_state: AnalogSDKState,
+    input_source: InputSource,
     is_detecting: bool,
+    bind_capture: Option<BindCapture>,
+    /// The keys held down according to raw input while capturing a bind
+    #[cfg(feature = "rawinput")]
+    capture_pressed: HashSet<KeyCode>,
+    state: ServiceState,
+    key_values: HashMap<u16, f32>,
+    telemetry_interval: Option<Duration>,
+    last_telemetry: Instant,
 }
 
-// Service should be wrapped in Mutex if used across threads so minimise unsafety
-unsafe impl Send for Service {}
-
-unsafe impl Sync for Service {}
-
 impl Service {
     pub fn new() -> Self {
         Service {
-            #[cfg(feature = "rawinput")]
-            key_bind_state: KeyBindState::new(),
             vigem: Vigem::new(),
-            controller: None,
-            controller_state: ControllerState::new(),
+            pads: vec![],
             #[cfg(feature = "rawinput")]
             input_manager: RawInputManager::new().unwrap(),
+            #[cfg(feature = "rawinput")]
+            bind_table: BindTable::default(),
+            #[cfg(feature = "rawinput")]
+            mouse_motion: (0, 0),
+            #[cfg(feature = "rawinput")]
+            mice_registered: false,
             initd: false,
             config: ServiceConfiguration::default(),
-            analog_initialised: false,
+            analog: AnalogInput::new(Box::new(WootingAnalogSdk)),
             sdk_state: AnalogSDKState::Uninitialized,
+            input_source: InputSource::Digital,
             is_detecting: false,
+            bind_capture: None,
+            #[cfg(feature = "rawinput")]
+            capture_pressed: HashSet::new(),
+            state: ServiceState::Stopped,
+            key_values: HashMap::new(),
+            telemetry_interval: None,
+            last_telemetry: Instant::now(),
         }
     }
 
@@ -131,450 +468,556 @@ impl Service {
             return Ok(());
         }
         self.config = config;
+        #[cfg(feature = "rawinput")]
+        {
+            self.bind_table = BindTable::new(&self.config.controllers);
+        }
 
         info!("Service init");
 
-        if self.config.use_analog_input {
-            self.init_analog()?;
+        if self.config.uses_analog() {
+            self.analog.init()?;
+            self.publish_sdk_state();
         }
 
         #[cfg(feature = "rawinput")]
         self.input_manager.register_devices(DeviceType::Keyboards);
+        self.register_mouse();
 
         // connect our client to a VigemBus
         self.vigem.connect().context(
             "Failed to connect to VigemBus. Please ensure you have ViGEmBus properly installed",
         )?;
 
-        #[cfg(feature = "ds4")]
-        let mut target = {
-            // Make a new target which represent DualShock4 controller
-            let t = Target::new(TargetType::DualShock4);
-            // DS4 vid/pid
-            t.set_vid(0x054C);
-            t.set_pid(0x05C4);
-            t
-        };
-
-        #[cfg(not(feature = "ds4"))]
-        let mut target = {
-            // Make a new target which represent XBOX360 controller
-            let t = Target::new(TargetType::Xbox360);
-            t.set_vid(0x31e3);
-            t.set_pid(0xFFFF);
-            t
-        };
-
-        // Get controller state - as target isnt connected state is "Initialized"
-        debug!("Controller state {:?}", target.state());
-
-        // Add target to VigemBUS
-        self.vigem
-            .target_add(&mut target)
-            .context("Failed to add target to ViGEmBus")?;
-
-        // Now it's connected!
-        debug!("Controller state {:?}", target.state());
-
-        info!(
-            "Added Controller target to ViGEm with state {:?}",
-            target.state()
-        );
-
-        self.controller = Some(target);
+        self.sync_pads()?;
 
         self.update_controller()?;
 
         self.initd = true;
+        self.set_state(ServiceState::Running);
 
         Ok(())
     }
 
-    fn init_analog(&mut self) -> Result<()> {
-        if self.analog_initialised {
-            return Ok(());
+    pub fn set_state(&mut self, state: ServiceState) {
+        if self.state != state {
+            info!("Service state changed to {:?}", state);
+            self.state = state;
+            events::emit(ServiceEvent::ServiceStateChanged(self.state.clone()));
         }
+    }
 
-        // TODO: Remove this, is only for testing without uninit
-        // if sdk::is_initialised() {
-        //     return Ok(());
-        // }
-
-        let init_result = sdk::initialise();
-
-        match init_result.0 {
-            Ok(device_num) => {
-                info!(
-                    "Analog SDK Successfully initialised with {} devices",
-                    device_num
-                );
-
-                let devices: Vec<DeviceInfo> = sdk::get_connected_devices_info(10).0.unwrap();
-                assert_eq!(device_num, devices.len() as u32);
-                for (i, device) in devices.iter().enumerate() {
-                    println!("Device {} is {:?}", i, device);
-                }
-                if device_num > 0 {
-                    self.sdk_state = AnalogSDKState::DevicesConnected(
-                        devices
-                            .iter()
-                            .map(|device| device.device_name.clone())
-                            .collect(),
-                    );
-                } else {
-                    self.sdk_state = AnalogSDKState::NoDevices;
-                }
-
-                sdk::set_keycode_mode(sdk::KeycodeType::VirtualKeyTranslate)
-                    .0
-                    .context("Failed to set keyboard mode")?;
-
-                self.analog_initialised = true;
-            }
-            Err(e) => {
-                error!("Wooting Analog SDK Failed to initialise: {}", e);
+    pub fn get_state(&self) -> ServiceState {
+        self.state.clone()
+    }
 
-                self.sdk_state = AnalogSDKState::Error(e)
+    /// Makes sure there's a virtual controller of the right type for each profile in the config. Only the ones that
+    /// changed are replaced, so games don't see every controller reconnect when the config is updated
+    fn sync_pads(&mut self) -> Result<()> {
+        self.pads.truncate(self.config.controllers.len());
+        feedback::truncate(self.config.controllers.len());
+        for (i, profile) in self.config.controllers.iter().enumerate() {
+            if i >= self.pads.len() {
+                self.pads
+                    .push(Pad::new(&mut self.vigem, profile.output, i)?);
+            } else if self.pads[i].output != profile.output {
+                self.pads[i] = Pad::new(&mut self.vigem, profile.output, i)?;
             }
-        };
-
+        }
         Ok(())
     }
 
-    fn uninit_analog(&mut self) -> Result<()> {
-        if !self.analog_initialised {
-            return Ok(());
+    /// Centres the sticks and sends that to the controllers, so nothing is left held down while we can't poll
+    pub fn output_neutral(&mut self) -> Result<()> {
+        for pad in self.pads.iter_mut() {
+            pad.state = ControllerState::new();
         }
-
-        self.sdk_state = AnalogSDKState::Uninitialized;
-        sdk::uninitialise()
-            .0
-            .context("Failed to uninitialise analog sdk")?;
-        self.analog_initialised = false;
-        Ok(())
+        self.key_values.clear();
+        self.update_controller()
     }
 
-    fn output_controller_detection(&mut self) -> Result<()> {
-        if let Some(controller) = self.controller.as_mut() {
-            let tiny_axis_y: f32 = 0.6;
+    /// Restarts the input side of the service, which is where persistent poll errors come from (e.g. the analog
+    /// SDK going away mid-game)
+    pub fn recover(&mut self) -> Result<()> {
+        info!("Attempting to recover the service");
+        if self.config.uses_analog() {
+            if let Err(e) = self.analog.uninit() {
+                warn!("Failed to uninitialise analog during recovery {:#}", e);
+            }
+            let result = self.analog.init();
+            self.publish_sdk_state();
+            result?;
+        }
 
-            let _ = self
-                .controller_state
-                .left_joystick
-                .set_direction_state_analog(JoystickDirection::Left, 0.0)
-                | self
-                    .controller_state
-                    .left_joystick
-                    .set_direction_state_analog(JoystickDirection::Right, 0.0)
-                | self
-                    .controller_state
-                    .left_joystick
-                    .set_direction_state_analog(JoystickDirection::Up, tiny_axis_y)
-                | self
-                    .controller_state
-                    .left_joystick
-                    .set_direction_state_analog(JoystickDirection::Down, 0.0);
+        self.update_controller()
+    }
 
-            #[cfg(feature = "ds4")]
-            let report = self
-                .controller_state
-                .get_ds4_report_from_axis(0.0, tiny_axis_y);
+    /// Lets JS know about any change to the SDK state since it was last told, along with which devices came and went
+    fn publish_sdk_state(&mut self) {
+        let state = self.analog.state();
+        if self.sdk_state == *state {
+            return;
+        }
 
-            #[cfg(not(feature = "ds4"))]
-            let report = self
-                .controller_state
-                .get_xusb_report_from_axis(0.0, tiny_axis_y);
+        let old_devices = self.sdk_state.devices();
+        let new_devices = state.devices();
+        let contains = |devices: &[AnalogDevice], device: &AnalogDevice| {
+            devices.iter().any(|d| d.device_id == device.device_id)
+        };
+        for device in old_devices.iter().filter(|d| !contains(new_devices, d)) {
+            events::emit(ServiceEvent::DeviceDisconnected(device.clone()));
+        }
+        for device in new_devices.iter().filter(|d| !contains(old_devices, d)) {
+            events::emit(ServiceEvent::DeviceConnected(device.clone()));
+        }
 
-            controller.update(&report)?;
+        debug!("SDK state changed to {:?}", state);
+        self.sdk_state = state.clone();
+        events::emit(ServiceEvent::SDKStateChanged(self.sdk_state.clone()));
+    }
+
+    fn output_controller_detection(&mut self) -> Result<()> {
+        for pad in self.pads.iter_mut() {
+            pad.output_controller_detection()?;
         }
         Ok(())
     }
 
     fn update_controller(&mut self) -> Result<()> {
-        if let Some(controller) = self.controller.as_mut() {
-            #[cfg(feature = "ds4")]
-            let report = self
-                .controller_state
-                .get_ds4_report(Some(&self.config.left_joystick_strafing_angles));
-
-            #[cfg(not(feature = "ds4"))]
-            let report = self
-                .controller_state
-                .get_xusb_report(Some(&self.config.left_joystick_strafing_angles));
-
-            controller.update(&report)?;
+        for (pad, profile) in self.pads.iter_mut().zip(self.config.controllers.iter()) {
+            pad.update_controller(profile)?;
         }
         Ok(())
     }
 
+    /// Handles everything raw input has queued since the last poll. Key and mouse button events only drive the
+    /// controllers while digital input is in use, mouse motion is collected for the mouse stick
     #[cfg(feature = "rawinput")]
-    fn process_rawinput_event(&mut self) -> bool {
-        if let Some(event) = self.input_manager.get_event() {
-            // debug!("{:?}", event);
+    fn process_rawinput_events(&mut self) -> bool {
+        let mut changed = false;
+        while let Some(event) = self.input_manager.get_event() {
             match event {
-                RawEvent::KeyboardEvent(_, key, state) => match KeyId::to_u8(&key).unwrap() {
-                    x if x == self.config.key_mapping.left_joystick.up => {
-                        self.key_bind_state.up = state == State::Pressed;
-                        self.controller_state
-                            .left_joystick
-                            .set_direction_state_digital(
-                                JoystickDirection::Up,
-                                self.key_bind_state.up || self.key_bind_state.up_two,
-                            )
-                    }
-                    x if Some(x) == self.config.key_mapping.left_joystick.up_two => {
-                        self.key_bind_state.up_two = state == State::Pressed;
-                        self.controller_state
-                            .left_joystick
-                            .set_direction_state_digital(
-                                JoystickDirection::Up,
-                                self.key_bind_state.up || self.key_bind_state.up_two,
-                            )
-                    }
-                    x if x == self.config.key_mapping.left_joystick.down => {
-                        self.key_bind_state.down = state == State::Pressed;
-                        self.controller_state
-                            .left_joystick
-                            .set_direction_state_digital(
-                                JoystickDirection::Down,
-                                self.key_bind_state.down || self.key_bind_state.down_two,
-                            )
-                    }
-                    x if Some(x) == self.config.key_mapping.left_joystick.down_two => {
-                        self.key_bind_state.down_two = state == State::Pressed;
-                        self.controller_state
-                            .left_joystick
-                            .set_direction_state_digital(
-                                JoystickDirection::Down,
-                                self.key_bind_state.down || self.key_bind_state.down_two,
-                            )
-                    }
-                    x if x == self.config.key_mapping.left_joystick.right => {
-                        self.key_bind_state.right = state == State::Pressed;
-                        self.controller_state
-                            .left_joystick
-                            .set_direction_state_digital(
-                                JoystickDirection::Right,
-                                self.key_bind_state.right || self.key_bind_state.right_two,
-                            )
-                    }
-                    x if Some(x) == self.config.key_mapping.left_joystick.right_two => {
-                        self.key_bind_state.right_two = state == State::Pressed;
-                        self.controller_state
-                            .left_joystick
-                            .set_direction_state_digital(
-                                JoystickDirection::Right,
-                                self.key_bind_state.right || self.key_bind_state.right_two,
-                            )
-                    }
-                    x if x == self.config.key_mapping.left_joystick.left => {
-                        self.key_bind_state.left = state == State::Pressed;
-                        self.controller_state
-                            .left_joystick
-                            .set_direction_state_digital(
-                                JoystickDirection::Left,
-                                self.key_bind_state.left || self.key_bind_state.left_two,
-                            )
-                    }
-                    x if Some(x) == self.config.key_mapping.left_joystick.left_two => {
-                        self.key_bind_state.left_two = state == State::Pressed;
-                        self.controller_state
-                            .left_joystick
-                            .set_direction_state_digital(
-                                JoystickDirection::Left,
-                                self.key_bind_state.left || self.key_bind_state.left_two,
-                            )
+                RawEvent::MouseMoveEvent(_, x, y) => {
+                    self.mouse_motion.0 += x;
+                    self.mouse_motion.1 += y;
+                }
+                event if self.input_source == InputSource::Digital => {
+                    if let Some((key, state)) = key_event(&event) {
+                        changed |= self.process_key_event(key, state);
                     }
-                    _ => false,
-                },
-                _ => false,
+                }
+                _ => {}
             }
-        } else {
-            false
         }
+        changed
     }
 
-    fn get_bind_analog(
-        &self,
-        analog_data: &HashMap<u16, f32>,
-        bind_one: Option<u8>,
-        bind_two: Option<u8>,
-    ) -> f32 {
-        let analog_one = {
-            if let Some(bind) = bind_one {
-                *analog_data.get(&(bind as u16)).unwrap_or(&0.0)
-            } else {
-                0.0
+    #[cfg(feature = "rawinput")]
+    fn process_key_event(&mut self, key: KeyCode, state: State) -> bool {
+        // Taps are worked out every poll by `update_taps`, which only needs to know whether the key is down
+        for (pad, profile) in self.pads.iter_mut().zip(self.config.controllers.iter()) {
+            if profile.key_mapping.taps.iter().any(|tap| tap.key == key) {
+                pad.key_bind_state.set(key, state == State::Pressed);
             }
-        };
+        }
 
-        let analog_two = {
-            if let Some(bind) = bind_two {
-                *analog_data.get(&(bind as u16)).unwrap_or(&0.0)
-            } else {
-                0.0
+        let mut changed = false;
+        for bound in self.bind_table.get(key) {
+            if let (Some(pad), Some(profile)) = (
+                self.pads.get_mut(bound.pad),
+                self.config.controllers.get(bound.pad),
+            ) {
+                changed |= pad.process_key_event(profile, key, state, bound.target);
             }
-        };
+        }
+        changed
+    }
+
+    /// Starts listening to the mouse if any controller follows it or has its buttons bound
+    fn register_mouse(&mut self) {
+        if !self.config.uses_mouse() {
+            return;
+        }
+
+        #[cfg(feature = "rawinput")]
+        self.register_mice();
+
+        // Without raw input mouse buttons are read from the key state, only the mouse stick is missing
+        #[cfg(not(feature = "rawinput"))]
+        if self
+            .config
+            .controllers
+            .iter()
+            .any(|controller| controller.mouse_stick.enabled)
+        {
+            warn!("The mouse stick needs raw input, which this build doesn't support");
+        }
+    }
+
+    /// Mice are only registered once, as raw input has no way of unregistering them
+    #[cfg(feature = "rawinput")]
+    fn register_mice(&mut self) {
+        if !self.mice_registered {
+            self.input_manager.register_devices(DeviceType::Mice);
+            self.mice_registered = true;
+        }
+    }
 
-        f32::max(analog_one, analog_two)
+    /// Moves the right stick of every controller from its keys and the mouse. This has to run after the other
+    /// inputs have been read, as it uses the analog values they stored. Returns whether any of them changed
+    fn update_right_joysticks(&mut self) -> bool {
+        #[cfg(feature = "rawinput")]
+        let motion = std::mem::take(&mut self.mouse_motion);
+        #[cfg(not(feature = "rawinput"))]
+        let motion = (0, 0);
+
+        let now = Instant::now();
+        let mut changed = false;
+        for (pad, profile) in self.pads.iter_mut().zip(self.config.controllers.iter()) {
+            changed |= pad.update_right_joystick(
+                profile,
+                self.input_source,
+                &self.key_values,
+                motion,
+                now,
+            );
+        }
+        changed
     }
 
+    /// Updates the controllers for when there's no analog data to read, so only digital binds can be pressed
     fn update_direction_analog_empty(&mut self) -> bool {
-        self.controller_state
-            .left_joystick
-            .set_direction_state_analog(JoystickDirection::Left, 0.0)
-            | self
-                .controller_state
-                .left_joystick
-                .set_direction_state_analog(JoystickDirection::Right, 0.0)
-            | self
-                .controller_state
-                .left_joystick
-                .set_direction_state_analog(JoystickDirection::Up, 0.0)
-            | self
-                .controller_state
-                .left_joystick
-                .set_direction_state_analog(JoystickDirection::Down, 0.0)
+        let now = Instant::now();
+        let empty = HashMap::new();
+        let mut changed = false;
+        for (pad, profile) in self.pads.iter_mut().zip(self.config.controllers.iter()) {
+            // Still moves the taps on, so the button press of a tap doesn't get stuck
+            changed |= pad.update_taps(profile, InputSource::Analog, &empty, now)
+                | pad.update_analog_inputs(&profile.key_mapping, &empty);
+        }
+        changed
     }
 
-    fn update_direction_analog(
-        &mut self,
-        direction: JoystickDirection,
-        analog_data: &HashMap<u16, f32>,
-        bind_one: Option<u8>,
-        bind_two: Option<u8>,
-    ) -> bool {
-        let analog = self.get_bind_analog(analog_data, bind_one, bind_two);
-        self.controller_state
-            .left_joystick
-            .set_direction_state_analog(direction, analog)
+    /// Whether analog input can be read, meaning the SDK is loaded and the keyboard we read from is connected
+    fn analog_available(&self) -> bool {
+        self.analog.is_available(self.config.analog_device_id)
     }
 
-    fn update_analog_inputs(&mut self) -> bool {
-        // TODO: Handle case where there are no devices connected
-        // TODO: Potentially update connected devices state in here
-        match sdk::read_full_buffer(20).0 {
-            Ok(analog) => {
-                if self.sdk_state == AnalogSDKState::NoDevices {
-                    let devices: Vec<DeviceInfo> = sdk::get_connected_devices_info(10).0.unwrap();
-
-                    if devices.len() > 0 {
-                        self.sdk_state = AnalogSDKState::DevicesConnected(
-                            devices
-                                .iter()
-                                .map(|device| device.device_name.clone())
-                                .collect(),
-                        );
-                    }
+    /// Keeps trying to load the SDK or find a device while analog input is wanted but isn't available
+    fn retry_analog(&mut self) {
+        if !self.config.uses_analog() {
+            return;
+        }
+        self.analog
+            .retry(self.config.analog_device_id, Instant::now());
+        self.publish_sdk_state();
+    }
+
+    /// Works out which inputs should drive the controllers from the policy and whether analog is available. Returns
+    /// true if it changed, in which case the controllers have been reset so nothing from the old source is left held
+    fn update_input_source(&mut self) -> bool {
+        let source = match self.config.input_policy {
+            InputPolicy::Analog => InputSource::Analog,
+            InputPolicy::Digital => InputSource::Digital,
+            InputPolicy::AnalogWithFallback => {
+                if self.analog_available() {
+                    InputSource::Analog
+                } else {
+                    InputSource::Digital
                 }
+            }
+        };
 
-                self.update_direction_analog(
-                    JoystickDirection::Left,
-                    &analog,
-                    self.config.key_mapping.left_joystick.left,
-                    self.config.key_mapping.left_joystick.left_two,
-                ) | self.update_direction_analog(
-                    JoystickDirection::Up,
-                    &analog,
-                    self.config.key_mapping.left_joystick.up,
-                    self.config.key_mapping.left_joystick.up_two,
-                ) | self.update_direction_analog(
-                    JoystickDirection::Down,
-                    &analog,
-                    self.config.key_mapping.left_joystick.down,
-                    self.config.key_mapping.left_joystick.down_two,
-                ) | self.update_direction_analog(
-                    JoystickDirection::Right,
-                    &analog,
-                    self.config.key_mapping.left_joystick.right,
-                    self.config.key_mapping.left_joystick.right_two,
-                )
+        if source == self.input_source {
+            return false;
+        }
+
+        info!("Input source changed to {:?}", source);
+        self.input_source = source;
+        self.reset_inputs();
+        events::emit(ServiceEvent::InputSourceChanged(source));
+        true
+    }
+
+    /// Forgets every key that's held, leaving the controllers centred until the next input
+    fn reset_inputs(&mut self) {
+        #[cfg(feature = "rawinput")]
+        {
+            self.mouse_motion = (0, 0);
+        }
+        for pad in self.pads.iter_mut() {
+            pad.state = ControllerState::new();
+            pad.mouse = MouseStick::new();
+            pad.turn = TurnRate::new();
+            pad.taps = Taps::new();
+            #[cfg(feature = "rawinput")]
+            {
+                pad.key_bind_state = KeyBindState::new();
             }
-            Err(e) => {
-                if e == WootingAnalogResult::NoDevices {
-                    self.sdk_state = AnalogSDKState::NoDevices;
-                }
+        }
+        self.key_values.clear();
+    }
+
+    /// Works out the taps and reads the key state of every bind. With raw input, key events are handled as they arrive
+    /// by `process_rawinput_events` instead, so only the binds of keys with a tap can need updating here
+    fn update_digital_inputs(&mut self) -> bool {
+        let now = Instant::now();
+        let empty = HashMap::new();
+        let mut changed = false;
+        for (pad, profile) in self.pads.iter_mut().zip(self.config.controllers.iter()) {
+            let taps_changed = pad.update_taps(profile, InputSource::Digital, &empty, now);
+            changed |= taps_changed;
 
-                self.update_direction_analog_empty()
+            #[cfg(feature = "rawinput")]
+            if taps_changed {
+                changed |= pad.update_tap_targets(profile);
+            }
+
+            #[cfg(not(feature = "rawinput"))]
+            {
+                let mapping = &profile.key_mapping;
+                let taps = &pad.taps;
+                let is_held = |key: KeyCode| {
+                    let value = if is_key_pressed(key) { 1.0 } else { 0.0 };
+                    taps.gate(&mapping.taps, key, value) > 0.0
+                };
+                changed |= pad
+                    .state
+                    .left_joystick
+                    .update_key_states(&mapping.left_joystick, &is_held)
+                    | pad.state.update_button_key_states(mapping, &is_held);
+            }
+        }
+        changed
+    }
+
+    fn update_analog_inputs(&mut self) -> bool {
+        let analog = self.analog.read(self.config.analog_device_id);
+        self.publish_sdk_state();
+        let analog = match analog {
+            Some(analog) => analog,
+            None => {
+                // Only digital binds work until there's analog data again
+                self.key_values.clear();
+                return self.update_direction_analog_empty();
             }
+        };
+
+        self.key_values = self
+            .config
+            .controllers
+            .iter()
+            .flat_map(|profile| profile.key_mapping.keys())
+            .map(|key| (key.0, *analog.get(&key.0).unwrap_or(&0.0)))
+            .collect();
+
+        let now = Instant::now();
+        let mut changed = false;
+        for (pad, profile) in self.pads.iter_mut().zip(self.config.controllers.iter()) {
+            changed |= pad.update_taps(profile, InputSource::Analog, &analog, now)
+                | pad.update_analog_inputs(&profile.key_mapping, &analog);
         }
+        changed
     }
 
-    pub fn poll(&mut self) -> Result<()> {
+    /// Reads the inputs and updates the controller if they've changed. Returns whether a new report was sent
+    pub fn poll(&mut self) -> Result<bool> {
+        let mut report_sent = false;
         if self.initd {
+            self.retry_analog();
+
             if self.is_detecting {
                 self.output_controller_detection()?;
+            } else if self.bind_capture.is_some() {
+                self.update_bind_capture();
             } else {
-                let should_update = {
-                    // Analog being initialised implies that the setting for using analog is on
-                    if self.analog_initialised {
-                        self.update_analog_inputs()
-                    } else {
-                        #[cfg(feature = "rawinput")]
-                        {
-                            self.process_rawinput_event()
-                        }
-
-                        #[cfg(not(feature = "rawinput"))]
-                        {
-                            self.controller_state
-                                .left_joystick
-                                .update_key_states(&self.config.key_mapping.left_joystick)
-                        }
-                    }
+                let mut should_update = self.update_input_source();
+                // Raw input has to be drained every poll, as the mouse comes through it whatever the input source
+                #[cfg(feature = "rawinput")]
+                {
+                    should_update |= self.process_rawinput_events();
+                }
+                should_update |= match self.input_source {
+                    InputSource::Analog => self.update_analog_inputs(),
+                    InputSource::Digital => self.update_digital_inputs(),
                 };
+                should_update |= self.update_right_joysticks();
 
                 if should_update {
                     self.update_controller()?;
+                    report_sent = true;
                 }
             }
+
+            self.emit_telemetry();
         }
 
-        Ok(())
+        Ok(report_sent)
     }
 
-    pub fn get_xinput_slot(&mut self) -> Option<u32> {
-        #[cfg(not(feature = "ds4"))]
-        if let Some(controller) = self.controller.as_ref() {
-            let slot = self.vigem.xbox_get_user_index(&controller);
-            info!("We got slot {}", slot);
-            return Some(slot);
+    pub fn get_telemetry(&self) -> Telemetry {
+        Telemetry {
+            keys: self.key_values.clone(),
+            controllers: self
+                .pads
+                .iter()
+                .zip(self.config.controllers.iter())
+                .map(|(pad, profile)| {
+                    pad.state
+                        .get_telemetry(Some(&profile.left_joystick_strafing_angles), pad.output)
+                })
+                .collect(),
         }
-        None
     }
 
-    pub fn get_sdk_state(&self) -> AnalogSDKState {
-        self.sdk_state.clone()
+    /// Sets how often telemetry is pushed to JS as an event, or stops it being pushed with `None`
+    pub fn set_telemetry_interval(&mut self, interval: Option<Duration>) {
+        self.telemetry_interval = interval.map(|i| i.max(MIN_TELEMETRY_INTERVAL));
+    }
+
+    fn emit_telemetry(&mut self) {
+        if let Some(interval) = self.telemetry_interval {
+            if self.last_telemetry.elapsed() >= interval {
+                self.last_telemetry = Instant::now();
+                events::emit(ServiceEvent::Telemetry(self.get_telemetry()));
+            }
+        }
+    }
+
+    /// Returns the xinput slot of each controller, DualShock4 controllers don't have one
+    pub fn get_xinput_slots(&self) -> Vec<Option<u32>> {
+        let vigem = &self.vigem;
+        self.pads
+            .iter()
+            .map(|pad| match pad.output {
+                OutputType::Xbox360 => {
+                    let slot = vigem.xbox_get_user_index(&pad.target);
+                    info!("We got slot {}", slot);
+                    Some(slot)
+                }
+                OutputType::DualShock4 => None,
+            })
+            .collect()
+    }
+
+    pub fn get_poll_config(&self) -> &PollConfiguration {
+        &self.config.poll
+    }
+
+    pub fn get_sdk_state(&self) -> &AnalogSDKState {
+        &self.sdk_state
+    }
+
+    pub fn get_sdk_info(&self) -> &AnalogSDKInfo {
+        self.analog.info()
+    }
+
+    pub fn get_input_source(&self) -> InputSource {
+        self.input_source
     }
 
     pub fn stop(&mut self) {
         info!("Service stop");
 
-        if let Some(controller) = self.controller.take() {
-            drop(controller);
-        }
+        self.pads.clear();
+        feedback::truncate(0);
 
         self.vigem.disconnect();
 
-        if let Err(e) = self.uninit_analog() {
+        if let Err(e) = self.analog.uninit() {
             error!("Error uninitialising analog {}", e)
         }
+        self.publish_sdk_state();
         self.initd = false;
+        self.set_state(ServiceState::Stopped);
     }
 
     pub fn set_config(&mut self, config: ServiceConfiguration) -> Result<()> {
         self.config = config;
-        if self.config.use_analog_input {
-            self.init_analog()?;
-        } else {
-            self.uninit_analog()?;
+        #[cfg(feature = "rawinput")]
+        {
+            self.bind_table = BindTable::new(&self.config.controllers);
         }
+        self.analog.reset_retry();
+        let result = if self.config.uses_analog() {
+            self.analog.init()
+        } else {
+            self.analog.uninit()
+        };
+        self.publish_sdk_state();
+        result?;
 
+        if self.initd {
+            self.sync_pads()?;
+            self.register_mouse();
+        }
         self.update_controller()?;
+        events::emit(ServiceEvent::ConfigReloaded);
         Ok(())
     }
 
     pub fn set_gamepad_detection_state(&mut self, enabled: bool) {
         self.is_detecting = enabled;
     }
+
+    /// Starts listening for the next key press to bind, which is reported with a `BindCaptured` event. The
+    /// controllers are centred and ignore input until the capture is over, so pressing the key doesn't move anything
+    pub fn start_bind_capture(&mut self, threshold: f32) -> Result<()> {
+        info!("Starting bind capture with threshold {}", threshold);
+        self.bind_capture = Some(BindCapture::new(threshold));
+        #[cfg(feature = "rawinput")]
+        {
+            self.capture_pressed.clear();
+            // Mouse buttons can be bound too, which raw input only reports once mice are registered
+            self.register_mice();
+        }
+        self.reset_inputs();
+        self.update_controller()
+    }
+
+    pub fn end_bind_capture(&mut self) {
+        if self.bind_capture.take().is_some() {
+            info!("Bind capture ended");
+            // Anything pressed during the capture was never seen by the controllers
+            self.reset_inputs();
+        }
+    }
+
+    fn update_bind_capture(&mut self) {
+        let analog = self
+            .analog
+            .read(self.config.analog_device_id)
+            .unwrap_or_default();
+        self.publish_sdk_state();
+        let pressed = self.capture_pressed_keys();
+
+        let captured = match self.bind_capture.as_mut() {
+            Some(capture) => capture.update(&analog, &pressed),
+            None => return,
+        };
+        if let Some(bind) = captured {
+            info!("Captured bind {} from {:?}", bind.key, bind.source);
+            events::emit(ServiceEvent::BindCaptured(bind));
+            self.end_bind_capture();
+        }
+    }
+
+    #[cfg(feature = "rawinput")]
+    fn capture_pressed_keys(&mut self) -> HashSet<KeyCode> {
+        while let Some(event) = self.input_manager.get_event() {
+            if let Some((key, state)) = key_event(&event) {
+                if state == State::Pressed {
+                    self.capture_pressed.insert(key);
+                } else {
+                    self.capture_pressed.remove(&key);
+                }
+            }
+        }
+        self.capture_pressed.clone()
+    }
+
+    #[cfg(not(feature = "rawinput"))]
+    fn capture_pressed_keys(&mut self) -> HashSet<KeyCode> {
+        KeyCode::all().filter(|key| is_key_pressed(*key)).collect()
+    }
 }
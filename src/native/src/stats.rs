@@ -0,0 +1,202 @@
+// Only the service runner uses this, which is Windows only, but it's built everywhere for its tests
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Width of each histogram bucket in microseconds
+const BUCKET_WIDTH_US: u64 = 50;
+/// Number of buckets, anything past the last one is counted in it. This covers 0 -> 10ms
+const BUCKET_COUNT: usize = 200;
+
+/// Collects durations into a fixed size histogram so percentiles can be worked out without keeping every sample
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum_us: u64,
+    min_us: u64,
+    max_us: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HistogramSummary {
+    pub count: u64,
+    pub min_us: u64,
+    pub mean_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+    pub bucket_width_us: u64,
+    /// Sample count for each bucket, trailing empty buckets are left off
+    pub buckets: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram {
+            buckets: vec![0; BUCKET_COUNT],
+            count: 0,
+            sum_us: 0,
+            min_us: u64::MAX,
+            max_us: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: Duration) {
+        let value_us = value.as_micros() as u64;
+        let bucket = ((value_us / BUCKET_WIDTH_US) as usize).min(BUCKET_COUNT - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_us += value_us;
+        self.min_us = self.min_us.min(value_us);
+        self.max_us = self.max_us.max(value_us);
+    }
+
+    /// Returns the upper bound of the bucket the given percentile (0 -> 1) falls in. The last bucket has no upper bound
+    /// as it holds everything past the end, so the max is used for it
+    fn percentile_us(&self, percentile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        let target = ((self.count as f64) * percentile).ceil() as u64;
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                if i == BUCKET_COUNT - 1 {
+                    break;
+                }
+                return ((i as u64 + 1) * BUCKET_WIDTH_US).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+
+    pub fn summary(&self) -> HistogramSummary {
+        let used_buckets = self
+            .buckets
+            .iter()
+            .rposition(|&count| count > 0)
+            .map_or(0, |i| i + 1);
+
+        HistogramSummary {
+            count: self.count,
+            min_us: if self.count == 0 { 0 } else { self.min_us },
+            mean_us: self.sum_us.checked_div(self.count).unwrap_or(0),
+            p99_us: self.percentile_us(0.99),
+            max_us: self.max_us,
+            bucket_width_us: BUCKET_WIDTH_US,
+            buckets: self.buckets[..used_buckets].to_vec(),
+        }
+    }
+}
+
+/// Timing statistics for the poll loop
+pub struct PollStats {
+    /// Time from reading the input to the report being handed to the controller, for polls that sent a report
+    pub latency: Histogram,
+    /// How far each poll started from when it was scheduled to
+    pub jitter: Histogram,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PollStatsSummary {
+    pub latency: HistogramSummary,
+    pub jitter: HistogramSummary,
+}
+
+impl PollStats {
+    pub fn new() -> Self {
+        PollStats {
+            latency: Histogram::new(),
+            jitter: Histogram::new(),
+        }
+    }
+
+    pub fn summary(&self) -> PollStatsSummary {
+        PollStatsSummary {
+            latency: self.latency.summary(),
+            jitter: self.jitter.summary(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(samples_us: &[u64]) -> Histogram {
+        let mut histogram = Histogram::new();
+        for &sample in samples_us {
+            histogram.record(Duration::from_micros(sample));
+        }
+        histogram
+    }
+
+    #[test]
+    fn empty() {
+        let summary = Histogram::new().summary();
+
+        assert_eq!(summary.count, 0);
+        assert_eq!(summary.min_us, 0);
+        assert_eq!(summary.mean_us, 0);
+        assert_eq!(summary.p99_us, 0);
+        assert_eq!(summary.max_us, 0);
+        assert_eq!(summary.bucket_width_us, BUCKET_WIDTH_US);
+        assert!(summary.buckets.is_empty());
+    }
+
+    #[test]
+    fn summarises_a_known_distribution() {
+        // One sample every 10us from 0 to 9990us, so the 99th percentile is the 990th sample at 9890us
+        let samples: Vec<_> = (0..1000).map(|i| i * 10).collect();
+        let summary = histogram(&samples).summary();
+
+        assert_eq!(summary.count, 1000);
+        assert_eq!(summary.min_us, 0);
+        assert_eq!(summary.mean_us, 4995);
+        assert_eq!(summary.p99_us, 9900);
+        assert_eq!(summary.max_us, 9990);
+        assert_eq!(summary.buckets, vec![5; BUCKET_COUNT]);
+    }
+
+    #[test]
+    fn p99_ignores_the_slowest_percent() {
+        let mut samples = vec![100; 99];
+        samples.push(5000);
+        let summary = histogram(&samples).summary();
+
+        assert_eq!(summary.min_us, 100);
+        assert_eq!(summary.mean_us, 149);
+        assert_eq!(summary.p99_us, 150);
+        assert_eq!(summary.max_us, 5000);
+
+        samples.push(5000);
+        assert_eq!(histogram(&samples).summary().p99_us, 5000);
+    }
+
+    #[test]
+    fn p99_is_capped_at_the_max() {
+        assert_eq!(histogram(&[120]).summary().p99_us, 120);
+    }
+
+    #[test]
+    fn trailing_empty_buckets_are_left_off() {
+        let summary = histogram(&[0, 49, 50, 120]).summary();
+
+        assert_eq!(summary.buckets, vec![2, 1, 1]);
+    }
+
+    #[test]
+    fn anything_past_the_end_goes_in_the_last_bucket() {
+        let summary = histogram(&[100, 10_000, 20_000, 1_000_000]).summary();
+
+        assert_eq!(summary.buckets.len(), BUCKET_COUNT);
+        assert_eq!(summary.buckets[2], 1);
+        assert_eq!(summary.buckets[BUCKET_COUNT - 1], 3);
+        assert_eq!(summary.max_us, 1_000_000);
+        // The last bucket has no upper bound, so a percentile in it is only known to be at most the max
+        assert_eq!(summary.p99_us, 1_000_000);
+    }
+}
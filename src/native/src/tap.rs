@@ -0,0 +1,215 @@
+// Only the service uses this, which is Windows only, but it's built everywhere for its tests
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::TapBind;
+use crate::controller::ControllerButton;
+use crate::keycode::KeyCode;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TapPhase {
+    Up,
+    /// Pressed at the given time, and not held long enough yet to tell whether it's a tap or a hold
+    Deciding(Instant),
+    Holding,
+}
+
+#[derive(Debug)]
+struct KeyTap {
+    phase: TapPhase,
+    /// When the button press from the last tap ends
+    tapped_until: Option<Instant>,
+}
+
+/// Tells taps apart from holds on the keys that have a `TapBind`. Until a key has been held for its tap window it
+/// counts as not pressed, as there's no way to know yet whether the press is a tap
+pub struct Taps {
+    keys: HashMap<KeyCode, KeyTap>,
+}
+
+impl Taps {
+    pub fn new() -> Self {
+        Taps {
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Moves each tap on to `now`, given how far its key is pressed. Returns whether anything the controller sees
+    /// changed, meaning a key started or stopped being held or a tap's button press started or ended
+    pub fn update<'a>(
+        &mut self,
+        taps: impl Iterator<Item = (&'a TapBind, f32)>,
+        now: Instant,
+    ) -> bool {
+        let mut changed = false;
+        for (tap, value) in taps {
+            let key = self.keys.entry(tap.key).or_insert(KeyTap {
+                phase: TapPhase::Up,
+                tapped_until: None,
+            });
+
+            if matches!(key.tapped_until, Some(until) if now >= until) {
+                key.tapped_until = None;
+                changed = true;
+            }
+
+            let window = Duration::from_millis(tap.window_ms as u64);
+            key.phase = match (key.phase, value > 0.0) {
+                (TapPhase::Up, true) => TapPhase::Deciding(now),
+                (TapPhase::Deciding(pressed_at), true) => {
+                    if now.saturating_duration_since(pressed_at) >= window {
+                        changed = true;
+                        TapPhase::Holding
+                    } else {
+                        TapPhase::Deciding(pressed_at)
+                    }
+                }
+                (TapPhase::Deciding(pressed_at), false) => {
+                    // A poll can come late enough that the window has passed, in which case it was too long for a tap
+                    if now.saturating_duration_since(pressed_at) < window {
+                        key.tapped_until = Some(now + Duration::from_millis(tap.press_ms as u64));
+                        changed = true;
+                    }
+                    TapPhase::Up
+                }
+                (TapPhase::Holding, false) => {
+                    changed = true;
+                    TapPhase::Up
+                }
+                (phase, _) => phase,
+            };
+        }
+        changed
+    }
+
+    /// How far the key counts as pressed for its binds, which is nothing until a key that can be tapped is being held
+    pub fn gate(&self, taps: &[TapBind], key: KeyCode, value: f32) -> f32 {
+        if !taps.iter().any(|tap| tap.key == key) {
+            return value;
+        }
+        match self.keys.get(&key) {
+            Some(tap) if tap.phase == TapPhase::Holding => value,
+            _ => 0.0,
+        }
+    }
+
+    /// The buttons being pressed by a tap
+    pub fn tapped_buttons<'a>(
+        &'a self,
+        taps: &'a [TapBind],
+    ) -> impl Iterator<Item = ControllerButton> + 'a {
+        taps.iter()
+            .filter(move |tap| {
+                matches!(self.keys.get(&tap.key), Some(key) if key.tapped_until.is_some())
+            })
+            .map(|tap| tap.button)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW_MS: u64 = 200;
+    const PRESS_MS: u64 = 50;
+
+    fn tap_bind() -> TapBind {
+        TapBind {
+            key: KeyCode::from_name("Space").unwrap(),
+            button: ControllerButton::A,
+            window_ms: WINDOW_MS as u32,
+            press_ms: PRESS_MS as u32,
+        }
+    }
+
+    /// Runs a sequence of polls given as `(ms after the start, key value, changed, tapped, gated value)`, checking
+    /// after each whether anything changed, whether the button is tapped and how far the key counts as pressed
+    fn run(steps: &[(u64, f32, bool, bool, f32)]) {
+        let taps = [tap_bind()];
+        let mut state = Taps::new();
+        let start = Instant::now();
+        for (i, &(ms, value, changed, tapped, gated)) in steps.iter().enumerate() {
+            let now = start + Duration::from_millis(ms);
+            assert_eq!(
+                state.update(taps.iter().map(|tap| (tap, value)), now),
+                changed,
+                "changed at step {}",
+                i
+            );
+            assert_eq!(
+                state.tapped_buttons(&taps).count() > 0,
+                tapped,
+                "tapped at step {}",
+                i
+            );
+            assert_eq!(
+                state.gate(&taps, taps[0].key, value),
+                gated,
+                "gated value at step {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn tap_presses_the_button_for_press_ms() {
+        run(&[
+            (0, 1.0, false, false, 0.0),
+            (100, 1.0, false, false, 0.0),
+            (150, 0.0, true, true, 0.0),
+            (150 + PRESS_MS - 1, 0.0, false, true, 0.0),
+            (150 + PRESS_MS, 0.0, true, false, 0.0),
+        ]);
+    }
+
+    #[test]
+    fn hold_is_gated_until_the_window_passes() {
+        run(&[
+            (0, 0.5, false, false, 0.0),
+            (WINDOW_MS - 1, 0.5, false, false, 0.0),
+            (WINDOW_MS, 0.5, true, false, 0.5),
+            (WINDOW_MS + 100, 0.8, false, false, 0.8),
+            (WINDOW_MS + 200, 0.0, true, false, 0.0),
+        ]);
+    }
+
+    #[test]
+    fn release_on_the_window_boundary_is_not_a_tap() {
+        run(&[
+            (0, 1.0, false, false, 0.0),
+            (WINDOW_MS, 0.0, false, false, 0.0),
+        ]);
+        run(&[
+            (0, 1.0, false, false, 0.0),
+            (WINDOW_MS - 1, 0.0, true, true, 0.0),
+        ]);
+    }
+
+    #[test]
+    fn late_poll_after_the_window_is_not_a_tap() {
+        run(&[(0, 1.0, false, false, 0.0), (500, 0.0, false, false, 0.0)]);
+    }
+
+    #[test]
+    fn pressing_again_while_tapped_starts_a_new_decision() {
+        run(&[
+            (0, 1.0, false, false, 0.0),
+            (20, 0.0, true, true, 0.0),
+            (30, 1.0, false, true, 0.0),
+            (20 + PRESS_MS, 1.0, true, false, 0.0),
+            (30 + WINDOW_MS, 1.0, true, false, 1.0),
+            (40 + WINDOW_MS, 0.0, true, false, 0.0),
+        ]);
+    }
+
+    #[test]
+    fn keys_without_a_tap_are_not_gated() {
+        let taps = [tap_bind()];
+        let state = Taps::new();
+        let other = KeyCode::from_name("KeyW").unwrap();
+        assert_eq!(state.gate(&taps, other, 0.7), 0.7);
+        assert_eq!(state.gate(&taps, taps[0].key, 0.7), 0.0);
+    }
+}
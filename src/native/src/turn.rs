@@ -0,0 +1,236 @@
+// Only the service uses this, which is Windows only, but it's built everywhere for its tests
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use std::time::Instant;
+
+use crate::config::TurnRateConfiguration;
+
+/// Turns how far the right stick keys are pressed into a turn rate for camera control. The rate builds up over time
+/// rather than jumping straight to where the keys say, which keeps small adjustments precise while still reaching
+/// full speed quickly, and can get a boost once the keys have been held for a while
+pub struct TurnRate {
+    velocity: (f32, f32),
+    held_since: Option<Instant>,
+    last_update: Option<Instant>,
+}
+
+impl TurnRate {
+    pub fn new() -> Self {
+        TurnRate {
+            velocity: (0.0, 0.0),
+            held_since: None,
+            last_update: None,
+        }
+    }
+
+    /// Takes the key travel on each axis, between -1 -> 1, and returns the stick position. The acceleration is
+    /// measured from the previous update, so the first update has nothing to build up over and stays centred unless
+    /// the acceleration is 0. The service updates this on every poll, so that's only the first poll after it's reset
+    pub fn update(
+        &mut self,
+        travel: (f32, f32),
+        config: &TurnRateConfiguration,
+        now: Instant,
+    ) -> (f32, f32) {
+        let elapsed = self.last_update.replace(now).map_or(0.0, |last_update| {
+            now.saturating_duration_since(last_update).as_secs_f32()
+        });
+
+        let held = travel != (0.0, 0.0);
+        let held_since = match (held, self.held_since) {
+            (false, _) => None,
+            (true, None) => Some(now),
+            (true, Some(held_since)) => Some(held_since),
+        };
+        self.held_since = held_since;
+        let boost = match (held_since, config.boost_after_ms) {
+            (Some(held_since), Some(boost_after_ms))
+                if now.saturating_duration_since(held_since).as_millis()
+                    >= boost_after_ms as u128 =>
+            {
+                config.boost
+            }
+            _ => 1.0,
+        };
+
+        let target = |travel: f32| {
+            let rate = travel.abs().powf(config.curve) * config.max_rate * boost;
+            rate.min(1.0).copysign(travel)
+        };
+        self.velocity = (
+            accumulate(self.velocity.0, target(travel.0), config, elapsed),
+            accumulate(self.velocity.1, target(travel.1), config, elapsed),
+        );
+        self.velocity
+    }
+}
+
+/// Moves the velocity towards the target. Speeding up is limited by the acceleration, but slowing down happens
+/// straight away so letting go of a key stops the camera
+fn accumulate(velocity: f32, target: f32, config: &TurnRateConfiguration, elapsed: f32) -> f32 {
+    let slowing = target.abs() <= velocity.abs() && target * velocity >= 0.0;
+    if slowing || config.acceleration <= 0.0 {
+        return target;
+    }
+
+    // Reversing starts from the centre rather than having to swing back through it
+    let velocity = if target * velocity < 0.0 {
+        0.0
+    } else {
+        velocity
+    };
+    let step = config.acceleration * elapsed;
+    if (target - velocity).abs() <= step {
+        target
+    } else {
+        velocity + step.copysign(target - velocity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config() -> TurnRateConfiguration {
+        TurnRateConfiguration {
+            enabled: true,
+            max_rate: 1.0,
+            curve: 1.0,
+            acceleration: 4.0,
+            boost_after_ms: None,
+            boost: 1.5,
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn assert_near(actual: (f32, f32), expected: (f32, f32), case: &str) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-4 && (actual.1 - expected.1).abs() < 1e-4,
+            "{}: expected {:?}, got {:?}",
+            case,
+            expected,
+            actual
+        );
+    }
+
+    /// Milliseconds since the start, the key travel and the expected output
+    type Step = (u64, (f32, f32), (f32, f32));
+
+    /// Runs the updates in order from a new `TurnRate`
+    fn check(config: &TurnRateConfiguration, steps: &[Step]) {
+        let start = Instant::now();
+        let mut turn = TurnRate::new();
+        for &(at, travel, expected) in steps {
+            let output = turn.update(travel, config, start + ms(at));
+            assert_near(output, expected, &format!("{}ms with {:?}", at, travel));
+        }
+    }
+
+    #[test]
+    fn the_first_update_has_nothing_to_accelerate_over() {
+        check(
+            &config(),
+            &[(0, (1.0, 0.0), (0.0, 0.0)), (1, (1.0, 0.0), (0.004, 0.0))],
+        );
+        let config = TurnRateConfiguration {
+            acceleration: 0.0,
+            ..config()
+        };
+        check(&config, &[(0, (1.0, 0.0), (1.0, 0.0))]);
+    }
+
+    #[test]
+    fn speeding_up_is_limited_by_the_acceleration() {
+        check(
+            &config(),
+            &[
+                (0, (0.0, 0.0), (0.0, 0.0)),
+                (100, (1.0, 0.5), (0.4, 0.4)),
+                (200, (1.0, 0.5), (0.8, 0.5)),
+                (300, (1.0, 0.5), (1.0, 0.5)),
+                (400, (1.0, 0.5), (1.0, 0.5)),
+            ],
+        );
+    }
+
+    #[test]
+    fn slowing_down_happens_straight_away() {
+        check(
+            &config(),
+            &[
+                (0, (0.0, 0.0), (0.0, 0.0)),
+                (250, (1.0, -1.0), (1.0, -1.0)),
+                (251, (0.25, -0.5), (0.25, -0.5)),
+                (252, (0.0, 0.0), (0.0, 0.0)),
+            ],
+        );
+    }
+
+    #[test]
+    fn reversing_starts_from_the_centre() {
+        check(
+            &config(),
+            &[
+                (0, (0.0, 0.0), (0.0, 0.0)),
+                (200, (1.0, 0.0), (0.8, 0.0)),
+                (300, (-1.0, 0.0), (-0.4, 0.0)),
+                (400, (-1.0, 0.0), (-0.8, 0.0)),
+            ],
+        );
+    }
+
+    #[test]
+    fn curve_is_applied_to_the_travel() {
+        let config = TurnRateConfiguration {
+            max_rate: 0.8,
+            curve: 2.0,
+            acceleration: 0.0,
+            ..config()
+        };
+        check(
+            &config,
+            &[(0, (0.5, -0.5), (0.2, -0.2)), (1, (1.0, 0.0), (0.8, 0.0))],
+        );
+    }
+
+    #[test]
+    fn boosts_once_held_for_long_enough() {
+        let config = TurnRateConfiguration {
+            max_rate: 0.5,
+            acceleration: 0.0,
+            boost_after_ms: Some(500),
+            ..config()
+        };
+        check(
+            &config,
+            &[
+                (0, (1.0, 0.0), (0.5, 0.0)),
+                (499, (1.0, 0.0), (0.5, 0.0)),
+                (500, (1.0, 0.0), (0.75, 0.0)),
+                // Changing direction keeps the boost, letting go starts the wait again
+                (600, (0.0, -1.0), (0.0, -0.75)),
+                (700, (0.0, 0.0), (0.0, 0.0)),
+                (800, (1.0, 0.0), (0.5, 0.0)),
+                (1300, (1.0, 0.0), (0.75, 0.0)),
+            ],
+        );
+    }
+
+    #[test]
+    fn boost_is_clamped_to_full_deflection() {
+        let config = TurnRateConfiguration {
+            acceleration: 0.0,
+            boost_after_ms: Some(0),
+            boost: 4.0,
+            ..config()
+        };
+        check(
+            &config,
+            &[(0, (1.0, -1.0), (1.0, -1.0)), (1, (0.5, -0.1), (1.0, -0.4))],
+        );
+    }
+}
@@ -0,0 +1,368 @@
+// Only the service runner uses this, which is Windows only, but it's built everywhere for its tests
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+/// How many polls in a row need to fail before the service is considered faulted
+const FAILURE_THRESHOLD: u32 = 100;
+/// The least amount of time between logging poll errors, anything in between is counted and summarised
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait before the first recovery attempt, this doubles with each failed attempt
+const RECOVERY_BACKOFF: Duration = Duration::from_secs(1);
+/// How many times recovery is attempted before giving up and staying faulted
+const MAX_RECOVERY_ATTEMPTS: u32 = 5;
+/// How long polling has to go without faulting again after a recovery for the attempts to be reset
+const HEALTHY_RESET: Duration = Duration::from_secs(60);
+/// How long the poll thread can go without checking in before it's considered stalled
+const STALL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What the poll loop should do after a failed poll
+pub struct FailureOutcome {
+    /// Set if the error should be reported, holding how many errors were suppressed since the last report
+    pub report: Option<u32>,
+    /// Set when this failure pushed the service over the threshold and it should fault
+    pub fault: bool,
+}
+
+/// Keeps track of poll failures, deciding when the service should be faulted and when to try recovering it
+pub struct Watchdog {
+    consecutive_failures: u32,
+    suppressed_errors: u32,
+    last_error_report: Option<Instant>,
+    recovery_attempts: u32,
+    next_recovery: Option<Instant>,
+    last_recovery: Option<Instant>,
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Watchdog {
+            consecutive_failures: 0,
+            suppressed_errors: 0,
+            last_error_report: None,
+            recovery_attempts: 0,
+            next_recovery: None,
+            last_recovery: None,
+        }
+    }
+
+    pub fn record_success(&mut self, now: Instant) {
+        self.consecutive_failures = 0;
+        if let Some(last_recovery) = self.last_recovery {
+            if now.saturating_duration_since(last_recovery) >= HEALTHY_RESET {
+                debug!("Service has been healthy since recovering, resetting recovery attempts");
+                self.reset_recovery();
+            }
+        }
+    }
+
+    pub fn record_failure(&mut self, now: Instant) -> FailureOutcome {
+        self.consecutive_failures += 1;
+
+        let report = match self.last_error_report {
+            Some(last) if now.saturating_duration_since(last) < ERROR_LOG_INTERVAL => {
+                self.suppressed_errors += 1;
+                None
+            }
+            _ => {
+                self.last_error_report = Some(now);
+                Some(std::mem::replace(&mut self.suppressed_errors, 0))
+            }
+        };
+
+        let fault = self.consecutive_failures == FAILURE_THRESHOLD;
+        if fault {
+            self.schedule_recovery(now);
+        }
+
+        FailureOutcome { report, fault }
+    }
+
+    fn schedule_recovery(&mut self, now: Instant) {
+        if self.recovery_attempts >= MAX_RECOVERY_ATTEMPTS {
+            error!(
+                "Giving up on recovering the service after {} attempts",
+                self.recovery_attempts
+            );
+            self.next_recovery = None;
+            return;
+        }
+
+        let backoff = RECOVERY_BACKOFF * 2u32.pow(self.recovery_attempts);
+        info!("Attempting to recover the service in {:?}", backoff);
+        self.next_recovery = Some(now + backoff);
+    }
+
+    /// Returns true once a scheduled recovery attempt is due, counting it as an attempt
+    pub fn should_attempt_recovery(&mut self, now: Instant) -> bool {
+        match self.next_recovery {
+            Some(next) if now >= next => {
+                self.next_recovery = None;
+                self.last_recovery = Some(now);
+                self.recovery_attempts += 1;
+                self.consecutive_failures = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Called when a recovery attempt itself failed, so the next one gets scheduled
+    pub fn recovery_failed(&mut self, now: Instant) {
+        self.schedule_recovery(now);
+    }
+
+    /// Resets the attempts and backoff once the service has been running fine again, e.g. after a new config. A
+    /// recovery that's already scheduled still happens
+    pub fn reset_recovery(&mut self) {
+        self.recovery_attempts = 0;
+        self.last_recovery = None;
+    }
+
+    /// Schedules a recovery attempt for straight away, even if recovery had been given up on
+    pub fn recover_now(&mut self, now: Instant) {
+        self.next_recovery = Some(now);
+    }
+}
+
+/// Watches for the poll thread going quiet, calling `on_stall` from its own thread once it does so JS can be told
+/// about it. The stall is also flagged for the poll thread to see once it comes back, so it can centre the controllers
+pub struct StallMonitor {
+    epoch: Instant,
+    heartbeat_ms: Arc<AtomicU64>,
+    stalled: Arc<AtomicBool>,
+    /// Set while the poll thread is doing something that's expected to take longer than `STALL_TIMEOUT`
+    paused: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl StallMonitor {
+    pub fn start<F>(on_stall: F) -> Self
+    where
+        F: Fn(Duration) + Send + 'static,
+    {
+        let epoch = Instant::now();
+        let heartbeat_ms = Arc::new(AtomicU64::new(0));
+        let stalled = Arc::new(AtomicBool::new(false));
+        let paused = Arc::new(AtomicBool::new(false));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread = {
+            let heartbeat_ms = heartbeat_ms.clone();
+            let stalled = stalled.clone();
+            let paused = paused.clone();
+            let running = running.clone();
+            thread::Builder::new()
+                .name("service-watchdog".to_string())
+                .spawn(move || {
+                    while running.load(Ordering::SeqCst) {
+                        thread::sleep(STALL_TIMEOUT / 5);
+                        if paused.load(Ordering::SeqCst) {
+                            continue;
+                        }
+                        let since_heartbeat = epoch
+                            .elapsed()
+                            .checked_sub(Duration::from_millis(heartbeat_ms.load(Ordering::SeqCst)))
+                            .unwrap_or_default();
+                        if since_heartbeat > STALL_TIMEOUT && !stalled.swap(true, Ordering::SeqCst)
+                        {
+                            error!(
+                                "Poll thread hasn't responded in {:?}, it appears to be stalled",
+                                since_heartbeat
+                            );
+                            on_stall(since_heartbeat);
+                        }
+                    }
+                })
+        };
+
+        let thread = match thread {
+            Ok(thread) => Some(thread),
+            Err(e) => {
+                error!("Failed to start the stall monitor {}", e);
+                None
+            }
+        };
+
+        StallMonitor {
+            epoch,
+            heartbeat_ms,
+            stalled,
+            paused,
+            running,
+            thread,
+        }
+    }
+
+    /// Called by the poll thread every loop. Returns true if it was considered stalled since the last heartbeat
+    pub fn heartbeat(&self) -> bool {
+        self.beat();
+        self.stalled.swap(false, Ordering::SeqCst)
+    }
+
+    fn beat(&self) {
+        self.heartbeat_ms
+            .store(self.epoch.elapsed().as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Runs `f` on the poll thread without it counting as a stall, for work that can legitimately take a while such
+    /// as reloading the analog SDK or reconnecting to ViGEm
+    pub fn paused<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        self.paused.store(true, Ordering::SeqCst);
+        let result = f();
+        // The time spent paused mustn't count once checking resumes
+        self.beat();
+        self.paused.store(false, Ordering::SeqCst);
+        result
+    }
+}
+
+impl Drop for StallMonitor {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// Fails enough polls in a row at `now` to fault the service
+    fn fault(watchdog: &mut Watchdog, now: Instant) {
+        for _ in 1..FAILURE_THRESHOLD {
+            assert!(!watchdog.record_failure(now).fault);
+        }
+        assert!(watchdog.record_failure(now).fault);
+    }
+
+    #[test]
+    fn faults_once_the_threshold_is_reached() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new();
+
+        fault(&mut watchdog, start);
+        assert!(!watchdog.record_failure(start).fault);
+
+        // A success in between starts the count again
+        let mut watchdog = Watchdog::new();
+        for _ in 1..FAILURE_THRESHOLD {
+            watchdog.record_failure(start);
+        }
+        watchdog.record_success(start);
+        assert!(!watchdog.record_failure(start).fault);
+    }
+
+    #[test]
+    fn reports_are_rate_limited() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new();
+        let cases = [
+            (0, Some(0)),
+            (1, None),
+            (999, None),
+            (1000, Some(2)),
+            (1500, None),
+            (2000, Some(1)),
+        ];
+        for &(at, report) in cases.iter() {
+            assert_eq!(
+                watchdog.record_failure(start + ms(at)).report,
+                report,
+                "failure at {}ms",
+                at
+            );
+        }
+    }
+
+    #[test]
+    fn recovery_backs_off_then_gives_up() {
+        let mut now = Instant::now();
+        let mut watchdog = Watchdog::new();
+        fault(&mut watchdog, now);
+
+        let backoffs = [1000, 2000, 4000, 8000, 16000];
+        for (attempt, &backoff) in backoffs.iter().enumerate() {
+            assert!(
+                !watchdog.should_attempt_recovery(now + ms(backoff - 1)),
+                "attempt {} was early",
+                attempt
+            );
+            now += ms(backoff);
+            assert!(
+                watchdog.should_attempt_recovery(now),
+                "attempt {} didn't happen",
+                attempt
+            );
+            assert!(
+                !watchdog.should_attempt_recovery(now),
+                "attempt {} repeated",
+                attempt
+            );
+            watchdog.recovery_failed(now);
+        }
+
+        assert_eq!(watchdog.recovery_attempts, MAX_RECOVERY_ATTEMPTS);
+        assert!(!watchdog.should_attempt_recovery(now + ms(3_600_000)));
+    }
+
+    #[test]
+    fn recover_now_retries_even_after_giving_up() {
+        let mut now = Instant::now();
+        let mut watchdog = Watchdog::new();
+        fault(&mut watchdog, now);
+        for _ in 0..MAX_RECOVERY_ATTEMPTS {
+            now += ms(60_000);
+            assert!(watchdog.should_attempt_recovery(now));
+            watchdog.recovery_failed(now);
+        }
+        assert!(!watchdog.should_attempt_recovery(now + ms(60_000)));
+
+        watchdog.recover_now(now);
+        assert!(watchdog.should_attempt_recovery(now));
+    }
+
+    #[test]
+    fn a_new_config_keeps_a_pending_recovery_but_resets_the_backoff() {
+        let now = Instant::now();
+        let mut watchdog = Watchdog::new();
+        fault(&mut watchdog, now);
+        assert!(watchdog.should_attempt_recovery(now + ms(1000)));
+        watchdog.recovery_failed(now + ms(1000));
+
+        watchdog.reset_recovery();
+
+        // The attempt scheduled before the config still happens when it was due
+        assert!(!watchdog.should_attempt_recovery(now + ms(2999)));
+        assert!(watchdog.should_attempt_recovery(now + ms(3000)));
+        // That counts as the first attempt since the reset, so the next waits 2s rather than 4s
+        watchdog.recovery_failed(now + ms(3000));
+        assert!(!watchdog.should_attempt_recovery(now + ms(4999)));
+        assert!(watchdog.should_attempt_recovery(now + ms(5000)));
+    }
+
+    #[test]
+    fn healthy_polling_after_a_recovery_resets_the_attempts() {
+        let now = Instant::now();
+        let mut watchdog = Watchdog::new();
+        fault(&mut watchdog, now);
+        assert!(watchdog.should_attempt_recovery(now + ms(1000)));
+
+        watchdog.record_success(now + ms(1000) + HEALTHY_RESET - ms(1));
+        assert_eq!(watchdog.recovery_attempts, 1);
+        watchdog.record_success(now + ms(1000) + HEALTHY_RESET);
+        assert_eq!(watchdog.recovery_attempts, 0);
+    }
+}
@@ -1,5 +1,6 @@
 import { AppSettings } from "common";
 import { Key } from "ts-keycode-enum";
+import { hidFromCode } from "./keycode";
 
 export interface JoystickAngleConfiguration {
   upDiagonalAngle: number;
@@ -15,6 +16,10 @@ export const defaultLeftJoystickStrafingAngles: JoystickAngleConfiguration = {
   analogRange: [0.075, 0.9],
 };
 
+// Auto follows the input the service is using, Digital always reads the key state
+export type BindSource = "Auto" | "Digital";
+
+// Keys are HID usage codes, see keycode.ts
 export interface JoystickKeyMapping {
   up?: number;
   up_two?: number;
@@ -24,27 +29,229 @@ export interface JoystickKeyMapping {
   left_two?: number;
   right?: number;
   right_two?: number;
+  // Binds without a source are Auto
+  sources?: {
+    up?: BindSource;
+    up_two?: BindSource;
+    down?: BindSource;
+    down_two?: BindSource;
+    left?: BindSource;
+    left_two?: BindSource;
+    right?: BindSource;
+    right_two?: BindSource;
+  };
+}
+
+// When a bind presses a button, all values are key travel between 0-1
+export type Actuation =
+  // Pressed at press and released once back up past release, which can't be deeper than press
+  | { mode: "Threshold"; press: number; release: number }
+  // Pressed at press, then released after coming up by delta from the deepest point and pressed again after going
+  // back down by delta
+  | { mode: "RapidTrigger"; press: number; delta: number };
+
+export interface Bind {
+  // A HID usage code, or a KeyboardEvent.code style name such as "KeyW"
+  key: number | string;
+  source?: BindSource;
+  // Only used by button binds, defaults to a Threshold at 0.5 both ways
+  actuation?: Actuation;
+  // Only used by stick binds, also presses the button once the key goes down far enough
+  fullPress?: FullPress;
+}
+
+// Binds read digitally always count as fully pressed
+export interface FullPress {
+  button: ControllerButton;
+  // Defaults to a Threshold pressing at 0.9 and releasing at 0.8
+  actuation?: Actuation;
+}
+
+export type CombineRule = "Max" | "SumClamped" | "FirstNonZero";
+
+export interface DirectionBinds {
+  binds: Bind[];
+  // Defaults to Max
+  combine?: CombineRule;
+}
+
+// Any number of binds per direction, the native side accepts this as well as the two bind JoystickKeyMapping
+export interface JoystickBindMapping {
+  up?: DirectionBinds;
+  down?: DirectionBinds;
+  left?: DirectionBinds;
+  right?: DirectionBinds;
+}
+
+// Named after the Xbox 360 buttons, so on a DualShock4 A is cross, B is circle, X is square and Y is triangle. PS,
+// Share and Options are guide, back and start on an Xbox 360 controller
+export type ControllerButton =
+  | "A"
+  | "B"
+  | "X"
+  | "Y"
+  | "LeftShoulder"
+  | "RightShoulder"
+  | "LeftTrigger"
+  | "RightTrigger"
+  | "LeftThumb"
+  | "RightThumb"
+  | "DpadUp"
+  | "DpadDown"
+  | "DpadLeft"
+  | "DpadRight"
+  | "TouchpadClick"
+  | "PS"
+  | "Share"
+  | "Options";
+
+export interface ButtonKeyMapping {
+  a?: DirectionBinds;
+  b?: DirectionBinds;
+  x?: DirectionBinds;
+  y?: DirectionBinds;
+  leftShoulder?: DirectionBinds;
+  rightShoulder?: DirectionBinds;
+  // Pulls the trigger all the way
+  leftTrigger?: DirectionBinds;
+  rightTrigger?: DirectionBinds;
+  leftThumb?: DirectionBinds;
+  rightThumb?: DirectionBinds;
+  dpadUp?: DirectionBinds;
+  dpadDown?: DirectionBinds;
+  dpadLeft?: DirectionBinds;
+  dpadRight?: DirectionBinds;
+  // Only DualShock4 controllers have a touchpad, Xbox360 controllers ignore this
+  touchpadClick?: DirectionBinds;
+  ps?: DirectionBinds;
+  share?: DirectionBinds;
+  options?: DirectionBinds;
+}
+
+// A press of the key let go of within windowMs is a tap, which presses button for pressMs. A longer press does what
+// the key is bound to, but the key counts as not pressed until the window has passed, so it doesn't move the stick or
+// win against the opposite direction until then
+export interface TapBind {
+  key: number | string;
+  // Can't be TouchpadClick for Xbox360 controllers
+  button: ControllerButton;
+  // Defaults to 200
+  windowMs?: number;
+  // Defaults to 50
+  pressMs?: number;
 }
 
 export interface KeyMapping {
   leftJoystick: JoystickKeyMapping;
+  rightJoystick?: JoystickKeyMapping;
+  buttons?: ButtonKeyMapping;
+  // Only one tap per key
+  taps?: TapBind[];
 }
 
 export const defaultKeyMapping: KeyMapping = {
   leftJoystick: {
-    up: Key.W,
-    down: Key.S,
-    left: Key.A,
-    right: Key.D,
+    up: hidFromCode("KeyW"),
+    down: hidFromCode("KeyS"),
+    left: hidFromCode("KeyA"),
+    right: hidFromCode("KeyD"),
   },
 };
 
 export const defaultToggleAccelerator = [Key.Ctrl, Key.P];
 
-export interface ServiceConfiguration {
+export type PollMode = "Sleep" | "Precise";
+
+export interface PollConfiguration {
+  rateHz: number;
+  mode: PollMode;
+}
+
+export const defaultPollConfiguration: PollConfiguration = {
+  rateHz: 1000,
+  mode: "Sleep",
+};
+
+export type OutputType = "Xbox360" | "DualShock4";
+
+// Drives the right stick from mouse movement, this needs the native module to be built with raw input
+export interface MouseStickConfiguration {
+  enabled: boolean;
+  // Stick deflection for each 1000 counts per second the mouse moves
+  sensitivity: number;
+  // Exponent applied to the deflection, above 1 gives finer control over small movements
+  curve: number;
+  // 0-1, set to the game's deadzone so slow movements still register
+  deadzoneCompensation: number;
+  // How long the stick takes to return to the centre from full deflection after the mouse stops
+  decayMs: number;
+  invertX: boolean;
+  invertY: boolean;
+}
+
+export const defaultMouseStick: MouseStickConfiguration = {
+  enabled: false,
+  sensitivity: 0.5,
+  curve: 1,
+  deadzoneCompensation: 0,
+  decayMs: 50,
+  invertX: false,
+  invertY: false,
+};
+
+// Turns the right stick keys into a turn rate that builds up while they're held, for camera control
+export interface TurnRateConfiguration {
+  enabled: boolean;
+  // 0-1, the stick deflection with the keys fully pressed
+  maxRate: number;
+  // Exponent applied to the key travel, above 1 gives finer control over slow turns
+  curve: number;
+  // How quickly the turn rate builds up, in full deflections per second. 0 applies it straight away
+  acceleration: number;
+  // How long the keys have to be held before boost applies, no boost if not set
+  boostAfterMs?: number;
+  // What the turn rate is multiplied by once boosted
+  boost: number;
+}
+
+export const defaultRightJoystickTurn: TurnRateConfiguration = {
+  enabled: false,
+  maxRate: 1,
+  curve: 1,
+  acceleration: 4,
+  boost: 1.5,
+};
+
+export interface ControllerProfile {
   leftJoystickStrafingAngles: JoystickAngleConfiguration;
   keyMapping: KeyMapping;
-  useAnalogInput: boolean;
+  output?: OutputType;
+  mouseStick?: MouseStickConfiguration;
+  rightJoystickTurn?: TurnRateConfiguration;
+}
+
+// Must match MAX_CONTROLLERS in native/src/config.rs
+export const maxControllers = 4;
+
+// Where key presses are read from, AnalogWithFallback uses key states while no analog keyboard is available
+export type InputPolicy = "Analog" | "Digital" | "AnalogWithFallback";
+
+export type InputSource = "Analog" | "Digital";
+
+// Must match CONFIG_VERSION in native/src/config.rs. The settings store is migrated to HID usage codes in main.ts, so
+// configs are sent in the current format and the native migrations only run for ones saved by older versions
+export const serviceConfigurationVersion = 4;
+
+export interface ServiceConfiguration {
+  version?: number;
+  // Each of these gets its own virtual controller, in order
+  controllers: ControllerProfile[];
+  inputPolicy: InputPolicy;
+  poll?: PollConfiguration;
+  // The device_id of the analog keyboard to read from, as a string since it doesn't fit in a number
+  analogDeviceId?: string;
+  // Makes set_config throw a ConfigValidationError when a key is bound to opposite directions
+  rejectBindingConflicts?: boolean;
 }
 
 export const defaultSettings: AppSettings = {
@@ -55,8 +262,156 @@ export const defaultSettings: AppSettings = {
   useAnalogInput: false,
 };
 
+export type DeviceType = "Keyboard" | "Keypad" | "Other";
+
+export interface AnalogDevice {
+  vendor_id: number;
+  product_id: number;
+  manufacturer_name: string;
+  device_name: string;
+  // As a string since it doesn't fit in a number
+  device_id: string;
+  device_type: DeviceType;
+}
+
 export type SDKState =
   | { type: "Uninitialized" }
   | { type: "Error"; value: string }
-  | { type: "DevicesConnected"; value: string[] }
+  | { type: "DevicesConnected"; value: AnalogDevice[] }
   | { type: "NoDevices" };
+
+export type KeycodeType =
+  | "HID"
+  | "ScanCode1"
+  | "VirtualKey"
+  | "VirtualKeyTranslate";
+
+export interface SDKInfo {
+  version: number | null;
+  keycode_mode: KeycodeType | null;
+}
+
+export interface DirectionValues {
+  up: number;
+  down: number;
+  left: number;
+  right: number;
+}
+
+export interface JoystickTelemetry {
+  raw: DirectionValues;
+  ranged: DirectionValues;
+  output: [number, number];
+  report: [number, number];
+}
+
+export interface Telemetry {
+  // Keyed by HID usage code
+  keys: { [key: number]: number };
+  // One for each controller, in the same order as the config
+  controllers: {
+    left_joystick: JoystickTelemetry;
+    right_joystick: JoystickTelemetry;
+    buttons: ControllerButton[];
+  }[];
+}
+
+export interface HistogramSummary {
+  count: number;
+  min_us: number;
+  mean_us: number;
+  p99_us: number;
+  max_us: number;
+  bucket_width_us: number;
+  buckets: number[];
+}
+
+export interface PollStats {
+  latency: HistogramSummary;
+  jitter: HistogramSummary;
+}
+
+export interface LightbarColor {
+  red: number;
+  green: number;
+  blue: number;
+}
+
+// What the game last asked a controller to do, led_number is only set for Xbox360 and lightbar only for DualShock4
+export interface ControllerFeedback {
+  large_motor: number;
+  small_motor: number;
+  led_number: number | null;
+  lightbar: LightbarColor | null;
+}
+
+export type ServiceState =
+  | { type: "Stopped" }
+  | { type: "Running" }
+  | { type: "Faulted"; reason: string };
+
+export type ServiceEvent =
+  | { type: "ServiceStateChanged"; value: ServiceState }
+  | { type: "SDKStateChanged"; value: SDKState }
+  | { type: "InputSourceChanged"; value: InputSource }
+  | { type: "DeviceConnected"; value: AnalogDevice }
+  | { type: "DeviceDisconnected"; value: AnalogDevice }
+  | { type: "PollError"; value: string }
+  | {
+      type: "Rumble";
+      value: { controller: number; large_motor: number; small_motor: number };
+    }
+  | { type: "LedChanged"; value: { controller: number; led_number: number } }
+  | {
+      type: "LightbarChanged";
+      value: { controller: number; color: LightbarColor };
+    }
+  | { type: "ConfigReloaded" }
+  | { type: "Telemetry"; value: Telemetry }
+  | { type: "BindCaptured"; value: CapturedBind };
+
+export interface CapturedBind {
+  // HID usage code, the same as the service reads for the bind
+  key: number;
+  name: string | null;
+  // Digital if the key had no analog value
+  source: BindSource;
+  value: number;
+}
+
+export type JoystickDirection = "Up" | "Down" | "Left" | "Right";
+
+export type BindTarget =
+  | { type: "LeftJoystick"; value: JoystickDirection }
+  | { type: "RightJoystick"; value: JoystickDirection }
+  | { type: "Button"; value: ControllerButton };
+
+export type BindingIssueKind =
+  | "Conflict"
+  | "SharedKey"
+  | "Shadowed"
+  | "Unbound"
+  | "Tapped";
+
+export interface BindingIssue {
+  kind: BindingIssueKind;
+  // Only Conflict is an Error, which rejectBindingConflicts turns into a ConfigError
+  severity: "Warning" | "Error";
+  // Same form as ConfigError.path
+  path: string;
+  // HID usage code
+  key: number | null;
+  targets: BindTarget[];
+  message: string;
+}
+
+export interface ConfigError {
+  path: string;
+  message: string;
+}
+
+/// Thrown by start_service and set_config when the config fails validation
+export type ConfigValidationError = Error & {
+  name: "ConfigValidationError";
+  errors: ConfigError[];
+};
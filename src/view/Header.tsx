@@ -86,7 +86,7 @@ ${useAnalogInput ? checkText : crossText} 360 movement ${
     }
 ⌨️ ${
       sdkState.type === "DevicesConnected"
-        ? sdkState.value[0]
+        ? sdkState.value[0].device_name
         : "No analog keyboard detected"
     }`;
   }, [angleConfig, sdkState, useAnalogInput]);
@@ -108,7 +108,7 @@ function SDKStateDisplay(props: { state: SDKState }) {
         //   TODO: Improve error text
         return ["red.400", "Error: " + props.state.value, false];
       case "DevicesConnected":
-        return ["green.500", props.state.value[0] + " Connected", false];
+        return ["green.500", props.state.value[0].device_name + " Connected", false];
       case "NoDevices":
         return ["orange.500", "No Wooting keyboard connected", true];
     }
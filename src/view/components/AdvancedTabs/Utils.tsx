@@ -1,7 +1,14 @@
 import { Kbd } from "@chakra-ui/react";
 import React from "react";
-import { Key } from "ts-keycode-enum";
+import { hidName } from "../../../native/keycode";
 
+// keyOne is a HID usage code
 export function keybindDisplay(keyOne: number | undefined, fallback: string) {
-  return <Kbd>{keyOne === undefined ? fallback : Key[keyOne]}</Kbd>;
+  return (
+    <Kbd>
+      {keyOne === undefined
+        ? fallback
+        : hidName(keyOne) ?? `0x${keyOne.toString(16)}`}
+    </Kbd>
+  );
 }
@@ -9,6 +9,7 @@ import {
   CloseIcon,
 } from "@chakra-ui/icons";
 import { KeyBindEditor } from "./KeyBindEditor";
+import { hidFromCode } from "../../../../native/keycode";
 
 interface KeyBindControlProps {
   keyMapping: KeyMapping;
@@ -26,16 +27,17 @@ export function KeyBindControl(props: KeyBindControlProps) {
   };
 
   const listener = (event: KeyboardEvent) => {
-    if (event.keyCode === 27) {
+    if (event.code === "Escape") {
       setEditingState(undefined);
       return;
     }
 
     if (editingState !== undefined) {
-      assignNewJoystickBind(
-        editingState as keyof JoystickKeyMapping,
-        event.keyCode
-      );
+      // Binds are by physical key, so they stay the same whatever the keyboard layout is
+      const key = hidFromCode(event.code);
+      if (key !== undefined) {
+        assignNewJoystickBind(editingState as keyof JoystickKeyMapping, key);
+      }
       setEditingState(undefined);
     }
   };
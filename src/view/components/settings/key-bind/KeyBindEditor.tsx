@@ -1,10 +1,11 @@
 import { Input, InputProps } from "@chakra-ui/react";
 import React from "react";
-import { Key } from "ts-keycode-enum";
+import { hidName } from "../../../../native/keycode";
 import { JoystickKeyMapping } from "../../../../native/types";
 
 interface KeyBindEditorProps {
   keybind: keyof JoystickKeyMapping;
+  // HID usage code
   value?: number;
   requestEdit: (keybind: keyof JoystickKeyMapping) => void;
   isEditing: boolean;
@@ -15,7 +16,9 @@ export function KeyBindEditor(props: KeyBindEditorProps & InputProps) {
 
   return (
     <Input
-      value={!isEditing ? (props.value ? Key[props.value] : "") : ""}
+      value={
+        !isEditing ? (props.value ? hidName(props.value) ?? "" : "") : ""
+      }
       onClick={() => {
         requestEdit(keybind);
       }}